@@ -0,0 +1,102 @@
+//! Echo endpoints for the Autobahn WebSocket test suite
+//! (https://github.com/crossbario/autobahn-testsuite).
+//!
+//! Against `wstest -m fuzzingclient`, listen on 127.0.0.1:9001 and point the
+//! suite's `servers` at `ws://127.0.0.1:9001`:
+//!
+//!     cargo run --release --example autobahn --features deflate -- server
+//!
+//! Against `wstest -m fuzzingserver` listening on 127.0.0.1:9001, run every
+//! case and then have the suite write its report:
+//!
+//!     cargo run --release --example autobahn --features deflate -- client
+
+use session_rs::ws::{self, Config, Frame, HandshakeConfig, WebSocket};
+use tokio::net::TcpListener;
+
+const ADDR: &str = "127.0.0.1:9001";
+const AGENT: &str = "session-rs";
+
+fn config() -> Config {
+    Config {
+        strict: true,
+        ..Default::default()
+    }
+}
+
+/// Echo data messages until the connection ends
+async fn echo(ws: WebSocket) {
+    let ws = ws.with_config(config());
+    loop {
+        let res = match ws.read().await {
+            Ok(Frame::Text(text)) => ws.send(&text).await,
+            Ok(Frame::Binary(payload)) => ws.send_bin(&payload).await,
+            Ok(Frame::Ping(_) | Frame::Pong(_)) => Ok(()),
+            Ok(Frame::Close) | Err(_) => break,
+        };
+        if res.is_err() {
+            break;
+        }
+    }
+}
+
+async fn server() -> ws::Result<()> {
+    let listener = TcpListener::bind(ADDR).await?;
+    let handshake = HandshakeConfig {
+        #[cfg(feature = "deflate")]
+        permessage_deflate: true,
+        ..Default::default()
+    };
+
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let handshake = handshake.clone();
+        tokio::spawn(async move {
+            if let Ok(ws) = WebSocket::handshake_with(stream, &handshake).await {
+                echo(ws).await;
+            }
+        });
+    }
+}
+
+async fn client() -> ws::Result<()> {
+    let options = ws::ConnectOptions::default();
+    #[cfg(feature = "deflate")]
+    let options = options.permessage_deflate();
+
+    let ws = WebSocket::connect(ADDR, "/getCaseCount").await?;
+    let count: u32 = match ws.read().await? {
+        Frame::Text(text) => text.trim().parse().unwrap_or(0),
+        _ => 0,
+    };
+    ws.close().await.ok();
+
+    for case in 1..=count {
+        let path = format!("/runCase?case={case}&agent={AGENT}");
+        match WebSocket::connect_with(ADDR, &path, &options).await {
+            Ok(ws) => echo(ws).await,
+            Err(e) => eprintln!("case {case}: {e:?}"),
+        }
+    }
+
+    let ws = WebSocket::connect(ADDR, &format!("/updateReports?agent={AGENT}")).await?;
+    while let Ok(frame) = ws.read().await {
+        if matches!(frame, Frame::Close) {
+            break;
+        }
+    }
+    println!("Ran {count} cases");
+    Ok(())
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> ws::Result<()> {
+    match std::env::args().nth(1).as_deref() {
+        Some("server") => server().await,
+        Some("client") => client().await,
+        _ => {
+            eprintln!("usage: autobahn server|client");
+            Ok(())
+        }
+    }
+}
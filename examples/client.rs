@@ -26,9 +26,7 @@ async fn main() -> session_rs::Result<()> {
 
     println!(
         "Invalid data response: {:?}",
-        session
-            .request::<Data>("invalid_data".to_string())
-            .await?
+        session.request::<Data>("invalid_data".to_string()).await?
     );
 
     tokio::time::sleep(tokio::time::Duration::from_millis(1000)).await;
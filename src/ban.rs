@@ -0,0 +1,70 @@
+use std::collections::HashMap;
+
+use tokio::sync::Mutex;
+use tokio::time::{Duration, Instant};
+
+use crate::BoxFuture;
+
+/// Banned clients, keyed by peer IP address or principal. Keep bans where
+/// several server instances can share them (e.g. Redis, see
+/// [`crate::redis::RedisBans`]) so they apply on every instance and
+/// outlive restarts. See [`crate::server::SessionServer::with_bans`].
+pub trait BanStore: Send + Sync {
+    /// Ban `key` for `duration`, or until [`Self::unban`] if `None`
+    fn ban<'a>(
+        &'a self,
+        key: &'a str,
+        reason: &'a str,
+        duration: Option<Duration>,
+    ) -> BoxFuture<'a, ()>;
+    fn unban<'a>(&'a self, key: &'a str) -> BoxFuture<'a, ()>;
+    /// Why `key` is banned, or `None` if it isn't
+    fn banned<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Option<String>>;
+}
+
+/// In-process [`BanStore`], for a single instance. Bans are lost on
+/// restart.
+#[derive(Default)]
+pub struct MemoryBans {
+    /// Reason and expiry of each ban
+    bans: Mutex<HashMap<String, (String, Option<Instant>)>>,
+}
+
+impl MemoryBans {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl BanStore for MemoryBans {
+    fn ban<'a>(
+        &'a self,
+        key: &'a str,
+        reason: &'a str,
+        duration: Option<Duration>,
+    ) -> BoxFuture<'a, ()> {
+        Box::pin(async move {
+            let until = duration.map(|duration| Instant::now() + duration);
+            let mut bans = self.bans.lock().await;
+            bans.insert(key.to_string(), (reason.to_string(), until));
+        })
+    }
+
+    fn unban<'a>(&'a self, key: &'a str) -> BoxFuture<'a, ()> {
+        Box::pin(async move {
+            self.bans.lock().await.remove(key);
+        })
+    }
+
+    fn banned<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Option<String>> {
+        Box::pin(async move {
+            let mut bans = self.bans.lock().await;
+            let (reason, until) = bans.get(key)?;
+            if until.is_some_and(|until| until <= Instant::now()) {
+                bans.remove(key);
+                return None;
+            }
+            Some(reason.clone())
+        })
+    }
+}
@@ -0,0 +1,100 @@
+use std::collections::HashMap;
+use std::marker::PhantomData;
+
+use tokio::time::Duration;
+
+use crate::Method;
+use crate::session::Session;
+
+/// String pairs sent along with a request, e.g. trace ids. Handlers read
+/// them with [`Session::request_metadata`].
+pub type Metadata = HashMap<String, String>;
+
+/// How a call is scheduled on the calling side
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+    /// Waits for the session's rate limit, like [`Session::send_rate_limited`]
+    Low,
+    #[default]
+    Normal,
+    /// Exempt from the in-flight cap, for calls that must not queue behind
+    /// a backlog such as cancellations
+    High,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct CallOptions {
+    pub(crate) timeout: Option<Duration>,
+    pub(crate) priority: Priority,
+    pub(crate) key: Option<String>,
+    pub(crate) metadata: Metadata,
+    pub(crate) compress: bool,
+}
+
+impl Default for CallOptions {
+    fn default() -> Self {
+        Self {
+            timeout: None,
+            priority: Priority::default(),
+            key: None,
+            metadata: Metadata::new(),
+            compress: true,
+        }
+    }
+}
+
+/// Options of a single request, see [`Session::call_builder`]
+pub struct CallBuilder<'a, M: Method> {
+    session: &'a Session,
+    options: CallOptions,
+    method: PhantomData<M>,
+}
+
+impl<'a, M: Method> CallBuilder<'a, M> {
+    pub(crate) fn new(session: &'a Session) -> Self {
+        Self {
+            session,
+            options: CallOptions::default(),
+            method: PhantomData,
+        }
+    }
+
+    /// Fail with `ws::Error::Elapsed` if no response arrives within `dur`,
+    /// counting any wait for the rate limit
+    pub fn timeout(mut self, dur: Duration) -> Self {
+        self.options.timeout = Some(dur);
+        self
+    }
+
+    pub fn priority(mut self, priority: Priority) -> Self {
+        self.options.priority = priority;
+        self
+    }
+
+    /// Idempotency key, see [`crate::idempotency::IdempotencyStore`]
+    pub fn key(mut self, key: &str) -> Self {
+        self.options.key = Some(key.to_string());
+        self
+    }
+
+    /// Send the request without permessage-deflate, for payloads that are
+    /// already compressed or encrypted
+    pub fn no_compress(mut self) -> Self {
+        self.options.compress = false;
+        self
+    }
+
+    pub fn metadata(mut self, key: &str, value: impl ToString) -> Self {
+        self.options
+            .metadata
+            .insert(key.to_string(), value.to_string());
+        self
+    }
+
+    pub async fn send(
+        self,
+        req: M::Request,
+    ) -> crate::Result<std::result::Result<M::Response, M::Error>> {
+        self.session.call::<M>(req, self.options).await
+    }
+}
@@ -0,0 +1,85 @@
+use std::marker::PhantomData;
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+
+use crate::Method;
+use crate::session::{Message, Session};
+
+/// Carries a channel's item type through [`Message`]
+pub(crate) struct ChannelMethod<T>(PhantomData<T>);
+
+impl<T> Method for ChannelMethod<T>
+where
+    T: Serialize + for<'de> Deserialize<'de> + Send + Sync,
+{
+    const NAME: &'static str = "channel";
+    type Request = T;
+    type Response = ();
+    type Error = ();
+}
+
+/// A named, long-lived stream of `T` in both directions over a session,
+/// opened with [`Session::channel`] and accepted with
+/// [`Session::accept_channel`]
+pub struct Channel<T> {
+    session: Session,
+    name: String,
+    rx: mpsc::UnboundedReceiver<serde_json::Value>,
+    _item: PhantomData<fn() -> T>,
+}
+
+impl<T> Channel<T>
+where
+    T: Serialize + for<'de> Deserialize<'de> + Send + Sync,
+{
+    pub(crate) fn new(
+        session: Session,
+        name: String,
+        rx: mpsc::UnboundedReceiver<serde_json::Value>,
+    ) -> Self {
+        Self {
+            session,
+            name,
+            rx,
+            _item: PhantomData,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub async fn send(&self, data: T) -> crate::Result<()> {
+        self.session
+            .send::<ChannelMethod<T>>(&Message::ChannelData {
+                channel: self.name.clone(),
+                data,
+            })
+            .await
+    }
+
+    /// Next item from the peer, or `None` once either side closed the
+    /// channel or the session ended. Items that don't decode as `T` are
+    /// skipped.
+    pub async fn recv(&mut self) -> Option<T> {
+        loop {
+            let value = self.rx.recv().await?;
+
+            if let Ok(data) = serde_json::from_value(value) {
+                return Some(data);
+            }
+        }
+    }
+
+    /// Close the channel on both sides
+    pub async fn close(self) -> crate::Result<()> {
+        self.session.remove_channel(&self.name).await;
+
+        self.session
+            .send::<ChannelMethod<T>>(&Message::ChannelClose {
+                channel: self.name.clone(),
+            })
+            .await
+    }
+}
@@ -0,0 +1,182 @@
+use std::collections::{HashMap, HashSet};
+
+use tokio::time::{Duration, Instant};
+
+use crate::log::{self, Level};
+
+/// Recorded for a connection that ended without a close frame
+pub const ABNORMAL: u16 = 1006;
+
+/// Recorded for a close frame without a status code
+pub const NO_STATUS: u16 = 1005;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+/// Close codes counted during one window
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CloseCounts {
+    pub sent: HashMap<u16, u64>,
+    pub received: HashMap<u16, u64>,
+}
+
+impl CloseCounts {
+    fn get(&self, direction: Direction, code: u16) -> u64 {
+        let counts = match direction {
+            Direction::Sent => &self.sent,
+            Direction::Received => &self.received,
+        };
+        counts.get(&code).copied().unwrap_or(0)
+    }
+
+    fn add(&mut self, direction: Direction, code: u16) -> u64 {
+        let counts = match direction {
+            Direction::Sent => &mut self.sent,
+            Direction::Received => &mut self.received,
+        };
+        let count = counts.entry(code).or_default();
+        *count += 1;
+        *count
+    }
+}
+
+/// A close code that spiked, passed to [`CloseStats::on_anomaly`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CloseAnomaly {
+    pub code: u16,
+    pub direction: Direction,
+    /// Count in the current window so far
+    pub count: u64,
+    /// Count in the previous window
+    pub previous: u64,
+}
+
+type AnomalyHook = Box<dyn Fn(CloseAnomaly) + Send + Sync>;
+
+struct Windows {
+    started: Instant,
+    current: CloseCounts,
+    previous: CloseCounts,
+    /// Codes already reported in the current window
+    reported: HashSet<(Direction, u16)>,
+}
+
+/// Counts of close codes sent and received per fixed time window, with a
+/// hook for codes that spike compared to the previous window, e.g. a flood
+/// of 1006 after a bad client release. Shared by every session of a server,
+/// see [`crate::server::SessionServer::with_close_stats`].
+pub struct CloseStats {
+    window: Duration,
+    min_count: u64,
+    factor: f64,
+    windows: std::sync::Mutex<Windows>,
+    on_anomaly: Option<AnomalyHook>,
+}
+
+impl CloseStats {
+    /// Reports a code once it reaches 10 in a window and more than three
+    /// times its count in the previous one; see [`CloseStats::spike`]
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            min_count: 10,
+            factor: 3.0,
+            windows: std::sync::Mutex::new(Windows {
+                started: Instant::now(),
+                current: CloseCounts::default(),
+                previous: CloseCounts::default(),
+                reported: HashSet::new(),
+            }),
+            on_anomaly: None,
+        }
+    }
+
+    /// Report a code once its count in a window reaches `min_count` and
+    /// exceeds `factor` times its count in the previous window
+    pub fn spike(mut self, min_count: u64, factor: f64) -> Self {
+        self.min_count = min_count;
+        self.factor = factor;
+        self
+    }
+
+    /// Called at most once per code, direction and window
+    pub fn on_anomaly(mut self, hook: impl Fn(CloseAnomaly) + Send + Sync + 'static) -> Self {
+        self.on_anomaly = Some(Box::new(hook));
+        self
+    }
+
+    /// Counts of the window in progress
+    pub fn current(&self) -> CloseCounts {
+        let mut windows = self.lock();
+        self.rotate(&mut windows);
+        windows.current.clone()
+    }
+
+    /// Counts of the last complete window
+    pub fn previous(&self) -> CloseCounts {
+        let mut windows = self.lock();
+        self.rotate(&mut windows);
+        windows.previous.clone()
+    }
+
+    pub(crate) fn record(&self, direction: Direction, code: u16) {
+        let anomaly = {
+            let mut windows = self.lock();
+            self.rotate(&mut windows);
+
+            let count = windows.current.add(direction, code);
+            let previous = windows.previous.get(direction, code);
+
+            let spiked = count >= self.min_count && count as f64 > previous as f64 * self.factor;
+            (spiked && windows.reported.insert((direction, code))).then_some(CloseAnomaly {
+                code,
+                direction,
+                count,
+                previous,
+            })
+        };
+
+        if let Some(anomaly) = anomaly {
+            log::log(
+                Level::Warn,
+                "close_anomaly",
+                &[
+                    ("code", &anomaly.code),
+                    ("direction", &format!("{:?}", anomaly.direction)),
+                    ("count", &anomaly.count),
+                    ("previous", &anomaly.previous),
+                ],
+            );
+            if let Some(hook) = &self.on_anomaly {
+                hook(anomaly);
+            }
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Windows> {
+        self.windows.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Start a new window if the current one is over; after a gap of more
+    /// than one window the previous counts are empty
+    fn rotate(&self, windows: &mut Windows) {
+        let elapsed = windows.started.elapsed();
+        if elapsed < self.window {
+            return;
+        }
+
+        windows.previous = if elapsed < self.window * 2 {
+            std::mem::take(&mut windows.current)
+        } else {
+            windows.current = CloseCounts::default();
+            CloseCounts::default()
+        };
+        windows.reported.clear();
+
+        let windows_passed = (elapsed.as_nanos() / self.window.as_nanos().max(1)) as u32;
+        windows.started += self.window * windows_passed;
+    }
+}
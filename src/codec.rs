@@ -0,0 +1,146 @@
+/// Turns messages into frame payloads and back. Without a codec, sessions
+/// exchange JSON in text frames.
+pub trait Codec: Send + Sync {
+    /// Describes the encoding to the peer, usable as the subprotocol name
+    /// (see [`crate::server::SessionServer::with_codec`])
+    fn name(&self) -> String;
+    /// Whether payloads go in binary frames rather than text frames
+    fn binary(&self) -> bool;
+    fn encode(&self, message: &serde_json::Value) -> crate::Result<Vec<u8>>;
+    fn decode(&self, payload: &[u8]) -> crate::Result<serde_json::Value>;
+}
+
+/// JSON in text frames, the same as having no codec
+pub struct Json;
+
+impl Codec for Json {
+    fn name(&self) -> String {
+        "json".to_string()
+    }
+
+    fn binary(&self) -> bool {
+        false
+    }
+
+    fn encode(&self, message: &serde_json::Value) -> crate::Result<Vec<u8>> {
+        Ok(serde_json::to_vec(message)?)
+    }
+
+    fn decode(&self, payload: &[u8]) -> crate::Result<serde_json::Value> {
+        Ok(serde_json::from_slice(payload)?)
+    }
+}
+
+/// MessagePack in binary frames
+#[cfg(feature = "msgpack")]
+pub struct MsgPack;
+
+#[cfg(feature = "msgpack")]
+impl Codec for MsgPack {
+    fn name(&self) -> String {
+        "msgpack".to_string()
+    }
+
+    fn binary(&self) -> bool {
+        true
+    }
+
+    fn encode(&self, message: &serde_json::Value) -> crate::Result<Vec<u8>> {
+        rmp_serde::to_vec_named(message).map_err(|e| crate::Error::Codec(e.to_string()))
+    }
+
+    fn decode(&self, payload: &[u8]) -> crate::Result<serde_json::Value> {
+        rmp_serde::from_slice(payload).map_err(|e| crate::Error::Codec(e.to_string()))
+    }
+}
+
+/// Transform applied to encoded payloads, such as compression or encryption
+pub trait Layer: Send + Sync {
+    fn name(&self) -> &str;
+    fn wrap(&self, payload: Vec<u8>) -> crate::Result<Vec<u8>>;
+    fn unwrap(&self, payload: &[u8]) -> crate::Result<Vec<u8>>;
+}
+
+/// A codec whose payloads pass through layers, e.g.
+/// `Layered::new(MsgPack).layer(Gzip)`. Layers wrap in the order they are
+/// added and unwrap in reverse. The name lists the stack outermost first,
+/// like `gzip+msgpack`, so both sides agree on it through the subprotocol.
+pub struct Layered {
+    codec: Box<dyn Codec>,
+    layers: Vec<Box<dyn Layer>>,
+}
+
+impl Layered {
+    pub fn new(codec: impl Codec + 'static) -> Self {
+        Self {
+            codec: Box::new(codec),
+            layers: Vec::new(),
+        }
+    }
+
+    pub fn layer(mut self, layer: impl Layer + 'static) -> Self {
+        self.layers.push(Box::new(layer));
+        self
+    }
+}
+
+impl Codec for Layered {
+    fn name(&self) -> String {
+        let mut parts: Vec<String> = self
+            .layers
+            .iter()
+            .rev()
+            .map(|l| l.name().to_string())
+            .collect();
+        parts.push(self.codec.name());
+        parts.join("+")
+    }
+
+    /// Layers may produce arbitrary bytes
+    fn binary(&self) -> bool {
+        self.codec.binary() || !self.layers.is_empty()
+    }
+
+    fn encode(&self, message: &serde_json::Value) -> crate::Result<Vec<u8>> {
+        let mut payload = self.codec.encode(message)?;
+        for layer in &self.layers {
+            payload = layer.wrap(payload)?;
+        }
+        Ok(payload)
+    }
+
+    fn decode(&self, payload: &[u8]) -> crate::Result<serde_json::Value> {
+        let mut payload = payload.to_vec();
+        for layer in self.layers.iter().rev() {
+            payload = layer.unwrap(&payload)?;
+        }
+        self.codec.decode(&payload)
+    }
+}
+
+/// Gzip compression layer
+#[cfg(feature = "gzip")]
+pub struct Gzip;
+
+#[cfg(feature = "gzip")]
+impl Layer for Gzip {
+    fn name(&self) -> &str {
+        "gzip"
+    }
+
+    fn wrap(&self, payload: Vec<u8>) -> crate::Result<Vec<u8>> {
+        use std::io::Write;
+
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(&payload)?;
+        Ok(encoder.finish()?)
+    }
+
+    fn unwrap(&self, payload: &[u8]) -> crate::Result<Vec<u8>> {
+        use std::io::Read;
+
+        let mut out = Vec::new();
+        flate2::read::GzDecoder::new(payload).read_to_end(&mut out)?;
+        Ok(out)
+    }
+}
@@ -0,0 +1,322 @@
+//! Compact binary framing for backend links. Each message is a kind byte,
+//! varint method and request ids in place of JSON field names, and the
+//! payload encoded by an inner codec:
+//!
+//! ```text
+//! kind | flags   method (varint id, or varint length + name)
+//!                 id (varint), requests and responses
+//!                 key (varint length + bytes), if flagged
+//!                 metadata (varint count + length-prefixed pairs), if flagged
+//!                 seq (varint), if flagged
+//!                 channel (varint length + name), channel messages
+//!                 payload, the rest
+//! ```
+
+use std::collections::HashMap;
+
+use serde_json::{Map, Value};
+
+use crate::Method;
+use crate::codec::{Codec, Json};
+
+const REQUEST: u8 = 0;
+const RESPONSE: u8 = 1;
+const ERROR_RESPONSE: u8 = 2;
+const NOTIFICATION: u8 = 3;
+const CHANNEL_OPEN: u8 = 4;
+const CHANNEL_DATA: u8 = 5;
+const CHANNEL_CLOSE: u8 = 6;
+
+const KIND_MASK: u8 = 0x0f;
+/// The method is sent by name, not being in the registry
+const NAMED_METHOD: u8 = 0x10;
+const HAS_KEY: u8 = 0x20;
+const HAS_SEQ: u8 = 0x40;
+const HAS_META: u8 = 0x80;
+
+/// Numeric ids standing in for method names. Both sides must build the same
+/// registry; [`BinaryEnvelope::name`] includes a hash of it so sessions with
+/// different registries don't negotiate the envelope.
+#[derive(Debug, Clone, Default)]
+pub struct MethodIds {
+    by_name: HashMap<String, u64>,
+    by_id: HashMap<u64, String>,
+}
+
+impl MethodIds {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn method<M: Method>(mut self, id: u64) -> Self {
+        self.by_name.insert(M::NAME.to_string(), id);
+        self.by_id.insert(id, M::NAME.to_string());
+        self
+    }
+
+    /// FNV-1a over the sorted pairs, stable across builds
+    fn fingerprint(&self) -> u32 {
+        let mut pairs: Vec<_> = self.by_name.iter().collect();
+        pairs.sort();
+
+        let mut hash: u32 = 0x811c_9dc5;
+        for (name, id) in pairs {
+            for byte in name.bytes().chain(id.to_le_bytes()) {
+                hash ^= byte as u32;
+                hash = hash.wrapping_mul(0x0100_0193);
+            }
+        }
+        hash
+    }
+}
+
+/// [`Codec`] for the binary envelope. Payloads go through JSON unless set
+/// otherwise with [`Self::payload`].
+pub struct BinaryEnvelope {
+    ids: MethodIds,
+    payload: Box<dyn Codec>,
+}
+
+impl BinaryEnvelope {
+    pub fn new(ids: MethodIds) -> Self {
+        Self {
+            ids,
+            payload: Box::new(Json),
+        }
+    }
+
+    pub fn payload(mut self, codec: impl Codec + 'static) -> Self {
+        self.payload = Box::new(codec);
+        self
+    }
+
+    fn write_method(&self, out: &mut Vec<u8>, flags: &mut u8, name: &str) {
+        match self.ids.by_name.get(name) {
+            Some(id) => write_varint(out, *id),
+            None => {
+                *flags |= NAMED_METHOD;
+                write_bytes(out, name.as_bytes());
+            }
+        }
+    }
+
+    fn read_method(&self, r: &mut Reader, flags: u8) -> crate::Result<String> {
+        if flags & NAMED_METHOD != 0 {
+            return r.string();
+        }
+
+        let id = r.varint()?;
+        self.ids
+            .by_id
+            .get(&id)
+            .cloned()
+            .ok_or_else(|| invalid(format!("unknown method id {id}")))
+    }
+}
+
+impl Codec for BinaryEnvelope {
+    fn name(&self) -> String {
+        format!(
+            "envelope-{:08x}+{}",
+            self.ids.fingerprint(),
+            self.payload.name()
+        )
+    }
+
+    fn binary(&self) -> bool {
+        true
+    }
+
+    fn encode(&self, message: &Value) -> crate::Result<Vec<u8>> {
+        let field = |name: &str| message.get(name).filter(|v| !v.is_null());
+        let str_field = |name: &str| {
+            field(name)
+                .and_then(Value::as_str)
+                .ok_or_else(|| invalid(format!("missing {name}")))
+        };
+        let id_field = || {
+            field("id")
+                .and_then(Value::as_u64)
+                .ok_or_else(|| invalid("missing id"))
+        };
+
+        let kind = match str_field("type")? {
+            "request" => REQUEST,
+            "response" => RESPONSE,
+            "errorresponse" => ERROR_RESPONSE,
+            "notification" => NOTIFICATION,
+            "channelopen" => CHANNEL_OPEN,
+            "channeldata" => CHANNEL_DATA,
+            "channelclose" => CHANNEL_CLOSE,
+            other => return Err(invalid(format!("unknown message type {other}"))),
+        };
+
+        let mut flags = 0;
+        let mut body = Vec::new();
+        let payload = match kind {
+            REQUEST => {
+                self.write_method(&mut body, &mut flags, str_field("method")?);
+                write_varint(&mut body, id_field()?);
+                if let Some(key) = field("key").and_then(Value::as_str) {
+                    flags |= HAS_KEY;
+                    write_bytes(&mut body, key.as_bytes());
+                }
+                if let Some(meta) = field("meta").and_then(Value::as_object)
+                    && !meta.is_empty()
+                {
+                    flags |= HAS_META;
+                    write_varint(&mut body, meta.len() as u64);
+                    for (k, v) in meta {
+                        let v = v.as_str().ok_or_else(|| invalid("non-string metadata"))?;
+                        write_bytes(&mut body, k.as_bytes());
+                        write_bytes(&mut body, v.as_bytes());
+                    }
+                }
+                field("data")
+            }
+            RESPONSE => {
+                write_varint(&mut body, id_field()?);
+                field("result")
+            }
+            ERROR_RESPONSE => {
+                write_varint(&mut body, id_field()?);
+                field("error")
+            }
+            NOTIFICATION => {
+                self.write_method(&mut body, &mut flags, str_field("method")?);
+                if let Some(seq) = field("seq").and_then(Value::as_u64) {
+                    flags |= HAS_SEQ;
+                    write_varint(&mut body, seq);
+                }
+                field("data")
+            }
+            _ => {
+                write_bytes(&mut body, str_field("channel")?.as_bytes());
+                field("data")
+            }
+        };
+
+        let mut out = Vec::with_capacity(body.len() + 16);
+        out.push(kind | flags);
+        out.extend(body);
+        if let Some(payload) = payload {
+            out.extend(self.payload.encode(payload)?);
+        }
+        Ok(out)
+    }
+
+    fn decode(&self, payload: &[u8]) -> crate::Result<Value> {
+        let mut r = Reader(payload);
+        let head = r.byte()?;
+        let (kind, flags) = (head & KIND_MASK, head & !KIND_MASK);
+
+        let mut m = Map::new();
+        let data = match kind {
+            REQUEST => {
+                m.insert("type".into(), "request".into());
+                m.insert("method".into(), self.read_method(&mut r, flags)?.into());
+                m.insert("id".into(), r.varint()?.into());
+                if flags & HAS_KEY != 0 {
+                    m.insert("key".into(), r.string()?.into());
+                }
+                if flags & HAS_META != 0 {
+                    let mut meta = Map::new();
+                    for _ in 0..r.varint()? {
+                        let key = r.string()?;
+                        meta.insert(key, r.string()?.into());
+                    }
+                    m.insert("meta".into(), Value::Object(meta));
+                }
+                "data"
+            }
+            RESPONSE | ERROR_RESPONSE => {
+                let (ty, data) = match kind {
+                    RESPONSE => ("response", "result"),
+                    _ => ("errorresponse", "error"),
+                };
+                m.insert("type".into(), ty.into());
+                m.insert("id".into(), r.varint()?.into());
+                data
+            }
+            NOTIFICATION => {
+                m.insert("type".into(), "notification".into());
+                m.insert("method".into(), self.read_method(&mut r, flags)?.into());
+                if flags & HAS_SEQ != 0 {
+                    m.insert("seq".into(), r.varint()?.into());
+                }
+                "data"
+            }
+            CHANNEL_OPEN | CHANNEL_DATA | CHANNEL_CLOSE => {
+                let ty = match kind {
+                    CHANNEL_OPEN => "channelopen",
+                    CHANNEL_DATA => "channeldata",
+                    _ => "channelclose",
+                };
+                m.insert("type".into(), ty.into());
+                m.insert("channel".into(), r.string()?.into());
+                "data"
+            }
+            other => return Err(invalid(format!("unknown message kind {other}"))),
+        };
+
+        // Unit payloads serialize as null and are left out entirely
+        let value = match r.0.is_empty() {
+            true => Value::Null,
+            false => self.payload.decode(r.0)?,
+        };
+        if !(kind == CHANNEL_OPEN || kind == CHANNEL_CLOSE) {
+            m.insert(data.into(), value);
+        }
+
+        Ok(Value::Object(m))
+    }
+}
+
+fn invalid(reason: impl Into<String>) -> crate::Error {
+    crate::Error::Codec(reason.into())
+}
+
+fn write_varint(out: &mut Vec<u8>, mut n: u64) {
+    while n >= 0x80 {
+        out.push(n as u8 | 0x80);
+        n >>= 7;
+    }
+    out.push(n as u8);
+}
+
+fn write_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    write_varint(out, bytes.len() as u64);
+    out.extend_from_slice(bytes);
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl Reader<'_> {
+    fn byte(&mut self) -> crate::Result<u8> {
+        let (first, rest) = self.0.split_first().ok_or_else(|| invalid("truncated"))?;
+        self.0 = rest;
+        Ok(*first)
+    }
+
+    fn varint(&mut self) -> crate::Result<u64> {
+        let mut n = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.byte()?;
+            n |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(n);
+            }
+        }
+        Err(invalid("varint too long"))
+    }
+
+    fn string(&mut self) -> crate::Result<String> {
+        let len = self.varint()? as usize;
+        if len > self.0.len() {
+            return Err(invalid("truncated"));
+        }
+        let (bytes, rest) = self.0.split_at(len);
+        self.0 = rest;
+        String::from_utf8(bytes.to_vec()).map_err(|e| invalid(e.to_string()))
+    }
+}
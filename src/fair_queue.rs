@@ -0,0 +1,79 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+
+struct Topic {
+    weight: u32,
+    queue: VecDeque<Arc<[u8]>>,
+}
+
+/// Per-topic outbound queues drained by weighted round robin: each round
+/// takes up to `weight` messages from every topic with something queued, so
+/// a busy topic can't hold back a quiet one.
+pub(crate) struct FairQueue {
+    topics: HashMap<Arc<str>, Topic>,
+    weights: HashMap<Arc<str>, u32>,
+    /// Topics with queued messages, in round robin order
+    active: VecDeque<Arc<str>>,
+}
+
+impl FairQueue {
+    pub(crate) fn new() -> Self {
+        Self {
+            topics: HashMap::new(),
+            weights: HashMap::new(),
+            active: VecDeque::new(),
+        }
+    }
+
+    pub(crate) fn set_weight(&mut self, topic: &str, weight: u32) {
+        let weight = weight.max(1);
+
+        self.weights.insert(topic.into(), weight);
+        if let Some(t) = self.topics.get_mut(topic) {
+            t.weight = weight;
+        }
+    }
+
+    pub(crate) fn push(&mut self, topic: &Arc<str>, payload: Arc<[u8]>) {
+        let weight = self.weights.get(topic).copied().unwrap_or(1);
+        let t = self.topics.entry(topic.clone()).or_insert_with(|| Topic {
+            weight,
+            queue: VecDeque::new(),
+        });
+
+        if t.queue.is_empty() {
+            self.active.push_back(topic.clone());
+        }
+        t.queue.push_back(payload);
+    }
+
+    /// Messages waiting across all topics
+    pub(crate) fn len(&self) -> usize {
+        self.topics.values().map(|t| t.queue.len()).sum()
+    }
+
+    /// Messages of one round, empty once everything is drained
+    pub(crate) fn next_round(&mut self) -> Vec<Arc<[u8]>> {
+        let mut round = Vec::new();
+
+        for _ in 0..self.active.len() {
+            let Some(topic) = self.active.pop_front() else {
+                break;
+            };
+            let Some(t) = self.topics.get_mut(&topic) else {
+                continue;
+            };
+
+            let n = (t.weight as usize).min(t.queue.len());
+            round.extend(t.queue.drain(..n));
+
+            if t.queue.is_empty() {
+                self.topics.remove(&topic);
+            } else {
+                self.active.push_back(topic);
+            }
+        }
+
+        round
+    }
+}
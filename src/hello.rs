@@ -0,0 +1,101 @@
+//! Capability exchange between two session-rs endpoints. A connecting
+//! [`Session`](crate::session::Session) asks for it with the `Session-Hello`
+//! upgrade header and a [`SessionServer`](crate::server::SessionServer)
+//! agrees by answering with it. Both then send one hello listing their
+//! [`Capabilities`] ahead of anything else and switch on what they have in
+//! common. Other peers never get the header answered, so for them nothing
+//! changes.
+
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+
+use crate::codec::{self, Codec};
+
+pub const HELLO_HEADER: &str = "Session-Hello";
+
+/// Version of the hello this build sends
+pub const VERSION: u32 = 1;
+
+/// Multiplexed channels, see [`Session::channel`](crate::session::Session::channel)
+pub const CHANNELS: &str = "channels";
+/// Acknowledged pushes, see [`Session::push`](crate::session::Session::push)
+pub const ACKS: &str = "acks";
+/// Session tickets to resume with after a reconnect
+pub const TICKETS: &str = "tickets";
+
+/// What an endpoint supports, each list most preferred first
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Capabilities {
+    pub version: u32,
+    /// Codec names, see [`Codec::name`]
+    pub codecs: Vec<String>,
+    /// Compression extension tokens
+    pub compression: Vec<String>,
+    /// Optional protocol features such as [`CHANNELS`] and [`ACKS`]
+    pub features: Vec<String>,
+}
+
+impl Capabilities {
+    /// Everything this build supports
+    pub fn local() -> Self {
+        let codecs = [
+            #[cfg(feature = "msgpack")]
+            codec::MsgPack.name(),
+            codec::Json.name(),
+        ];
+        let compression: [&str; _] = [
+            #[cfg(feature = "zstd")]
+            "x-permessage-zstd",
+            #[cfg(feature = "deflate")]
+            "permessage-deflate",
+        ];
+
+        Self {
+            version: VERSION,
+            codecs: codecs.to_vec(),
+            compression: compression.map(String::from).to_vec(),
+            features: [CHANNELS, ACKS, TICKETS].map(String::from).to_vec(),
+        }
+    }
+
+    /// What `server` and `client` both support, in the server's order of
+    /// preference
+    pub fn common(server: &Self, client: &Self) -> Self {
+        let both = |ours: &[String], theirs: &[String]| {
+            ours.iter()
+                .filter(|item| theirs.contains(item))
+                .cloned()
+                .collect()
+        };
+
+        Self {
+            version: server.version.min(client.version),
+            codecs: both(&server.codecs, &client.codecs),
+            compression: both(&server.compression, &client.compression),
+            features: both(&server.features, &client.features),
+        }
+    }
+
+    pub fn has(&self, feature: &str) -> bool {
+        self.features.iter().any(|f| f == feature)
+    }
+}
+
+/// The hello itself, tagged like a message so it can't be taken for one
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", rename = "hello")]
+pub(crate) struct Hello {
+    #[serde(flatten)]
+    pub capabilities: Capabilities,
+}
+
+/// Codec named `name` in a hello, if this build has it
+pub(crate) fn codec(name: &str) -> Option<Arc<dyn Codec>> {
+    match name {
+        "json" => Some(Arc::new(codec::Json)),
+        #[cfg(feature = "msgpack")]
+        "msgpack" => Some(Arc::new(codec::MsgPack)),
+        _ => None,
+    }
+}
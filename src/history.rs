@@ -0,0 +1,115 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use tokio::time::{Duration, Instant};
+
+use crate::{BoxFuture, Method};
+
+/// Storage for the recent broadcasts of rooms with history, see
+/// [`crate::hub::Hub::set_history`]. Payloads are complete notifications,
+/// ready to resend.
+pub trait HistoryStore: Send + Sync {
+    fn append<'a>(&'a self, topic: &'a str, seq: u64, payload: Arc<[u8]>) -> BoxFuture<'a, ()>;
+    /// Retained messages of `topic` after `seq`, oldest first
+    fn since<'a>(&'a self, topic: &'a str, seq: u64) -> BoxFuture<'a, Vec<Arc<[u8]>>>;
+    /// Highest sequence number recorded for `topic`, so numbering continues
+    /// where a persistent store left off
+    fn last_seq<'a>(&'a self, topic: &'a str) -> BoxFuture<'a, Option<u64>>;
+}
+
+/// A retained message: (seq, when it was appended, payload)
+type Retained = (u64, Instant, Arc<[u8]>);
+
+/// How much history a [`MemoryHistory`] keeps per topic
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Retention {
+    pub max_messages: Option<usize>,
+    pub max_age: Option<Duration>,
+}
+
+/// In-process [`HistoryStore`]
+pub struct MemoryHistory {
+    retention: Retention,
+    topics: Mutex<HashMap<String, VecDeque<Retained>>>,
+}
+
+impl MemoryHistory {
+    pub fn new(retention: Retention) -> Self {
+        Self {
+            retention,
+            topics: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn expire(&self, messages: &mut VecDeque<Retained>) {
+        if let Some(max) = self.retention.max_messages {
+            while messages.len() > max {
+                messages.pop_front();
+            }
+        }
+        if let Some(max_age) = self.retention.max_age {
+            while messages
+                .front()
+                .is_some_and(|(_, at, _)| at.elapsed() > max_age)
+            {
+                messages.pop_front();
+            }
+        }
+    }
+}
+
+impl HistoryStore for MemoryHistory {
+    fn append<'a>(&'a self, topic: &'a str, seq: u64, payload: Arc<[u8]>) -> BoxFuture<'a, ()> {
+        Box::pin(async move {
+            let mut topics = self.topics.lock().await;
+            let messages = topics.entry(topic.to_string()).or_default();
+
+            messages.push_back((seq, Instant::now(), payload));
+            self.expire(messages);
+        })
+    }
+
+    fn since<'a>(&'a self, topic: &'a str, seq: u64) -> BoxFuture<'a, Vec<Arc<[u8]>>> {
+        Box::pin(async move {
+            let mut topics = self.topics.lock().await;
+            let Some(messages) = topics.get_mut(topic) else {
+                return Vec::new();
+            };
+
+            self.expire(messages);
+            messages
+                .iter()
+                .filter(|(s, _, _)| *s > seq)
+                .map(|(_, _, payload)| payload.clone())
+                .collect()
+        })
+    }
+
+    fn last_seq<'a>(&'a self, topic: &'a str) -> BoxFuture<'a, Option<u64>> {
+        Box::pin(async move {
+            let topics = self.topics.lock().await;
+            topics.get(topic)?.back().map(|(seq, _, _)| *seq)
+        })
+    }
+}
+
+/// Built-in request of sessions in a [`crate::hub::Hub`] room: resend the
+/// room's retained broadcasts after `since` as ordinary notifications.
+/// Responds with how many were resent.
+pub struct ReplaySince;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Replay {
+    pub topic: String,
+    /// `seq` of the last notification received; 0 replays everything
+    pub since: u64,
+}
+
+impl Method for ReplaySince {
+    const NAME: &'static str = "replay_since";
+    type Request = Replay;
+    type Response = usize;
+    type Error = String;
+}
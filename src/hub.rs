@@ -0,0 +1,900 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+use tokio::sync::Mutex;
+use tokio::task::{JoinHandle, JoinSet};
+use tokio::time::{Duration, MissedTickBehavior};
+
+use crate::call::CallOptions;
+use crate::history::{HistoryStore, Replay, ReplaySince};
+use crate::log::{self, Level};
+use crate::registry::{Registry, Rooms, add_member, remove_member};
+use crate::session::{Message, Session, SessionSnapshot};
+use crate::{BoxFuture, Method};
+
+/// When queued broadcasts are flushed automatically, besides [`Hub::flush`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Batching {
+    /// Flush once this many broadcasts are queued
+    pub max_messages: Option<usize>,
+    /// Flush every `interval`
+    pub interval: Option<Duration>,
+}
+
+/// What happens when a principal opens more sessions than allowed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimitPolicy {
+    /// Refuse the new session
+    Reject,
+    /// Close the principal's oldest session to make room
+    EvictOldest,
+}
+
+/// Spreads [`Hub::broadcast`] to a large room over `window`, sending to an
+/// equal share of the members every `tick`, so a single broadcast doesn't
+/// flood the scheduler with sends at once
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimeSlicing {
+    /// Rooms with fewer members are sent to all at once
+    pub min_members: usize,
+    pub window: Duration,
+    pub tick: Duration,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PrincipalLimit {
+    pub max_sessions: usize,
+    pub policy: LimitPolicy,
+}
+
+type EvictHandler =
+    Arc<dyn Fn(Session, String) -> BoxFuture<'static, Result<(), String>> + Send + Sync>;
+
+/// Whether a session may be in a room, see [`Hub::set_room_access`]
+type AccessCheck = dyn Fn(Session, String) -> BoxFuture<'static, bool> + Send + Sync;
+
+type RevokeHandler =
+    Box<dyn Fn(Session, String) -> BoxFuture<'static, Result<(), String>> + Send + Sync>;
+
+/// A serialized broadcast waiting for the next flush: (room, payload)
+type QueuedBroadcast = (Arc<str>, Arc<[u8]>);
+
+/// Last sequence number of a room, `None` until read from its store
+type RoomSeq = Arc<Mutex<Option<u64>>>;
+
+/// Outcome of one session's call in [`Hub::call_all`]
+pub type CallResult<M> =
+    crate::Result<std::result::Result<<M as Method>::Response, <M as Method>::Error>>;
+
+/// Key/value labels attached to a session, e.g. `tenant=acme`
+pub type Tags = HashMap<String, String>;
+
+/// Registry of sessions grouped into named rooms
+pub struct Hub {
+    sessions: Arc<Registry>,
+    rooms: Arc<Rooms>,
+    tags: Arc<Mutex<HashMap<u64, Tags>>>,
+    queued: Arc<Mutex<Vec<QueuedBroadcast>>>,
+    batching: Arc<Mutex<Batching>>,
+    flusher: Arc<Mutex<Option<JoinHandle<()>>>>,
+    time_slicing: Arc<Mutex<Option<TimeSlicing>>>,
+    /// Session ids per principal, oldest first
+    principals: Arc<Mutex<HashMap<String, Vec<u64>>>>,
+    principal_limit: Arc<Mutex<Option<PrincipalLimit>>>,
+    on_evict_fn: Arc<Mutex<Option<EvictHandler>>>,
+    room_access: Arc<Mutex<Option<Arc<AccessCheck>>>>,
+    on_revoke_fn: Arc<Mutex<Option<RevokeHandler>>>,
+    history: Arc<Mutex<HashMap<String, Arc<dyn HistoryStore>>>>,
+    /// Last sequence number per room with history. Each room has its own
+    /// lock so appends don't wait on other rooms.
+    seqs: Arc<Mutex<HashMap<String, RoomSeq>>>,
+}
+
+impl Clone for Hub {
+    fn clone(&self) -> Self {
+        Self {
+            sessions: self.sessions.clone(),
+            rooms: self.rooms.clone(),
+            tags: self.tags.clone(),
+            queued: self.queued.clone(),
+            batching: self.batching.clone(),
+            flusher: self.flusher.clone(),
+            time_slicing: self.time_slicing.clone(),
+            principals: self.principals.clone(),
+            principal_limit: self.principal_limit.clone(),
+            on_evict_fn: self.on_evict_fn.clone(),
+            room_access: self.room_access.clone(),
+            on_revoke_fn: self.on_revoke_fn.clone(),
+            history: self.history.clone(),
+            seqs: self.seqs.clone(),
+        }
+    }
+}
+
+impl Default for Hub {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Hub {
+    pub fn new() -> Self {
+        Self {
+            sessions: Arc::new(Registry::default()),
+            rooms: Arc::new(Rooms::default()),
+            tags: Arc::new(Mutex::new(HashMap::new())),
+            queued: Arc::new(Mutex::new(Vec::new())),
+            batching: Arc::new(Mutex::new(Batching::default())),
+            flusher: Arc::new(Mutex::new(None)),
+            time_slicing: Arc::new(Mutex::new(None)),
+            principals: Arc::new(Mutex::new(HashMap::new())),
+            principal_limit: Arc::new(Mutex::new(None)),
+            on_evict_fn: Arc::new(Mutex::new(None)),
+            room_access: Arc::new(Mutex::new(None)),
+            on_revoke_fn: Arc::new(Mutex::new(None)),
+            history: Arc::new(Mutex::new(HashMap::new())),
+            seqs: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Register a session; it is removed from the hub once it closes. The
+    /// session can request [`ReplaySince`] for the rooms it is in.
+    pub async fn add(&self, session: &Session) {
+        if self.sessions.insert(session).is_some() {
+            return;
+        }
+
+        let (hub, id) = (self.clone(), session.id());
+        session
+            .on_request::<ReplaySince, _>(move |_, req| {
+                let hub = hub.clone();
+                async move { hub.replay_request(id, req).await }
+            })
+            .await;
+
+        let hub = self.clone();
+        let session = session.clone();
+        tokio::spawn(async move {
+            let mut principal = session.watch_principal();
+            loop {
+                tokio::select! {
+                    _ = session.closed() => break,
+                    changed = principal.changed() => {
+                        if changed.is_err() {
+                            break;
+                        }
+                        hub.reauthorize(&session).await;
+                    }
+                }
+            }
+            hub.remove(&session).await;
+        });
+    }
+
+    pub async fn remove(&self, session: &Session) {
+        self.sessions.remove(session.id());
+
+        self.rooms.update(|rooms| {
+            let joined: Vec<String> = rooms
+                .iter()
+                .filter(|(_, ids)| ids.contains(&session.id()))
+                .map(|(room, _)| room.clone())
+                .collect();
+            for room in joined {
+                remove_member(rooms, &room, session.id());
+            }
+        });
+
+        self.tags.lock().await.remove(&session.id());
+
+        let mut principals = self.principals.lock().await;
+        for ids in principals.values_mut() {
+            ids.retain(|id| *id != session.id());
+        }
+        principals.retain(|_, ids| !ids.is_empty());
+    }
+
+    pub async fn get(&self, id: u64) -> Option<Session> {
+        self.sessions.get(id)
+    }
+
+    pub async fn len(&self) -> usize {
+        self.sessions.len()
+    }
+
+    pub async fn is_empty(&self) -> bool {
+        self.sessions.is_empty()
+    }
+
+    /// Add a session to `room`, registering it with the hub if needed
+    pub async fn join(&self, room: &str, session: &Session) {
+        if !self.sessions.contains(session.id()) {
+            self.add(session).await;
+        }
+
+        self.rooms
+            .update(|rooms| add_member(rooms, room, session.id()));
+    }
+
+    pub async fn leave(&self, room: &str, session: &Session) {
+        self.rooms
+            .update(|rooms| remove_member(rooms, room, session.id()));
+    }
+
+    /// Move a session from `from` to `to` in one step, so no broadcast sees
+    /// it in neither room or in both
+    pub async fn move_to(&self, from: &str, to: &str, session: &Session) {
+        if !self.sessions.contains(session.id()) {
+            self.add(session).await;
+        }
+
+        self.rooms.update(|rooms| {
+            remove_member(rooms, from, session.id());
+            add_member(rooms, to, session.id());
+        });
+    }
+
+    /// Make `rooms` the only rooms of a session, in one step like
+    /// [`Self::move_to`]
+    pub async fn set_rooms(&self, rooms: &[&str], session: &Session) {
+        if !self.sessions.contains(session.id()) {
+            self.add(session).await;
+        }
+
+        self.rooms.update(|all| {
+            let current: Vec<String> = all
+                .iter()
+                .filter(|(room, ids)| {
+                    ids.contains(&session.id()) && !rooms.contains(&room.as_str())
+                })
+                .map(|(room, _)| room.clone())
+                .collect();
+            for room in current {
+                remove_member(all, &room, session.id());
+            }
+            for room in rooms {
+                add_member(all, room, session.id());
+            }
+        });
+    }
+
+    /// Rooms a session is in
+    pub async fn rooms_of(&self, session: &Session) -> Vec<String> {
+        self.rooms
+            .load()
+            .iter()
+            .filter(|(_, ids)| ids.contains(&session.id()))
+            .map(|(room, _)| room.clone())
+            .collect()
+    }
+
+    /// [`Session::debug_snapshot`] of session `id` with the rooms it is in,
+    /// `None` if the hub doesn't have it
+    pub async fn debug_snapshot(&self, id: u64) -> Option<SessionSnapshot> {
+        let session = self.get(id).await?;
+        let mut snapshot = session.debug_snapshot().await;
+        snapshot.rooms = self.rooms_of(&session).await;
+        snapshot.rooms.sort_unstable();
+        Some(snapshot)
+    }
+
+    /// Session ids of every room, all taken at the same instant
+    pub async fn memberships(&self) -> HashMap<String, HashSet<u64>> {
+        self.rooms
+            .load()
+            .iter()
+            .map(|(room, ids)| (room.clone(), HashSet::clone(ids)))
+            .collect()
+    }
+
+    pub async fn members(&self, room: &str) -> Vec<Session> {
+        match self.rooms.members(room) {
+            Some(ids) => self.sessions.get_all(ids.iter()),
+            None => Vec::new(),
+        }
+    }
+
+    /// Send a notification to every session in `room`, spread over time
+    /// for rooms covered by [`Self::set_time_slicing`]
+    pub async fn broadcast<M: Method>(&self, room: &str, data: M::Request) -> crate::Result<()> {
+        let message: QueuedBroadcast = (room.into(), self.encode::<M>(room, data).await?);
+        let members = self.members(room).await;
+
+        let slicing = *self.time_slicing.lock().await;
+        match slicing {
+            Some(slicing) if members.len() >= slicing.min_members => {
+                send_sliced(members, message, slicing).await
+            }
+            _ => send_all(members, message).await,
+        }
+
+        Ok(())
+    }
+
+    /// Send each member of `room` its own notification, built by `map`;
+    /// members it returns `None` for are skipped. These per-recipient
+    /// messages aren't retained as room history.
+    pub async fn broadcast_map<M: Method>(
+        &self,
+        room: &str,
+        map: impl Fn(&Session) -> Option<M::Request>,
+    ) -> crate::Result<()> {
+        let topic: Arc<str> = room.into();
+
+        let mut sends = JoinSet::new();
+        for session in self.members(room).await {
+            let Some(data) = map(&session) else {
+                continue;
+            };
+            let message = (topic.clone(), encode_notification::<M>(data, None)?.into());
+            sends.spawn(async move { session.send_topic_payloads(&[message]).await });
+        }
+        sends.join_all().await;
+
+        Ok(())
+    }
+
+    /// Send the same request to every member of `room` at once and collect
+    /// the responses by session id. A session that doesn't answer within
+    /// `per_call` gets `ws::Error::Elapsed`; its answer, should it arrive
+    /// later, is dropped by the session's receiver.
+    pub async fn call_all<M>(
+        &self,
+        room: &str,
+        req: M::Request,
+        per_call: Duration,
+    ) -> HashMap<u64, CallResult<M>>
+    where
+        M: Method + 'static,
+        M::Request: Clone + 'static,
+        M::Response: Send + Sync + 'static,
+        M::Error: Send + Sync + 'static,
+    {
+        let mut calls = JoinSet::new();
+        for session in self.members(room).await {
+            let req = req.clone();
+            calls.spawn(async move {
+                let options = CallOptions {
+                    timeout: Some(per_call),
+                    ..Default::default()
+                };
+                let res = session.call::<M>(req, options).await;
+                (session.id(), res)
+            });
+        }
+
+        calls.join_all().await.into_iter().collect()
+    }
+}
+
+impl Hub {
+    /// Set tag `key` of a session to `value`, registering it with the hub
+    /// if needed. Tags are dropped when the session leaves the hub.
+    pub async fn tag(&self, session: &Session, key: &str, value: &str) {
+        if !self.sessions.contains(session.id()) {
+            self.add(session).await;
+        }
+
+        self.tags
+            .lock()
+            .await
+            .entry(session.id())
+            .or_default()
+            .insert(key.to_string(), value.to_string());
+    }
+
+    pub async fn untag(&self, session: &Session, key: &str) {
+        if let Some(tags) = self.tags.lock().await.get_mut(&session.id()) {
+            tags.remove(key);
+        }
+    }
+
+    pub async fn tags(&self, session: &Session) -> Tags {
+        self.tags
+            .lock()
+            .await
+            .get(&session.id())
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Sessions whose tags satisfy `predicate`
+    pub async fn find(&self, predicate: impl Fn(&Tags) -> bool) -> Vec<Session> {
+        let empty = Tags::new();
+        let sessions = self.sessions.snapshot();
+        let tags = self.tags.lock().await;
+
+        sessions
+            .into_iter()
+            .filter(|session| predicate(tags.get(&session.id()).unwrap_or(&empty)))
+            .collect()
+    }
+
+    /// Sessions tagged `key=value`
+    pub async fn tagged(&self, key: &str, value: &str) -> Vec<Session> {
+        self.find(|tags| tags.get(key).is_some_and(|v| v == value))
+            .await
+    }
+
+    /// Send a notification to every session whose tags satisfy `predicate`.
+    /// Fair queuing sees these under the `topic` name; they aren't retained
+    /// as history, even if a room has that name.
+    pub async fn broadcast_where<M: Method>(
+        &self,
+        topic: &str,
+        predicate: impl Fn(&Tags) -> bool,
+        data: M::Request,
+    ) -> crate::Result<()> {
+        let payload = encode_notification::<M>(data, None)?.into();
+        let message: QueuedBroadcast = (topic.into(), payload);
+        send_all(self.find(predicate).await, message).await;
+
+        Ok(())
+    }
+}
+
+impl Hub {
+    /// Retain broadcasts to `room` in `store` so sessions can catch up on
+    /// what they missed with [`Self::replay_since`]; `None` stops retaining.
+    /// Broadcasts to rooms with history carry a `seq`.
+    pub async fn set_history(&self, room: &str, store: Option<Arc<dyn HistoryStore>>) {
+        let mut history = self.history.lock().await;
+        match store {
+            Some(store) => history.insert(room.to_string(), store),
+            None => history.remove(room),
+        };
+    }
+
+    /// Resend the retained broadcasts of `room` after `seq` to `session`,
+    /// returning how many were sent
+    pub async fn replay_since(
+        &self,
+        session: &Session,
+        room: &str,
+        seq: u64,
+    ) -> crate::Result<usize> {
+        let Some(store) = self.history.lock().await.get(room).cloned() else {
+            return Ok(0);
+        };
+
+        let payloads = store.since(room, seq).await;
+        session.send_payloads(&payloads).await?;
+        Ok(payloads.len())
+    }
+
+    async fn replay_request(&self, id: u64, req: Replay) -> Result<usize, String> {
+        let member = self
+            .rooms
+            .members(&req.topic)
+            .is_some_and(|ids| ids.contains(&id));
+        let session = self.get(id).await.filter(|_| member);
+
+        let Some(session) = session else {
+            return Err(format!("not a member of {}", req.topic));
+        };
+
+        self.replay_since(&session, &req.topic, req.since)
+            .await
+            .map_err(|e| format!("{e:?}"))
+    }
+
+    /// Serialize a broadcast to `room`, numbering and retaining it if the
+    /// room has history
+    async fn encode<M: Method>(&self, room: &str, data: M::Request) -> crate::Result<Arc<[u8]>> {
+        let Some(store) = self.history.lock().await.get(room).cloned() else {
+            return Ok(encode_notification::<M>(data, None)?.into());
+        };
+
+        let last = self
+            .seqs
+            .lock()
+            .await
+            .entry(room.to_string())
+            .or_default()
+            .clone();
+
+        // Held until appended, so the store sees each room in order
+        let mut last = last.lock().await;
+        let seq = match *last {
+            Some(last) => last,
+            None => store.last_seq(room).await.unwrap_or(0),
+        } + 1;
+
+        let payload: Arc<[u8]> = encode_notification::<M>(data, Some(seq))?.into();
+        store.append(room, seq, payload.clone()).await;
+        *last = Some(seq);
+
+        Ok(payload)
+    }
+}
+
+impl Hub {
+    /// Cap on concurrent sessions per principal, checked by
+    /// [`Self::bind_principal`]; `None` removes the cap
+    pub async fn set_principal_limit(&self, limit: Option<PrincipalLimit>) {
+        *self.principal_limit.lock().await = limit;
+    }
+
+    /// Called with a session and its principal right before the session is
+    /// evicted under [`LimitPolicy::EvictOldest`], e.g. to tell that device why
+    pub async fn on_evict<Fut>(
+        &self,
+        handler: impl Fn(Session, String) -> Fut + Send + Sync + 'static,
+    ) where
+        Fut: Future<Output = Result<(), String>> + Send + 'static,
+    {
+        *self.on_evict_fn.lock().await = Some(Arc::new(move |session, principal| {
+            Box::pin(handler(session, principal))
+        }));
+    }
+
+    /// Record `session` as belonging to an authenticated `principal`,
+    /// registering it with the hub if needed. Fails with
+    /// `Error::SessionLimitExceeded` when the limit rejects the session.
+    /// Evicted sessions leave the hub right away and are notified and
+    /// closed in the background.
+    pub async fn bind_principal(&self, session: &Session, principal: &str) -> crate::Result<()> {
+        let limit = *self.principal_limit.lock().await;
+
+        let evicted = {
+            let mut principals = self.principals.lock().await;
+            let ids = principals.entry(principal.to_string()).or_default();
+
+            if ids.contains(&session.id()) {
+                return Ok(());
+            }
+
+            let mut evicted = Vec::new();
+            if let Some(limit) = limit {
+                let excess = (ids.len() + 1).saturating_sub(limit.max_sessions);
+
+                if excess > 0 {
+                    match limit.policy {
+                        LimitPolicy::Reject => {
+                            if ids.is_empty() {
+                                principals.remove(principal);
+                            }
+                            return Err(crate::Error::SessionLimitExceeded);
+                        }
+                        LimitPolicy::EvictOldest => {
+                            evicted = ids.drain(..excess.min(ids.len())).collect();
+                        }
+                    }
+                }
+            }
+
+            ids.push(session.id());
+            evicted
+        };
+
+        self.add(session).await;
+
+        let handler = self.on_evict_fn.lock().await.clone();
+        for id in evicted {
+            let Some(old) = self.get(id).await else {
+                continue;
+            };
+            self.remove(&old).await;
+
+            let handler = handler.clone();
+            let principal = principal.to_string();
+            tokio::spawn(async move {
+                if let Some(handler) = handler {
+                    let _ = handler(old.clone(), principal).await;
+                }
+                let _ = old.close().await;
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Who may be in which room: `allowed` gets a session and one of its
+    /// rooms, and is asked for each room of a session whenever the
+    /// session's principal changes, e.g. after a token refresh. Rooms it
+    /// turns down are left, see [`Self::on_revoke`]. Joining isn't checked.
+    pub async fn set_room_access<Fut>(
+        &self,
+        allowed: impl Fn(Session, String) -> Fut + Send + Sync + 'static,
+    ) where
+        Fut: Future<Output = bool> + Send + 'static,
+    {
+        *self.room_access.lock().await = Some(Arc::new(move |session, room| {
+            Box::pin(allowed(session, room))
+        }));
+    }
+
+    /// Called with a session and a room it was taken out of by
+    /// [`Self::reauthorize`], e.g. to tell the client its subscription ended
+    pub async fn on_revoke<Fut>(
+        &self,
+        handler: impl Fn(Session, String) -> Fut + Send + Sync + 'static,
+    ) where
+        Fut: Future<Output = Result<(), String>> + Send + 'static,
+    {
+        let handler = Arc::new(handler);
+
+        *self.on_revoke_fn.lock().await = Some(Box::new(move |session, room| {
+            let handler = handler.clone();
+            Box::pin(async move { handler(session, room).await })
+        }));
+    }
+
+    /// Check every room of `session` against [`Self::set_room_access`] and
+    /// leave those it may no longer be in, returning them. Runs on its own
+    /// when the principal changes; call it when permissions change some
+    /// other way, e.g. new scopes for the same principal.
+    pub async fn reauthorize(&self, session: &Session) -> Vec<String> {
+        let Some(allowed) = self.room_access.lock().await.clone() else {
+            return Vec::new();
+        };
+
+        let mut revoked = Vec::new();
+        for room in self.rooms_of(session).await {
+            if !allowed(session.clone(), room.clone()).await {
+                self.leave(&room, session).await;
+                revoked.push(room);
+            }
+        }
+
+        for room in &revoked {
+            log::log(
+                Level::Info,
+                "room_access_revoked",
+                &[("session", &session.id()), ("room", room)],
+            );
+            if let Some(handler) = self.on_revoke_fn.lock().await.as_ref() {
+                let _ = handler(session.clone(), room.clone()).await;
+            }
+        }
+
+        revoked
+    }
+
+    pub async fn principal_sessions(&self, principal: &str) -> Vec<Session> {
+        let ids = self
+            .principals
+            .lock()
+            .await
+            .get(principal)
+            .cloned()
+            .unwrap_or_default();
+
+        self.sessions.get_all(&ids)
+    }
+}
+
+impl Hub {
+    pub async fn set_batching(&self, batching: Batching) {
+        *self.batching.lock().await = batching;
+
+        let mut flusher = self.flusher.lock().await;
+        if let Some(task) = flusher.take() {
+            task.abort();
+        }
+
+        if let Some(interval) = batching.interval {
+            // Weak, so the task ends with the last handle of the hub
+            let queued = Arc::downgrade(&self.queued);
+            let rooms = Arc::downgrade(&self.rooms);
+            let sessions = Arc::downgrade(&self.sessions);
+            *flusher = Some(tokio::spawn(async move {
+                let mut ticker = tokio::time::interval(interval);
+                loop {
+                    ticker.tick().await;
+                    let (Some(queued), Some(rooms), Some(sessions)) =
+                        (queued.upgrade(), rooms.upgrade(), sessions.upgrade())
+                    else {
+                        break;
+                    };
+                    flush(&queued, &rooms, &sessions).await;
+                }
+            }));
+        }
+    }
+
+    /// Spread broadcasts to large rooms over time; `None` sends to every
+    /// member at once
+    pub async fn set_time_slicing(&self, slicing: Option<TimeSlicing>) {
+        *self.time_slicing.lock().await = slicing;
+    }
+
+    /// Queue a notification for `room`, sent on the next flush
+    pub async fn queue_broadcast<M: Method>(
+        &self,
+        room: &str,
+        data: M::Request,
+    ) -> crate::Result<()> {
+        let payload = self.encode::<M>(room, data).await?;
+
+        let queued = {
+            let mut queued = self.queued.lock().await;
+            queued.push((room.into(), payload));
+            queued.len()
+        };
+
+        let max = self.batching.lock().await.max_messages;
+        if max.is_some_and(|max| queued >= max) {
+            self.flush().await;
+        }
+
+        Ok(())
+    }
+
+    /// Send all queued broadcasts, one coalesced write per session
+    pub async fn flush(&self) {
+        flush(&self.queued, &self.rooms, &self.sessions).await;
+    }
+}
+
+/// [`Hub::flush`] on the parts of a hub it needs
+async fn flush(queued: &Mutex<Vec<QueuedBroadcast>>, rooms: &Rooms, sessions: &Registry) {
+    let queued = std::mem::take(&mut *queued.lock().await);
+    if queued.is_empty() {
+        return;
+    }
+
+    let mut batches: HashMap<u64, Vec<QueuedBroadcast>> = HashMap::new();
+    {
+        let rooms = rooms.load();
+        for (room, payload) in queued {
+            for id in rooms.get(&*room).into_iter().flat_map(|ids| ids.iter()) {
+                batches
+                    .entry(*id)
+                    .or_default()
+                    .push((room.clone(), payload.clone()));
+            }
+        }
+    }
+
+    let mut sends = JoinSet::new();
+    for (id, payloads) in batches {
+        if let Some(session) = sessions.get(id) {
+            sends.spawn(async move { session.send_topic_payloads(&payloads).await });
+        }
+    }
+    sends.join_all().await;
+}
+
+/// Send `message` to all `sessions` concurrently
+async fn send_all(sessions: Vec<Session>, message: QueuedBroadcast) {
+    let mut sends = JoinSet::new();
+    for session in sessions {
+        let message = message.clone();
+        sends.spawn(async move { session.send_topic_payloads(&[message]).await });
+    }
+    sends.join_all().await;
+}
+
+/// Send `message` to `sessions` one share per tick, finishing the last
+/// share about `window` after the first
+async fn send_sliced(sessions: Vec<Session>, message: QueuedBroadcast, slicing: TimeSlicing) {
+    let ticks = (slicing.window.as_nanos() / slicing.tick.as_nanos().max(1)).max(1) as usize;
+    let per_tick = sessions.len().div_ceil(ticks).max(1);
+
+    let mut ticker = tokio::time::interval(slicing.tick);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    let mut sends = JoinSet::new();
+    for share in sessions.chunks(per_tick) {
+        ticker.tick().await;
+        for session in share {
+            let (session, message) = (session.clone(), message.clone());
+            sends.spawn(async move { session.send_topic_payloads(&[message]).await });
+        }
+        // Reap sends that are done so the set stays at about one share
+        while sends.try_join_next().is_some() {}
+    }
+    sends.join_all().await;
+}
+
+fn encode_notification<M: Method>(data: M::Request, seq: Option<u64>) -> crate::Result<Vec<u8>> {
+    Ok(serde_json::to_vec(&Message::<M>::Notification {
+        method: M::NAME.to_string(),
+        data,
+        seq,
+    })?)
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::duplex;
+
+    use crate::GenericMethod;
+    use crate::history::{MemoryHistory, Retention};
+    use crate::ws::{Upgrade, WebSocket};
+
+    use super::*;
+
+    /// A server side session, and the client end that keeps it open
+    fn session() -> (Session, Session) {
+        let (ours, theirs) = duplex(1 << 16);
+        let server = Session::from_ws(WebSocket::from_stream(ours, false, Upgrade::default()));
+        let client = Session::from_ws(WebSocket::from_stream(theirs, true, Upgrade::default()));
+        (server, client)
+    }
+
+    /// History that takes a while to append to
+    struct SlowHistory(MemoryHistory);
+
+    impl HistoryStore for SlowHistory {
+        fn append<'a>(&'a self, topic: &'a str, seq: u64, payload: Arc<[u8]>) -> BoxFuture<'a, ()> {
+            Box::pin(async move {
+                tokio::time::sleep(Duration::from_millis(200)).await;
+                self.0.append(topic, seq, payload).await;
+            })
+        }
+
+        fn since<'a>(&'a self, topic: &'a str, seq: u64) -> BoxFuture<'a, Vec<Arc<[u8]>>> {
+            self.0.since(topic, seq)
+        }
+
+        fn last_seq<'a>(&'a self, topic: &'a str) -> BoxFuture<'a, Option<u64>> {
+            self.0.last_seq(topic)
+        }
+    }
+
+    #[tokio::test]
+    async fn slow_history_holds_up_only_its_room() {
+        let hub = Hub::new();
+        let slow = SlowHistory(MemoryHistory::new(Retention::default()));
+        hub.set_history("slow", Some(Arc::new(slow))).await;
+        let fast = MemoryHistory::new(Retention::default());
+        hub.set_history("fast", Some(Arc::new(fast))).await;
+
+        let h = hub.clone();
+        let slow = tokio::spawn(async move {
+            h.broadcast::<GenericMethod>("slow", serde_json::json!(1))
+                .await
+        });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+
+        let fast = hub.broadcast::<GenericMethod>("fast", serde_json::json!(2));
+        let fast = tokio::time::timeout(Duration::from_millis(100), fast).await;
+        assert!(matches!(fast, Ok(Ok(()))));
+        assert!(slow.await.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn batching_stops_with_the_hub() {
+        let hub = Hub::new();
+        hub.set_batching(Batching {
+            max_messages: None,
+            interval: Some(Duration::from_millis(5)),
+        })
+        .await;
+
+        let flusher = hub.flusher.lock().await.as_ref().unwrap().abort_handle();
+        let sessions = Arc::downgrade(&hub.sessions);
+        drop(hub);
+
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(flusher.is_finished());
+        assert!(sessions.upgrade().is_none());
+    }
+
+    #[tokio::test]
+    async fn eviction_does_not_hold_up_bind() {
+        let hub = Hub::new();
+        hub.set_principal_limit(Some(PrincipalLimit {
+            max_sessions: 1,
+            policy: LimitPolicy::EvictOldest,
+        }))
+        .await;
+        hub.on_evict(|_, _| async {
+            tokio::time::sleep(Duration::from_secs(10)).await;
+            Ok(())
+        })
+        .await;
+
+        let (old, _old_client) = session();
+        let (new, _new_client) = session();
+        hub.bind_principal(&old, "alice").await.unwrap();
+
+        let bind = hub.bind_principal(&new, "alice");
+        let bind = tokio::time::timeout(Duration::from_millis(100), bind).await;
+        assert!(matches!(bind, Ok(Ok(()))));
+        assert!(hub.get(old.id()).await.is_none());
+        assert!(hub.get(new.id()).await.is_some());
+    }
+}
@@ -0,0 +1,178 @@
+use std::collections::HashMap;
+
+use tokio::sync::{Mutex, watch};
+use tokio::time::{Duration, Instant};
+
+/// What a method handler produced: `(is_error, payload)`, or `None` when no
+/// response is sent
+type Outcome = Option<(bool, serde_json::Value)>;
+
+/// Whose key it is: the principal, or the session for clients without one
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) enum Owner {
+    Principal(String),
+    Session(u64),
+}
+
+/// (owner, method, key the client sent)
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) struct IdempotencyKey(Owner, String, String);
+
+impl IdempotencyKey {
+    pub(crate) fn new(principal: Option<&str>, session: u64, method: &str, key: &str) -> Self {
+        let owner = match principal {
+            Some(principal) => Owner::Principal(principal.to_string()),
+            None => Owner::Session(session),
+        };
+        Self(owner, method.to_string(), key.to_string())
+    }
+}
+
+enum Entry {
+    /// The first request with this key is still running
+    Pending(watch::Receiver<Option<Outcome>>),
+    Done {
+        outcome: Outcome,
+        at: Instant,
+    },
+}
+
+/// Server-side record of requests that carried an idempotency key, so a
+/// retried request gets the original response instead of running twice.
+/// Share one store across sessions, since retries usually arrive on a new
+/// connection after a reconnect. Keys are kept per principal (see
+/// [`crate::session::Session::set_principal`]), so clients can't see each
+/// other's responses; a client without one only matches retries on the
+/// same session.
+pub struct IdempotencyStore {
+    ttl: Duration,
+    entries: Mutex<HashMap<IdempotencyKey, Entry>>,
+}
+
+impl IdempotencyStore {
+    /// Keep completed responses for `ttl`
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Run `handler` unless `key` was already seen, in which case the
+    /// recorded outcome is returned, waiting for it if still running
+    pub(crate) async fn run(
+        &self,
+        key: IdempotencyKey,
+        handler: impl Future<Output = Outcome>,
+    ) -> Outcome {
+        let tx = loop {
+            let mut entries = self.entries.lock().await;
+            entries.retain(|_, e| !matches!(e, Entry::Done { at, .. } if at.elapsed() > self.ttl));
+
+            match entries.get(&key) {
+                Some(Entry::Done { outcome, .. }) => return outcome.clone(),
+                // Unless the attempt running it was dropped before finishing
+                Some(Entry::Pending(rx)) if rx.has_changed().is_ok() => {
+                    let mut rx = rx.clone();
+                    drop(entries);
+
+                    if let Ok(outcome) = rx.wait_for(Option::is_some).await {
+                        return outcome.clone().flatten();
+                    }
+                }
+                // Taken over under the lock, so only one retry runs it again
+                _ => {
+                    let (tx, rx) = watch::channel(None);
+                    entries.insert(key.clone(), Entry::Pending(rx));
+                    break tx;
+                }
+            }
+        };
+
+        let outcome = handler.await;
+
+        self.entries.lock().await.insert(
+            key,
+            Entry::Done {
+                outcome: outcome.clone(),
+                at: Instant::now(),
+            },
+        );
+        tx.send_replace(Some(outcome.clone()));
+
+        outcome
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    fn key() -> IdempotencyKey {
+        IdempotencyKey::new(Some("alice"), 1, "order", "order-1")
+    }
+
+    #[tokio::test]
+    async fn one_retry_takes_over_a_dropped_attempt() {
+        let store = Arc::new(IdempotencyStore::new(Duration::from_secs(60)));
+        let runs = Arc::new(AtomicUsize::new(0));
+
+        let handler = |runs: Arc<AtomicUsize>| async move {
+            runs.fetch_add(1, Ordering::Relaxed);
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            Some((false, serde_json::json!("done")))
+        };
+
+        // The first attempt is dropped mid-handler, e.g. by the drain grace
+        let first = store.run(key(), handler(runs.clone()));
+        assert!(
+            tokio::time::timeout(Duration::from_millis(10), first)
+                .await
+                .is_err()
+        );
+
+        let retries: Vec<_> = (0..3)
+            .map(|_| {
+                let (store, runs) = (store.clone(), runs.clone());
+                tokio::spawn(async move { store.run(key(), handler(runs)).await })
+            })
+            .collect();
+        for retry in retries {
+            assert_eq!(
+                retry.await.unwrap(),
+                Some((false, serde_json::json!("done")))
+            );
+        }
+
+        let later = store.run(key(), handler(runs.clone())).await;
+        assert_eq!(later, Some((false, serde_json::json!("done"))));
+        assert_eq!(runs.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn keys_are_kept_per_owner() {
+        let store = IdempotencyStore::new(Duration::from_secs(60));
+        let answer = |who: &'static str| async move { Some((false, serde_json::json!(who))) };
+
+        let alice = IdempotencyKey::new(Some("alice"), 1, "order", "order-1");
+        let bob = IdempotencyKey::new(Some("bob"), 2, "order", "order-1");
+        let anonymous = IdempotencyKey::new(None, 3, "order", "order-1");
+
+        assert_eq!(
+            store.run(alice.clone(), answer("alice")).await,
+            answer("alice").await
+        );
+        assert_eq!(store.run(bob, answer("bob")).await, answer("bob").await);
+        assert_eq!(
+            store.run(anonymous, answer("anon")).await,
+            answer("anon").await
+        );
+        assert_eq!(
+            store.run(alice, answer("again")).await,
+            answer("alice").await
+        );
+    }
+}
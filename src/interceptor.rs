@@ -0,0 +1,34 @@
+use std::sync::Arc;
+
+/// Whether an outbound message goes out after an [`Interceptor`] saw it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Send,
+    /// Don't send the message; the send fails with `Error::Vetoed`
+    Veto,
+}
+
+/// Sees every outbound message as JSON right before it is framed, and may
+/// change it (add envelope fields, redact data) or veto it
+pub trait Interceptor: Send + Sync {
+    fn intercept(&self, message: &mut serde_json::Value) -> Verdict;
+}
+
+impl<F> Interceptor for F
+where
+    F: Fn(&mut serde_json::Value) -> Verdict + Send + Sync,
+{
+    fn intercept(&self, message: &mut serde_json::Value) -> Verdict {
+        self(message)
+    }
+}
+
+/// Run `message` through `chain` in order, stopping at the first veto
+pub(crate) fn run(chain: &[Arc<dyn Interceptor>], message: &mut serde_json::Value) -> Verdict {
+    for interceptor in chain {
+        if interceptor.intercept(message) == Verdict::Veto {
+            return Verdict::Veto;
+        }
+    }
+    Verdict::Send
+}
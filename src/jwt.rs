@@ -0,0 +1,66 @@
+//! Bearer token authentication of upgrades, see
+//! [`crate::server::SessionServer::with_jwt_auth`]. The token comes from the
+//! `Authorization: Bearer` header or, for browsers, which can't set headers
+//! on a WebSocket, from a query parameter.
+
+use jsonwebtoken::{DecodingKey, Validation};
+
+use crate::ws::{HandshakeRequest, Rejection};
+
+/// Validates the signature and claims of a JWT presented with the upgrade
+#[derive(Clone)]
+pub struct JwtAuth {
+    key: DecodingKey,
+    validation: Validation,
+    query_param: Option<String>,
+}
+
+impl JwtAuth {
+    /// Tokens must be signed with `key` and pass `validation`, which sets
+    /// the accepted algorithms and checks expiry, audience and issuer
+    pub fn new(key: DecodingKey, validation: Validation) -> Self {
+        Self {
+            key,
+            validation,
+            query_param: None,
+        }
+    }
+
+    /// Also take the token from query parameter `name`, e.g. `access_token`,
+    /// when there is no `Authorization` header
+    pub fn query_param(mut self, name: &str) -> Self {
+        self.query_param = Some(name.to_string());
+        self
+    }
+
+    /// Claims of the request's token, or the 401 refusing it
+    pub fn verify(&self, request: &HandshakeRequest) -> Result<serde_json::Value, Rejection> {
+        let Some(token) = self.token(request) else {
+            return Err(Rejection::new(401).header("WWW-Authenticate", "Bearer"));
+        };
+
+        jsonwebtoken::decode::<serde_json::Value>(token, &self.key, &self.validation)
+            .map(|data| data.claims)
+            .map_err(|_| {
+                Rejection::new(401).header("WWW-Authenticate", "Bearer error=\"invalid_token\"")
+            })
+    }
+
+    fn token<'a>(&self, request: &'a HandshakeRequest) -> Option<&'a str> {
+        let bearer = request.header("authorization").and_then(|value| {
+            let (scheme, token) = value.split_once(' ')?;
+            scheme.eq_ignore_ascii_case("bearer").then(|| token.trim())
+        });
+
+        bearer.or_else(|| request.query_param(self.query_param.as_deref()?))
+    }
+}
+
+impl std::fmt::Debug for JwtAuth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("JwtAuth")
+            .field("validation", &self.validation)
+            .field("query_param", &self.query_param)
+            .finish_non_exhaustive()
+    }
+}
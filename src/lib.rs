@@ -2,14 +2,47 @@ use std::{pin::Pin, sync::Arc};
 
 use serde::{Deserialize, Serialize};
 
+pub mod ban;
+pub mod call;
+pub mod channel;
+pub mod clock;
+pub mod close_stats;
+pub mod codec;
+pub mod envelope;
+mod fair_queue;
+pub mod hello;
+pub mod history;
+pub mod hub;
+pub mod idempotency;
+pub mod interceptor;
+#[cfg(feature = "jwt")]
+pub mod jwt;
+pub mod log;
+pub mod metrics;
+pub mod rate_limit;
+pub mod reconnect;
+#[cfg(feature = "redis")]
+pub mod redis;
+mod registry;
+pub mod response_cache;
+pub mod router;
+pub mod schema;
 pub mod server;
 pub mod session;
+pub mod tenant;
+#[cfg(feature = "testing")]
+pub mod testing;
 pub mod ws;
 
+#[cfg(feature = "jwt")]
+pub use jsonwebtoken;
+#[cfg(feature = "tls")]
+pub use tokio_rustls::rustls;
+
 pub type Result<T> = std::result::Result<T, Error>;
 pub type BoxFuture<'a, T = Option<(bool, serde_json::Value)>> =
     Pin<Box<dyn Future<Output = T> + Send + 'a>>;
-pub type MethodHandler = Arc<dyn Fn(u32, serde_json::Value) -> BoxFuture<'static> + Send + Sync>;
+pub type MethodHandler = Arc<dyn Fn(u64, serde_json::Value) -> BoxFuture<'static> + Send + Sync>;
 
 pub trait Method {
     const NAME: &'static str;
@@ -33,6 +66,22 @@ pub enum Error {
     Json(serde_json::Error),
     Io(std::io::Error),
     RecvError(tokio::sync::broadcast::error::RecvError),
+    SessionLimitExceeded,
+    Vetoed,
+    Codec(String),
+    /// The session already has its maximum of requests awaiting a response
+    InFlightLimit,
+    /// The session's tenant is at its connection quota
+    QuotaExceeded,
+    /// The peer's address or principal is banned, for the given reason
+    Banned(String),
+    /// An inbound payload of `method` doesn't match its type at `path`,
+    /// see [`session::Session::set_validate_inbound`]
+    SchemaMismatch {
+        method: String,
+        path: String,
+        reason: String,
+    },
 }
 
 impl From<ws::Error> for Error {
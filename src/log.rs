@@ -0,0 +1,97 @@
+//! Where the crate reports notable events: handshakes, closes, protocol
+//! violations and overflowing buffers. Install a [`LogSink`] with
+//! [`set_sink`] to route them into your own logging stack.
+
+use std::fmt::{self, Display};
+use std::sync::{Arc, OnceLock, RwLock};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+/// One logged event: a stable `event` name plus key/value fields
+pub struct Record<'a> {
+    pub level: Level,
+    pub event: &'static str,
+    pub fields: &'a [(&'static str, &'a dyn Display)],
+}
+
+impl Display for Record<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.event)?;
+        for (key, value) in self.fields {
+            write!(f, " {key}={value}")?;
+        }
+        Ok(())
+    }
+}
+
+pub trait LogSink: Send + Sync {
+    fn log(&self, record: &Record<'_>);
+}
+
+/// Discards every record
+pub struct NoopSink;
+
+impl LogSink for NoopSink {
+    fn log(&self, _: &Record<'_>) {}
+}
+
+/// Prints warnings and errors to stderr. The default without `tracing`.
+pub struct StderrSink;
+
+impl LogSink for StderrSink {
+    fn log(&self, record: &Record<'_>) {
+        if record.level >= Level::Warn {
+            eprintln!("{record}");
+        }
+    }
+}
+
+/// Forwards records as `tracing` events. The default with `tracing`.
+#[cfg(feature = "tracing")]
+pub struct TracingSink;
+
+#[cfg(feature = "tracing")]
+impl LogSink for TracingSink {
+    fn log(&self, record: &Record<'_>) {
+        match record.level {
+            Level::Debug => tracing::debug!("{record}"),
+            Level::Info => tracing::info!("{record}"),
+            Level::Warn => tracing::warn!("{record}"),
+            Level::Error => tracing::error!("{record}"),
+        }
+    }
+}
+
+fn sink() -> &'static RwLock<Arc<dyn LogSink>> {
+    static SINK: OnceLock<RwLock<Arc<dyn LogSink>>> = OnceLock::new();
+
+    SINK.get_or_init(|| {
+        #[cfg(feature = "tracing")]
+        let sink = Arc::new(TracingSink);
+        #[cfg(not(feature = "tracing"))]
+        let sink = Arc::new(StderrSink);
+
+        RwLock::new(sink)
+    })
+}
+
+/// Replace the process-wide sink
+pub fn set_sink(new: impl LogSink + 'static) {
+    *sink().write().unwrap_or_else(|e| e.into_inner()) = Arc::new(new);
+}
+
+pub(crate) fn log(level: Level, event: &'static str, fields: &[(&'static str, &dyn Display)]) {
+    let sink = sink().read().unwrap_or_else(|e| e.into_inner()).clone();
+
+    sink.log(&Record {
+        level,
+        event,
+        fields,
+    });
+}
@@ -0,0 +1,267 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use tokio::sync::Mutex;
+use tokio::time::Duration;
+
+/// Upper bounds of the latency buckets, in seconds
+const LATENCY_BUCKETS: [f64; 12] = [
+    0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Upper bounds of the message size buckets in bytes, growing by 4x from
+/// 64 B to 16 MiB
+const SIZE_BUCKETS: [f64; 10] = [
+    64.0, 256.0, 1024.0, 4096.0, 16384.0, 65536.0, 262144.0, 1048576.0, 4194304.0, 16777216.0,
+];
+
+struct Histogram {
+    bounds: &'static [f64],
+    /// Per bucket, not cumulative; the last slot counts values above every bound
+    buckets: Vec<AtomicU64>,
+    count: AtomicU64,
+    /// In the unit of `bounds` times `scale`, so it fits an integer
+    sum: AtomicU64,
+    scale: f64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64], scale: f64) -> Self {
+        Self {
+            bounds,
+            buckets: (0..=bounds.len()).map(|_| AtomicU64::new(0)).collect(),
+            count: AtomicU64::new(0),
+            sum: AtomicU64::new(0),
+            scale,
+        }
+    }
+
+    fn record(&self, value: f64) {
+        let bucket = self
+            .bounds
+            .iter()
+            .position(|bound| value <= *bound)
+            .unwrap_or(self.bounds.len());
+
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum
+            .fetch_add((value * self.scale) as u64, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> HistogramSnapshot {
+        let mut total = 0;
+        let buckets = self
+            .bounds
+            .iter()
+            .copied()
+            .chain([f64::INFINITY])
+            .zip(&self.buckets)
+            .map(|(bound, count)| {
+                total += count.load(Ordering::Relaxed);
+                (bound, total)
+            })
+            .collect();
+
+        HistogramSnapshot {
+            buckets,
+            count: self.count.load(Ordering::Relaxed),
+            sum: self.sum.load(Ordering::Relaxed) as f64 / self.scale,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct HistogramSnapshot {
+    /// Cumulative `(upper bound, count)` pairs, ending with `f64::INFINITY`
+    pub buckets: Vec<(f64, u64)>,
+    pub count: u64,
+    pub sum: f64,
+}
+
+/// Request counters of one method
+struct MethodStats {
+    calls: AtomicU64,
+    errors: AtomicU64,
+    latency: Histogram,
+    sizes: Histogram,
+}
+
+impl MethodStats {
+    fn new() -> Self {
+        Self {
+            calls: AtomicU64::new(0),
+            errors: AtomicU64::new(0),
+            latency: Histogram::new(&LATENCY_BUCKETS, 1e6),
+            sizes: Histogram::new(&SIZE_BUCKETS, 1.0),
+        }
+    }
+}
+
+/// Snapshot of one method's counters
+#[derive(Debug, Clone, PartialEq)]
+pub struct MethodMetrics {
+    pub calls: u64,
+    /// Calls answered with an error or not answered at all
+    pub errors: u64,
+    /// Handler latency in seconds
+    pub latency: HistogramSnapshot,
+    /// Size of the request messages in bytes
+    pub request_sizes: HistogramSnapshot,
+}
+
+/// Per-method call counts, errors and handler latency, plus message sizes,
+/// recorded by every session it is installed on. Share one across sessions
+/// to aggregate them.
+pub struct Metrics {
+    methods: Mutex<HashMap<String, Arc<MethodStats>>>,
+    inbound_sizes: Histogram,
+    outbound_sizes: Histogram,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self {
+            methods: Mutex::new(HashMap::new()),
+            inbound_sizes: Histogram::new(&SIZE_BUCKETS, 1.0),
+            outbound_sizes: Histogram::new(&SIZE_BUCKETS, 1.0),
+        }
+    }
+
+    async fn method(&self, method: &str) -> Arc<MethodStats> {
+        self.methods
+            .lock()
+            .await
+            .entry(method.to_string())
+            .or_insert_with(|| Arc::new(MethodStats::new()))
+            .clone()
+    }
+
+    pub(crate) async fn record(&self, method: &str, elapsed: Duration, error: bool) {
+        let stats = self.method(method).await;
+
+        stats.calls.fetch_add(1, Ordering::Relaxed);
+        if error {
+            stats.errors.fetch_add(1, Ordering::Relaxed);
+        }
+        stats.latency.record(elapsed.as_secs_f64());
+    }
+
+    pub(crate) async fn record_request_size(&self, method: &str, size: usize) {
+        self.method(method).await.sizes.record(size as f64);
+    }
+
+    pub(crate) fn record_inbound(&self, size: usize) {
+        self.inbound_sizes.record(size as f64);
+    }
+
+    pub(crate) fn record_outbound(&self, size: usize) {
+        self.outbound_sizes.record(size as f64);
+    }
+
+    pub async fn snapshot(&self) -> HashMap<String, MethodMetrics> {
+        let methods = self.methods.lock().await;
+
+        methods
+            .iter()
+            .map(|(name, stats)| {
+                let metrics = MethodMetrics {
+                    calls: stats.calls.load(Ordering::Relaxed),
+                    errors: stats.errors.load(Ordering::Relaxed),
+                    latency: stats.latency.snapshot(),
+                    request_sizes: stats.sizes.snapshot(),
+                };
+                (name.clone(), metrics)
+            })
+            .collect()
+    }
+
+    /// Sizes of all data messages received, in bytes
+    pub fn inbound_sizes(&self) -> HistogramSnapshot {
+        self.inbound_sizes.snapshot()
+    }
+
+    /// Sizes of all data messages sent, in bytes
+    pub fn outbound_sizes(&self) -> HistogramSnapshot {
+        self.outbound_sizes.snapshot()
+    }
+
+    /// Render the counters in the Prometheus text exposition format
+    pub async fn prometheus(&self) -> String {
+        let mut snapshot: Vec<_> = self.snapshot().await.into_iter().collect();
+        snapshot.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let mut out = String::new();
+
+        out.push_str("# TYPE session_requests_total counter\n");
+        for (method, m) in &snapshot {
+            let _ = writeln!(
+                out,
+                "session_requests_total{{method=\"{method}\"}} {}",
+                m.calls
+            );
+        }
+
+        out.push_str("# TYPE session_request_errors_total counter\n");
+        for (method, m) in &snapshot {
+            let _ = writeln!(
+                out,
+                "session_request_errors_total{{method=\"{method}\"}} {}",
+                m.errors
+            );
+        }
+
+        out.push_str("# TYPE session_request_duration_seconds histogram\n");
+        for (method, m) in &snapshot {
+            let labels = format!("method=\"{method}\"");
+            write_histogram(
+                &mut out,
+                "session_request_duration_seconds",
+                &labels,
+                &m.latency,
+            );
+        }
+
+        out.push_str("# TYPE session_request_size_bytes histogram\n");
+        for (method, m) in &snapshot {
+            let labels = format!("method=\"{method}\"");
+            write_histogram(
+                &mut out,
+                "session_request_size_bytes",
+                &labels,
+                &m.request_sizes,
+            );
+        }
+
+        out.push_str("# TYPE session_message_size_bytes histogram\n");
+        for (direction, h) in [
+            ("inbound", self.inbound_sizes()),
+            ("outbound", self.outbound_sizes()),
+        ] {
+            let labels = format!("direction=\"{direction}\"");
+            write_histogram(&mut out, "session_message_size_bytes", &labels, &h);
+        }
+
+        out
+    }
+}
+
+fn write_histogram(out: &mut String, name: &str, labels: &str, h: &HistogramSnapshot) {
+    for (bound, count) in &h.buckets {
+        let le = match bound.is_infinite() {
+            true => "+Inf".to_string(),
+            false => bound.to_string(),
+        };
+        let _ = writeln!(out, "{name}_bucket{{{labels},le=\"{le}\"}} {count}");
+    }
+    let _ = writeln!(out, "{name}_sum{{{labels}}} {}", h.sum);
+    let _ = writeln!(out, "{name}_count{{{labels}}} {}", h.count);
+}
@@ -0,0 +1,114 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use tokio::sync::Mutex;
+use tokio::time::{Duration, Instant};
+
+use crate::BoxFuture;
+use crate::clock::{Clock, TokioClock};
+
+/// Sustained rate and burst size for a token bucket, in messages.
+/// `per_second` must be positive.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    pub per_second: f64,
+    pub burst: u32,
+}
+
+pub(crate) struct TokenBucket {
+    limit: RateLimit,
+    clock: Arc<dyn Clock>,
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl TokenBucket {
+    pub(crate) fn new(limit: RateLimit, clock: Arc<dyn Clock>) -> Self {
+        Self {
+            limit,
+            tokens: limit.burst as f64,
+            refilled: clock.now(),
+            clock,
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = self.clock.now();
+        let earned = now.duration_since(self.refilled).as_secs_f64() * self.limit.per_second;
+
+        self.tokens = (self.tokens + earned).min(self.limit.burst.max(1) as f64);
+        self.refilled = now;
+    }
+
+    /// Take one token, sleeping until it is available. Callers hold the
+    /// bucket's lock while waiting, so concurrent senders are served in order.
+    /// Returns whether it had to wait.
+    pub(crate) async fn acquire(&mut self) -> bool {
+        self.refill();
+
+        let throttled = self.tokens < 1.0;
+        if throttled {
+            let missing = 1.0 - self.tokens;
+            self.clock
+                .sleep(Duration::from_secs_f64(missing / self.limit.per_second))
+                .await;
+            self.refill();
+        }
+
+        self.tokens -= 1.0;
+        throttled
+    }
+}
+
+/// Token buckets keyed by client, kept where several server instances can
+/// share them (e.g. Redis), so a client spreading its connections across
+/// instances gets one quota instead of one per instance. See
+/// [`crate::tenant::Tenants::with_rate_store`].
+pub trait RateLimitStore: Send + Sync {
+    /// Take one token from the bucket of `key`, going into debt if it's
+    /// empty, and return how long the caller must wait before using it
+    fn acquire<'a>(&'a self, key: &'a str, limit: RateLimit) -> BoxFuture<'a, Duration>;
+}
+
+/// In-process [`RateLimitStore`], for a single instance
+pub struct MemoryRateLimits {
+    clock: Arc<dyn Clock>,
+    /// Tokens left, negative while in debt, and when they were counted
+    buckets: Mutex<HashMap<String, (f64, Instant)>>,
+}
+
+impl Default for MemoryRateLimits {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MemoryRateLimits {
+    pub fn new() -> Self {
+        Self {
+            clock: Arc::new(TokioClock),
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl RateLimitStore for MemoryRateLimits {
+    fn acquire<'a>(&'a self, key: &'a str, limit: RateLimit) -> BoxFuture<'a, Duration> {
+        Box::pin(async move {
+            let now = self.clock.now();
+            let burst = limit.burst.max(1) as f64;
+
+            let mut buckets = self.buckets.lock().await;
+            let (tokens, counted) = buckets.entry(key.to_string()).or_insert((burst, now));
+
+            let earned = now.duration_since(*counted).as_secs_f64() * limit.per_second;
+            *tokens = (*tokens + earned).min(burst) - 1.0;
+            *counted = now;
+
+            match *tokens < 0.0 {
+                true => Duration::from_secs_f64(-*tokens / limit.per_second),
+                false => Duration::ZERO,
+            }
+        })
+    }
+}
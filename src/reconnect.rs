@@ -0,0 +1,156 @@
+use tokio::sync::watch;
+use tokio::time::Duration;
+
+use crate::{Method, session::Session, ws::ConnectOptions};
+
+/// How often [`ReconnectingSession::call`] retries a call cut off by a
+/// disconnect before giving up
+const MAX_CALL_ATTEMPTS: usize = 5;
+
+const MIN_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_BACKOFF: Duration = Duration::from_secs(10);
+
+/// Client session that reconnects with exponential backoff whenever the
+/// connection drops. Reconnects present the last ticket the server sent
+/// (see [`Session::send_ticket`]) so it can resume the session.
+pub struct ReconnectingSession {
+    current: watch::Sender<Session>,
+    /// Set by [`Self::close`]
+    stopped: watch::Sender<bool>,
+}
+
+impl Clone for ReconnectingSession {
+    fn clone(&self) -> Self {
+        Self {
+            current: self.current.clone(),
+            stopped: self.stopped.clone(),
+        }
+    }
+}
+
+impl ReconnectingSession {
+    pub async fn connect(addr: &str, path: &str) -> crate::Result<Self> {
+        Self::connect_with(addr, path, &ConnectOptions::default()).await
+    }
+
+    /// Connect with `options`, which every reconnect reuses, e.g. for an
+    /// `Authorization` header
+    pub async fn connect_with(
+        addr: &str,
+        path: &str,
+        options: &ConnectOptions,
+    ) -> crate::Result<Self> {
+        let session = Session::connect_with(addr, path, options).await?;
+        session.start_receiver();
+
+        let s = Self {
+            current: watch::Sender::new(session),
+            stopped: watch::Sender::new(false),
+        };
+
+        let (addr, path, base) = (addr.to_string(), path.to_string(), options.clone());
+        let r = s.clone();
+        tokio::spawn(async move {
+            let mut ticket = None;
+
+            loop {
+                let old = r.session();
+                old.closed().await;
+
+                // Resume with the newest ticket, as late as it arrived
+                ticket = old.ticket().await.or(ticket);
+                let options = ConnectOptions {
+                    ticket: ticket.clone().or(base.ticket.clone()),
+                    ..base.clone()
+                };
+
+                let mut backoff = MIN_BACKOFF;
+                let session = loop {
+                    if *r.stopped.borrow() {
+                        return;
+                    }
+
+                    match Session::connect_with(&addr, &path, &options).await {
+                        Ok(session) => break session,
+                        Err(_) => {
+                            tokio::time::sleep(backoff).await;
+                            backoff = (backoff * 2).min(MAX_BACKOFF);
+                        }
+                    }
+                };
+
+                session.start_receiver();
+                r.current.send_replace(session);
+            }
+        });
+
+        Ok(s)
+    }
+
+    /// The live session. Handlers registered on it don't carry over to the
+    /// session that replaces it after a reconnect.
+    pub fn session(&self) -> Session {
+        self.current.borrow().clone()
+    }
+
+    /// Wait until the session is replaced by a newer one than `old`, or
+    /// return `false` once reconnecting stopped
+    async fn reconnected(&self, old: &Session) -> bool {
+        let mut current = self.current.subscribe();
+        let mut stopped = self.stopped.subscribe();
+
+        tokio::select! {
+            _ = stopped.wait_for(|stopped| *stopped) => false,
+            _ = current.wait_for(|s| s.id() != old.id()) => true,
+        }
+    }
+
+    /// Like [`Session::request`], but a call interrupted by a disconnect is
+    /// retried on the next connection. Every attempt carries the same
+    /// idempotency key, so a server with an `IdempotencyStore` runs the
+    /// handler once even if the first attempt did reach it, as long as the
+    /// client authenticates as the same principal on every connection.
+    pub async fn call<M: Method>(
+        &self,
+        req: M::Request,
+    ) -> crate::Result<std::result::Result<M::Response, M::Error>>
+    where
+        M::Request: Clone,
+    {
+        let key = format!("{:032x}", rand::random::<u128>());
+        let mut attempt = 1;
+
+        loop {
+            let session = self.session();
+
+            match session
+                .request_with_key::<M>(req.clone(), Some(key.clone()))
+                .await
+            {
+                Err(e) if is_disconnect(&e) && attempt < MAX_CALL_ATTEMPTS => {
+                    attempt += 1;
+                    if !self.reconnected(&session).await {
+                        return Err(e);
+                    }
+                }
+                res => return res,
+            }
+        }
+    }
+
+    /// Close the session and stop reconnecting
+    pub async fn close(&self) -> crate::Result<()> {
+        self.stopped.send_replace(true);
+        self.session().close().await
+    }
+}
+
+fn is_disconnect(e: &crate::Error) -> bool {
+    matches!(
+        e,
+        crate::Error::Io(_)
+            | crate::Error::WebSocket(crate::ws::Error::Io(_))
+            | crate::Error::WebSocket(crate::ws::Error::ConnectionClosed)
+            | crate::Error::WebSocket(crate::ws::Error::PongTimeout)
+    )
+}
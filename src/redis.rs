@@ -0,0 +1,156 @@
+//! [`BanStore`] and [`RateLimitStore`] kept in Redis, so bans and quota
+//! consumption are shared by every instance using the same Redis and
+//! survive restarts. Both fail open: while Redis is unreachable, clients
+//! are treated as not banned and not throttled, and the error is logged.
+
+use ::redis::{AsyncCommands, Script, aio::MultiplexedConnection};
+use tokio::time::Duration;
+
+use crate::{
+    BoxFuture,
+    ban::BanStore,
+    log::{self, Level},
+    rate_limit::{RateLimit, RateLimitStore},
+};
+
+fn failed(event: &'static str, key: &str, e: &::redis::RedisError) {
+    log::log(Level::Warn, event, &[("key", &key), ("error", &e)]);
+}
+
+/// [`BanStore`] holding each ban in a key of its own, expiring with the ban
+pub struct RedisBans {
+    conn: MultiplexedConnection,
+    prefix: String,
+}
+
+impl RedisBans {
+    /// Keys are `session:ban:` followed by the banned key
+    pub fn new(conn: MultiplexedConnection) -> Self {
+        Self::with_prefix(conn, "session:ban:")
+    }
+
+    pub fn with_prefix(conn: MultiplexedConnection, prefix: &str) -> Self {
+        Self {
+            conn,
+            prefix: prefix.to_string(),
+        }
+    }
+}
+
+impl BanStore for RedisBans {
+    fn ban<'a>(
+        &'a self,
+        key: &'a str,
+        reason: &'a str,
+        duration: Option<Duration>,
+    ) -> BoxFuture<'a, ()> {
+        Box::pin(async move {
+            let mut conn = self.conn.clone();
+            let name = format!("{}{key}", self.prefix);
+            let set: ::redis::RedisResult<()> = match duration {
+                Some(duration) => {
+                    let ms = duration.as_millis().max(1) as u64;
+                    conn.pset_ex(name, reason, ms).await
+                }
+                None => conn.set(name, reason).await,
+            };
+            if let Err(e) = set {
+                failed("ban_store_failed", key, &e);
+            }
+        })
+    }
+
+    fn unban<'a>(&'a self, key: &'a str) -> BoxFuture<'a, ()> {
+        Box::pin(async move {
+            let mut conn = self.conn.clone();
+            let deleted: ::redis::RedisResult<()> = conn.del(format!("{}{key}", self.prefix)).await;
+            if let Err(e) = deleted {
+                failed("ban_store_failed", key, &e);
+            }
+        })
+    }
+
+    fn banned<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Option<String>> {
+        Box::pin(async move {
+            let mut conn = self.conn.clone();
+            conn.get(format!("{}{key}", self.prefix))
+                .await
+                .inspect_err(|e| failed("ban_store_failed", key, e))
+                .ok()
+                .flatten()
+        })
+    }
+}
+
+/// Refill and take one token atomically, timed by the Redis clock so
+/// instances with skewed clocks agree. Returns the wait in seconds as a
+/// string, since Redis truncates Lua numbers to integers.
+const ACQUIRE: &str = r"
+local burst = tonumber(ARGV[1])
+local rate = tonumber(ARGV[2])
+local time = redis.call('TIME')
+local now = tonumber(time[1]) + tonumber(time[2]) / 1000000
+
+local bucket = redis.call('HMGET', KEYS[1], 'tokens', 'at')
+local tokens = tonumber(bucket[1]) or burst
+local at = tonumber(bucket[2]) or now
+
+tokens = math.min(tokens + (now - at) * rate, burst) - 1
+redis.call('HSET', KEYS[1], 'tokens', tostring(tokens), 'at', tostring(now))
+redis.call('PEXPIRE', KEYS[1], math.ceil((burst - tokens) / rate * 1000) + 1000)
+
+if tokens < 0 then
+    return tostring(-tokens / rate)
+end
+return '0'
+";
+
+/// [`RateLimitStore`] keeping each token bucket in a hash that expires once
+/// it would be full again
+pub struct RedisRateLimits {
+    conn: MultiplexedConnection,
+    prefix: String,
+    acquire: Script,
+}
+
+impl RedisRateLimits {
+    /// Keys are `session:rate:` followed by the bucket's key
+    pub fn new(conn: MultiplexedConnection) -> Self {
+        Self::with_prefix(conn, "session:rate:")
+    }
+
+    pub fn with_prefix(conn: MultiplexedConnection, prefix: &str) -> Self {
+        Self {
+            conn,
+            prefix: prefix.to_string(),
+            acquire: Script::new(ACQUIRE),
+        }
+    }
+}
+
+impl RateLimitStore for RedisRateLimits {
+    fn acquire<'a>(&'a self, key: &'a str, limit: RateLimit) -> BoxFuture<'a, Duration> {
+        Box::pin(async move {
+            let mut conn = self.conn.clone();
+            let wait: ::redis::RedisResult<String> = self
+                .acquire
+                .key(format!("{}{key}", self.prefix))
+                .arg(limit.burst.max(1))
+                .arg(limit.per_second)
+                .invoke_async(&mut conn)
+                .await;
+
+            match wait {
+                Ok(wait) => wait
+                    .parse()
+                    .ok()
+                    .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+                    .unwrap_or_default(),
+                Err(e) => {
+                    failed("rate_store_failed", key, &e);
+                    Duration::ZERO
+                }
+            }
+        })
+    }
+}
@@ -0,0 +1,153 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+use arc_swap::ArcSwap;
+
+use crate::session::Session;
+
+/// Shards of a [`Registry`]. Session ids are random, so they spread evenly.
+const SHARDS: usize = 64;
+
+/// Sessions by id, split across shards. Each shard is published as an
+/// immutable map that lookups load without locking, so broadcasts never
+/// wait on each other or on a session joining. Writers copy their shard,
+/// change the copy and swap it in, one at a time per shard.
+pub(crate) struct Registry {
+    shards: Box<[Shard]>,
+}
+
+#[derive(Default)]
+struct Shard {
+    sessions: ArcSwap<HashMap<u64, Arc<Session>>>,
+    write: Mutex<()>,
+}
+
+impl Shard {
+    fn update<R>(&self, change: impl FnOnce(&mut HashMap<u64, Arc<Session>>) -> R) -> R {
+        let _write = self.write.lock().unwrap_or_else(|e| e.into_inner());
+        let mut sessions = HashMap::clone(&self.sessions.load());
+        let result = change(&mut sessions);
+        self.sessions.store(Arc::new(sessions));
+        result
+    }
+}
+
+impl Default for Registry {
+    fn default() -> Self {
+        Self {
+            shards: (0..SHARDS).map(|_| Shard::default()).collect(),
+        }
+    }
+}
+
+impl Registry {
+    fn shard(&self, id: u64) -> &Shard {
+        &self.shards[id as usize % SHARDS]
+    }
+
+    /// Returns the session that had this id before, if any
+    pub(crate) fn insert(&self, session: &Session) -> Option<Session> {
+        let old = self
+            .shard(session.id())
+            .update(|sessions| sessions.insert(session.id(), Arc::new(session.clone())));
+        old.map(|old| Session::clone(&old))
+    }
+
+    pub(crate) fn remove(&self, id: u64) -> Option<Session> {
+        let shard = self.shard(id);
+        if !shard.sessions.load().contains_key(&id) {
+            return None;
+        }
+        let old = shard.update(|sessions| sessions.remove(&id));
+        old.map(|old| Session::clone(&old))
+    }
+
+    pub(crate) fn get(&self, id: u64) -> Option<Session> {
+        let sessions = self.shard(id).sessions.load();
+        sessions.get(&id).map(|session| Session::clone(session))
+    }
+
+    pub(crate) fn contains(&self, id: u64) -> bool {
+        self.shard(id).sessions.load().contains_key(&id)
+    }
+
+    /// Sessions of `ids` still registered, in the order of `ids`
+    pub(crate) fn get_all<'a>(&self, ids: impl IntoIterator<Item = &'a u64>) -> Vec<Session> {
+        ids.into_iter().filter_map(|id| self.get(*id)).collect()
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| shard.sessions.load().len())
+            .sum()
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.shards
+            .iter()
+            .all(|shard| shard.sessions.load().is_empty())
+    }
+
+    /// Every registered session, taken shard by shard: sessions added or
+    /// removed meanwhile may or may not be in it
+    pub(crate) fn snapshot(&self) -> Vec<Session> {
+        let mut sessions = Vec::new();
+        for shard in &self.shards {
+            let shard = shard.sessions.load();
+            sessions.extend(shard.values().map(|session| Session::clone(session)));
+        }
+        sessions
+    }
+}
+
+/// Session ids per room
+pub(crate) type Memberships = HashMap<String, Arc<HashSet<u64>>>;
+
+/// Room memberships, published like a [`Registry`] shard: readers load the
+/// current map without locking, writers swap in a changed copy one at a
+/// time. A change to several rooms lands in one swap, so no reader sees
+/// half of it. Copies share the member sets of rooms they don't touch.
+#[derive(Default)]
+pub(crate) struct Rooms {
+    current: ArcSwap<Memberships>,
+    write: Mutex<()>,
+}
+
+impl Rooms {
+    pub(crate) fn load(&self) -> Arc<Memberships> {
+        self.current.load_full()
+    }
+
+    pub(crate) fn members(&self, room: &str) -> Option<Arc<HashSet<u64>>> {
+        self.current.load().get(room).cloned()
+    }
+
+    pub(crate) fn update<R>(&self, change: impl FnOnce(&mut Memberships) -> R) -> R {
+        let _write = self.write.lock().unwrap_or_else(|e| e.into_inner());
+        let mut rooms = Memberships::clone(&self.current.load());
+        let result = change(&mut rooms);
+        self.current.store(Arc::new(rooms));
+        result
+    }
+}
+
+/// Put `id` in `room`, creating the room if needed
+pub(crate) fn add_member(rooms: &mut Memberships, room: &str, id: u64) {
+    let members = rooms.entry(room.to_string()).or_default();
+    if !members.contains(&id) {
+        Arc::make_mut(members).insert(id);
+    }
+}
+
+/// Take `id` out of `room`, dropping the room once it's empty
+pub(crate) fn remove_member(rooms: &mut Memberships, room: &str, id: u64) {
+    if let Some(members) = rooms.get_mut(room)
+        && members.contains(&id)
+    {
+        Arc::make_mut(members).remove(&id);
+        if members.is_empty() {
+            rooms.remove(room);
+        }
+    }
+}
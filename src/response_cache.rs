@@ -0,0 +1,121 @@
+use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use tokio::sync::Mutex;
+use tokio::time::{Duration, Instant};
+
+use crate::Method;
+
+/// What a method handler produced: `(is_error, payload)`, or `None` when no
+/// response is sent
+type Outcome = Option<(bool, serde_json::Value)>;
+
+/// (principal, method, hash of the request data)
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) struct CacheKey(Option<String>, String, u64);
+
+/// Server-side cache of successful responses to designated methods, served
+/// to identical requests without running the handler. Entries are kept per
+/// principal (see [`crate::session::Session::set_principal`]); sessions
+/// without one share entries, so only cache methods whose response doesn't
+/// depend on who asks unless every session has a principal.
+pub struct ResponseCache {
+    ttls: HashMap<String, Duration>,
+    /// Cached result and when it expires
+    entries: Mutex<HashMap<CacheKey, (serde_json::Value, Instant)>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl Default for ResponseCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ResponseCache {
+    pub fn new() -> Self {
+        Self {
+            ttls: HashMap::new(),
+            entries: Mutex::new(HashMap::new()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Cache responses of `M` for `ttl`
+    pub fn method<M: Method>(mut self, ttl: Duration) -> Self {
+        self.ttls.insert(M::NAME.to_string(), ttl);
+        self
+    }
+
+    /// Requests answered from the cache
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    /// Requests to cached methods that ran the handler
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+
+    /// Drop every entry of `method`, e.g. after the data behind it changed
+    pub async fn invalidate(&self, method: &str) {
+        self.entries.lock().await.retain(|key, _| key.1 != method);
+    }
+
+    /// Key of a request, or `None` if its method isn't cached
+    pub(crate) fn key(
+        &self,
+        principal: Option<&str>,
+        method: &str,
+        data: &serde_json::Value,
+    ) -> Option<CacheKey> {
+        if !self.ttls.contains_key(method) {
+            return None;
+        }
+
+        let mut hasher = DefaultHasher::new();
+        data.to_string().hash(&mut hasher);
+
+        Some(CacheKey(
+            principal.map(str::to_string),
+            method.to_string(),
+            hasher.finish(),
+        ))
+    }
+
+    /// Serve the cached response of `key`, or run `handler` and cache what
+    /// it returns unless that is an error
+    pub(crate) async fn run(
+        &self,
+        key: CacheKey,
+        handler: impl Future<Output = Outcome>,
+    ) -> Outcome {
+        {
+            let mut entries = self.entries.lock().await;
+            let now = Instant::now();
+            entries.retain(|_, (_, expires)| *expires > now);
+
+            if let Some((result, _)) = entries.get(&key) {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                return Some((false, result.clone()));
+            }
+        }
+
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        let outcome = handler.await;
+
+        if let Some((false, result)) = &outcome {
+            let ttl = self.ttls.get(&key.1).copied().unwrap_or_default();
+            self.entries
+                .lock()
+                .await
+                .insert(key, (result.clone(), Instant::now() + ttl));
+        }
+
+        outcome
+    }
+}
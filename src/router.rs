@@ -0,0 +1,80 @@
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+use crate::{Method, MethodHandler};
+
+/// Request handlers by method name
+#[derive(Clone, Default)]
+pub struct Router {
+    methods: HashMap<String, MethodHandler>,
+}
+
+impl Router {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Handle requests for `M`, replacing any handler registered before
+    pub fn route<M, Fut>(
+        mut self,
+        handler: impl Fn(u64, M::Request) -> Fut + Send + Sync + 'static,
+    ) -> Self
+    where
+        M: Method,
+        Fut: Future<Output = Result<M::Response, M::Error>> + Send + 'static,
+    {
+        self.insert::<M, Fut>(handler);
+        self
+    }
+
+    pub(crate) fn insert<M, Fut>(
+        &mut self,
+        handler: impl Fn(u64, M::Request) -> Fut + Send + Sync + 'static,
+    ) where
+        M: Method,
+        Fut: Future<Output = Result<M::Response, M::Error>> + Send + 'static,
+    {
+        let handler = Arc::new(handler);
+
+        self.methods.insert(
+            M::NAME.to_string(),
+            Arc::new(move |id, value| {
+                let handler = Arc::clone(&handler);
+
+                Box::pin(async move {
+                    Some(
+                        match handler(id, serde_json::from_value(value).ok()?).await {
+                            Ok(v) => (false, serde_json::to_value(v).ok()?),
+                            Err(v) => (true, serde_json::to_value(v).ok()?),
+                        },
+                    )
+                })
+            }),
+        );
+    }
+
+    pub(crate) fn get(&self, method: &str) -> Option<MethodHandler> {
+        self.methods.get(method).cloned()
+    }
+}
+
+/// A router shared by many sessions that can be replaced while they run,
+/// see [`crate::server::SessionServer::set_router`]. Requests already being
+/// handled finish on the router they started with.
+#[derive(Clone, Default)]
+pub struct SharedRouter(Arc<RwLock<Router>>);
+
+impl SharedRouter {
+    pub fn new(router: Router) -> Self {
+        Self(Arc::new(RwLock::new(router)))
+    }
+
+    /// Route every later request to `router`
+    pub fn set(&self, router: Router) {
+        *self.0.write().unwrap_or_else(|e| e.into_inner()) = router;
+    }
+
+    pub(crate) fn get(&self, method: &str) -> Option<MethodHandler> {
+        self.0.read().unwrap_or_else(|e| e.into_inner()).get(method)
+    }
+}
@@ -0,0 +1,57 @@
+use serde::{Serialize, de::DeserializeOwned};
+
+use crate::session::Message;
+use crate::{GenericMethod, Method};
+
+/// What to do with an outbound message that doesn't match the types of its
+/// method, see [`crate::session::Session::set_schema_check`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SchemaCheck {
+    /// Log a warning and send the message anyway
+    Log,
+    /// Panic, so the mismatch can't go unnoticed in development
+    Panic,
+}
+
+/// Why `msg` doesn't match `M`: a method name other than `M::NAME`, or a
+/// payload that doesn't deserialize back into the type it was sent as
+pub(crate) fn mismatch<M: Method>(msg: &Message<M>) -> Option<String> {
+    match msg {
+        Message::Request { method, data, .. }
+        | Message::Notification { method, data, .. }
+        | Message::Push { method, data, .. } => {
+            if method != M::NAME && M::NAME != GenericMethod::NAME {
+                return Some(format!("method {method} sent as {}", M::NAME));
+            }
+            round_trip(data)
+        }
+        Message::Response { result, .. } => round_trip(result),
+        Message::ErrorResponse { error, .. } => round_trip(error),
+        Message::ChannelData { data, .. } => round_trip(data),
+        Message::Ack { .. } | Message::ChannelOpen { .. } | Message::ChannelClose { .. } => None,
+    }
+}
+
+/// Decode an inbound payload of `method`, naming where it first stops
+/// matching `T` in the error
+pub(crate) fn decode<T: DeserializeOwned>(
+    method: &str,
+    value: serde_json::Value,
+) -> crate::Result<T> {
+    serde_path_to_error::deserialize(value).map_err(|e| crate::Error::SchemaMismatch {
+        method: method.to_string(),
+        path: e.path().to_string(),
+        reason: e.inner().to_string(),
+    })
+}
+
+fn round_trip<T: Serialize + DeserializeOwned>(value: &T) -> Option<String> {
+    let json = match serde_json::to_value(value) {
+        Ok(json) => json,
+        Err(e) => return Some(format!("payload doesn't serialize: {e}")),
+    };
+
+    serde_json::from_value::<T>(json)
+        .err()
+        .map(|e| format!("payload doesn't deserialize back: {e}"))
+}
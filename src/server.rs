@@ -1,26 +1,1177 @@
-use std::{net::SocketAddr, sync::Arc};
+#[cfg(unix)]
+use std::net::{IpAddr, Ipv4Addr};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+};
 
-use tokio::{net::TcpListener, time::timeout};
+use serde::{Deserialize, Serialize};
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::{Mutex, Semaphore, broadcast},
+    time::{Duration, Instant, timeout},
+};
 
-use crate::{session::Session, ws::WebSocket};
+use crate::{
+    BoxFuture, Method,
+    ban::BanStore,
+    clock::TokioClock,
+    close_stats::CloseStats,
+    codec::Codec,
+    hub::Hub,
+    idempotency::IdempotencyStore,
+    interceptor::Interceptor,
+    log::{self, Level},
+    metrics::Metrics,
+    rate_limit::{RateLimit, TokenBucket},
+    response_cache::ResponseCache,
+    router::{Router, SharedRouter},
+    schema::SchemaCheck,
+    session::{BulkLane, CloseReason, Session},
+    tenant::Tenants,
+    ws::{
+        self, BufferSource, CookieAuth, HandshakeConfig, HandshakeLimits, HandshakeRequest,
+        OriginPolicy, Rejection, UpgradeHook, WebSocket, close,
+    },
+};
+
+/// Lifecycle events of the server's sessions, see [`SessionServer::events`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ServerEvent {
+    Connected {
+        id: u64,
+        addr: SocketAddr,
+    },
+    Disconnected {
+        id: u64,
+        reason: CloseReason,
+    },
+    /// The session connected just before presented a ticket that the
+    /// handler from [`SessionServer::with_resume_handler`] accepted
+    Resumed {
+        id: u64,
+    },
+    /// A client was turned away because its IP or principal is banned
+    Banned {
+        ip: IpAddr,
+    },
+    /// This subscriber fell behind and `count` events were dropped before
+    /// it saw them. Anything built up from events, e.g. a list of who is
+    /// online, is now stale and needs rebuilding from the source of truth
+    /// such as [`crate::hub::Hub`].
+    MissedMessages {
+        count: u64,
+    },
+}
+
+/// Subscription to [`ServerEvent`]s, see [`SessionServer::events`]
+pub struct Events {
+    rx: broadcast::Receiver<ServerEvent>,
+}
+
+impl Events {
+    /// Next event, or `None` once the server is gone. Events dropped
+    /// because this subscriber fell behind show up as one
+    /// [`ServerEvent::MissedMessages`] in their place.
+    pub async fn recv(&mut self) -> Option<ServerEvent> {
+        match self.rx.recv().await {
+            Ok(event) => Some(event),
+            Err(broadcast::error::RecvError::Lagged(count)) => {
+                Some(ServerEvent::MissedMessages { count })
+            }
+            Err(broadcast::error::RecvError::Closed) => None,
+        }
+    }
+}
+
+/// The server's own state, see [`SessionServer::health`]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HealthSnapshot {
+    /// Sessions open now
+    pub connections: u64,
+    /// Sessions set up since the server started
+    pub accepted: u64,
+    /// Handshakes that failed or timed out, including refused upgrades
+    pub handshake_failures: u64,
+    /// Sessions turned away at their tenant's connection quota
+    pub shed: u64,
+    /// Bytes buffered against `ws::Config::memory_budget`, if one is set
+    pub memory_used: Option<usize>,
+    pub memory_limit: Option<usize>,
+    pub uptime_secs: u64,
+}
+
+/// Notification carrying a [`HealthSnapshot`], published to a room by
+/// [`SessionServer::with_health_room`]
+pub struct ServerHealth;
+
+impl Method for ServerHealth {
+    const NAME: &'static str = "server_health";
+    type Request = HealthSnapshot;
+    type Response = ();
+    type Error = ();
+}
+
+/// Running totals behind [`HealthSnapshot`]
+struct Counters {
+    started: Instant,
+    connections: AtomicU64,
+    accepted: AtomicU64,
+    handshake_failures: AtomicU64,
+    shed: AtomicU64,
+}
+
+impl Counters {
+    fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            connections: AtomicU64::new(0),
+            accepted: AtomicU64::new(0),
+            handshake_failures: AtomicU64::new(0),
+            shed: AtomicU64::new(0),
+        }
+    }
+
+    fn snapshot(&self, memory_budget: Option<&ws::MemoryBudget>) -> HealthSnapshot {
+        HealthSnapshot {
+            connections: self.connections.load(Ordering::Relaxed),
+            accepted: self.accepted.load(Ordering::Relaxed),
+            handshake_failures: self.handshake_failures.load(Ordering::Relaxed),
+            shed: self.shed.load(Ordering::Relaxed),
+            memory_used: memory_budget.map(|budget| budget.used()),
+            memory_limit: memory_budget.map(|budget| budget.limit()),
+            uptime_secs: self.started.elapsed().as_secs(),
+        }
+    }
+}
+
+/// Rate cap on new sessions for the first moments after bind, while
+/// clients of a previous instance reconnect all at once. Clients resuming
+/// with a session ticket aren't held back.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WarmUp {
+    pub duration: Duration,
+    pub accept_rate: RateLimit,
+}
+
+/// A configuration problem found by [`SessionServer::validate`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ConfigIssue {
+    /// A limit set to a value nothing can get under, such as zero
+    Limit {
+        setting: &'static str,
+        problem: &'static str,
+    },
+    /// Settings that contradict each other
+    Conflict(String),
+    /// The TLS configuration can't serve WebSocket clients
+    #[cfg(feature = "tls")]
+    Tls(String),
+    /// A client on the same machine couldn't complete the handshake
+    SelfTest(String),
+}
+
+/// Picks the tenant of a new connection, or `None` to leave it unassigned
+type TenantResolver = Arc<dyn Fn(&WebSocket) -> Option<String> + Send + Sync>;
+
+type ResumeHandler =
+    Arc<dyn Fn(Session, String) -> BoxFuture<'static, Result<(), String>> + Send + Sync>;
+
+/// Handles the sessions of one path, see [`SessionServer::with_route`]
+type ConnHandler =
+    Arc<dyn Fn(Session, SocketAddr) -> BoxFuture<'static, crate::Result<()>> + Send + Sync>;
+
+/// Called once per connection for the source of its payload buffers
+type BufferSourceFactory = Arc<dyn Fn() -> Arc<dyn BufferSource> + Send + Sync>;
+
+/// How every accepted session is set up
+#[derive(Clone)]
+struct SessionSetup {
+    ws_config: ws::Config,
+    idempotency: Option<Arc<IdempotencyStore>>,
+    response_cache: Option<Arc<ResponseCache>>,
+    interceptors: Vec<Arc<dyn Interceptor>>,
+    drain_grace: Option<Duration>,
+    max_lifetime: Option<Duration>,
+    metrics: Option<Arc<Metrics>>,
+    close_stats: Option<Arc<CloseStats>>,
+    buffers: Option<BufferSourceFactory>,
+    slow_request: Option<Duration>,
+    bulk_lane: Option<BulkLane>,
+    ndjson: bool,
+    oneshot: bool,
+    schema_check: Option<SchemaCheck>,
+    on_resume: Option<ResumeHandler>,
+    events: broadcast::Sender<ServerEvent>,
+    handshake: HandshakeConfig,
+    counters: Arc<Counters>,
+    bans: Option<Arc<dyn BanStore>>,
+    /// End of the warm-up and the bucket pacing fresh sessions until then
+    warm_up: Option<Arc<(Instant, Mutex<TokenBucket>)>>,
+    /// Codec and router of each subprotocol offered in `handshake`
+    protocols: HashMap<String, (Arc<dyn Codec>, Router)>,
+    /// Handles methods the session has no handler of its own for
+    router: SharedRouter,
+    tenants: Option<(Arc<Tenants>, TenantResolver)>,
+    #[cfg(feature = "tls")]
+    tls: Option<tokio_rustls::TlsAcceptor>,
+    /// Serve connections that don't open with a TLS handshake in plaintext
+    #[cfg(feature = "tls")]
+    plaintext_fallback: bool,
+}
+
+impl SessionSetup {
+    /// Wait for a token while warming up, unless `ws` resumes an earlier
+    /// session
+    async fn pace(&self, ws: &WebSocket) {
+        if let Some((until, bucket)) = self.warm_up.as_deref()
+            && Instant::now() < *until
+            && ws.resume_ticket().is_none()
+        {
+            bucket.lock().await.acquire().await;
+        }
+    }
+
+    /// Upgrade a client of this setup over an in-memory pipe, through TLS if
+    /// configured. Authentication is left out since the probe has no
+    /// credentials.
+    async fn self_test(&self) -> Result<(), String> {
+        let mut config = self.handshake.clone();
+        config.origins = None;
+        config.cookie_auth = None;
+        config.on_upgrade = None;
+        #[cfg(feature = "jwt")]
+        {
+            config.jwt = None;
+        }
+
+        let options = ws::ConnectOptions {
+            protocols: config.protocols.clone(),
+            ..Default::default()
+        };
+        let (client, server) = tokio::io::duplex(64 * 1024);
+
+        let accept = async {
+            #[cfg(feature = "tls")]
+            if let Some(tls) = &self.tls {
+                let stream = tls.accept(server).await?;
+                return WebSocket::handshake_from(stream, &config, None).await;
+            }
+            WebSocket::handshake_from(server, &config, None).await
+        };
+        let connect = async {
+            #[cfg(feature = "tls")]
+            if self.tls.is_some() {
+                let name = tokio_rustls::rustls::pki_types::ServerName::try_from("localhost")
+                    .expect("valid server name");
+                let stream = tokio_rustls::TlsConnector::from(any_certificate())
+                    .connect(name, client)
+                    .await?;
+                return WebSocket::client_handshake(stream, "localhost", "/", &options).await;
+            }
+            WebSocket::client_handshake(client, "localhost", "/", &options).await
+        };
+
+        let (accepted, connected) = timeout(config.limits.timeout, async {
+            tokio::join!(accept, connect)
+        })
+        .await
+        .map_err(|_| "Handshake timed out".to_string())?;
+        let server = accepted.map_err(|e| format!("Server side failed: {e:?}"))?;
+        let client = connected.map_err(|e| format!("Client side failed: {e:?}"))?;
+
+        if server.protocol() != client.protocol() {
+            return Err(format!(
+                "Sides disagree on the subprotocol: {:?} and {:?}",
+                server.protocol(),
+                client.protocol()
+            ));
+        }
+        Ok(())
+    }
+
+    /// Terminate TLS if configured, then run the WebSocket handshake
+    async fn handshake(&self, conn: Conn) -> ws::Result<WebSocket> {
+        let stream = match conn {
+            Conn::Tcp(stream) => stream,
+            #[cfg(unix)]
+            Conn::Unix(stream) => {
+                return WebSocket::handshake_from(stream, &self.handshake, None).await;
+            }
+        };
+        let peer_addr = stream.peer_addr().ok();
+
+        #[cfg(feature = "tls")]
+        if let Some(tls) = &self.tls
+            && (!self.plaintext_fallback || opens_with_tls(&stream).await?)
+        {
+            let stream = tls.accept(stream).await?;
+            return WebSocket::handshake_from(stream, &self.handshake, peer_addr).await;
+        }
+
+        WebSocket::handshake_from(stream, &self.handshake, peer_addr).await
+    }
+
+    async fn session(&self, ws: WebSocket, addr: SocketAddr) -> crate::Result<Session> {
+        let ws = ws
+            .with_close_stats(self.close_stats.clone())
+            .with_buffers(self.buffers.as_ref().map(|make| make()));
+        let protocol = ws.protocol().and_then(|p| self.protocols.get(p)).cloned();
+
+        if let Some(bans) = &self.bans {
+            let ip = addr.ip().to_string();
+            let keys = std::iter::once(ip.as_str()).chain(ws.principal());
+            for key in keys {
+                if let Some(reason) = bans.banned(key).await {
+                    let _ = ws.send_close(close::POLICY_VIOLATION, "Banned").await;
+                    log::log(
+                        Level::Warn,
+                        "banned_rejected",
+                        &[("addr", &addr), ("key", &key), ("reason", &reason)],
+                    );
+                    let _ = self.events.send(ServerEvent::Banned { ip: addr.ip() });
+                    return Err(crate::Error::Banned(reason));
+                }
+            }
+        }
+
+        let tenant = match &self.tenants {
+            Some((tenants, resolve)) => match resolve(&ws) {
+                Some(name) => match tenants.admit(&name).await {
+                    Some(tenant) => Some(tenant),
+                    None => {
+                        let _ = ws
+                            .send_close(close::TRY_AGAIN_LATER, "Tenant connection quota")
+                            .await;
+                        log::log(
+                            Level::Warn,
+                            "tenant_rejected",
+                            &[("addr", &addr), ("tenant", &name)],
+                        );
+                        self.counters.shed.fetch_add(1, Ordering::Relaxed);
+                        return Err(crate::Error::QuotaExceeded);
+                    }
+                },
+                None => None,
+            },
+            None => None,
+        };
+
+        let ws = ws
+            .with_config(self.ws_config.clone())
+            .with_tenant_budget(tenant.as_ref().and_then(|t| t.budget()));
+        let session = Session::from_ws(ws);
+        if let Err(e) = session.hello().await {
+            if let Some(tenant) = &tenant {
+                tenant.release();
+            }
+            return Err(e);
+        }
+        if let Some(principal) = session.ws.principal() {
+            session.set_principal(Some(principal.to_string())).await;
+        }
+        if let Some(tenant) = tenant {
+            session.set_tenant(tenant.clone()).await;
+
+            let s = session.clone();
+            tokio::spawn(async move {
+                s.closed().await;
+                tenant.release();
+            });
+        }
+        if let Some((codec, router)) = protocol {
+            session.set_codec(Some(codec)).await;
+            session.set_router(router).await;
+        }
+        session.set_shared_router(Some(self.router.clone())).await;
+        session
+            .set_idempotency_store(self.idempotency.clone())
+            .await;
+        session
+            .set_response_cache(self.response_cache.clone())
+            .await;
+        for interceptor in &self.interceptors {
+            session.add_interceptor(interceptor.clone()).await;
+        }
+        session.set_metrics(self.metrics.clone()).await;
+        session.set_slow_request_threshold(self.slow_request).await;
+        session.set_ndjson(self.ndjson);
+        session.set_oneshot(self.oneshot);
+        session.set_schema_check(self.schema_check).await;
+        if self.bulk_lane.is_some() {
+            session.set_bulk_lane(self.bulk_lane).await;
+        }
+        if let Some(grace) = self.drain_grace {
+            session.set_drain_grace(grace).await;
+        }
+        if let Some(max) = self.max_lifetime {
+            expire(session.clone(), max);
+        }
+
+        let mut resumed = false;
+        if let (Some(handler), Some(ticket)) = (&self.on_resume, session.ws.resume_ticket()) {
+            match handler(session.clone(), ticket.to_string()).await {
+                Ok(()) => resumed = true,
+                Err(e) => log::log(
+                    Level::Warn,
+                    "resume_failed",
+                    &[("session", &session.id()), ("error", &e)],
+                ),
+            }
+        }
+
+        let id = session.id();
+        let _ = self.events.send(ServerEvent::Connected { id, addr });
+        if resumed {
+            let _ = self.events.send(ServerEvent::Resumed { id });
+        }
+        self.counters.accepted.fetch_add(1, Ordering::Relaxed);
+        self.counters.connections.fetch_add(1, Ordering::Relaxed);
+
+        let s = session.clone();
+        let events = self.events.clone();
+        let counters = self.counters.clone();
+        tokio::spawn(async move {
+            let reason = s.closed().await;
+            counters.connections.fetch_sub(1, Ordering::Relaxed);
+            let _ = events.send(ServerEvent::Disconnected { id, reason });
+        });
+
+        Ok(session)
+    }
+}
+
+/// Close `session` gracefully once it has lived for about `max`
+fn expire(session: Session, max: Duration) {
+    let lifetime = max.mul_f64(1.0 - rand::random::<f64>() / 10.0);
+
+    tokio::spawn(async move {
+        tokio::select! {
+            _ = session.closed() => {}
+            _ = tokio::time::sleep(lifetime) => {
+                log::log(
+                    Level::Info,
+                    "session_expired",
+                    &[("session", &session.id()), ("lifetime", &format!("{lifetime:?}"))],
+                );
+                let _ = session
+                    .close_with(close::SERVICE_RESTART, "Session lifetime reached")
+                    .await;
+            }
+        }
+    });
+}
+
+/// Client TLS config for the self-test, which trusts whatever certificate
+/// the server presents but still checks the server holds its key
+#[cfg(feature = "tls")]
+fn any_certificate() -> Arc<tokio_rustls::rustls::ClientConfig> {
+    use tokio_rustls::rustls::{
+        self, DigitallySignedStruct, SignatureScheme,
+        client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+        crypto::{self, CryptoProvider},
+        pki_types::{CertificateDer, ServerName, UnixTime},
+    };
+
+    #[derive(Debug)]
+    struct AnyCertificate(CryptoProvider);
+
+    impl ServerCertVerifier for AnyCertificate {
+        fn verify_server_cert(
+            &self,
+            _end_entity: &CertificateDer<'_>,
+            _intermediates: &[CertificateDer<'_>],
+            _server_name: &ServerName<'_>,
+            _ocsp_response: &[u8],
+            _now: UnixTime,
+        ) -> Result<ServerCertVerified, rustls::Error> {
+            Ok(ServerCertVerified::assertion())
+        }
+
+        fn verify_tls12_signature(
+            &self,
+            message: &[u8],
+            cert: &CertificateDer<'_>,
+            dss: &DigitallySignedStruct,
+        ) -> Result<HandshakeSignatureValid, rustls::Error> {
+            crypto::verify_tls12_signature(
+                message,
+                cert,
+                dss,
+                &self.0.signature_verification_algorithms,
+            )
+        }
+
+        fn verify_tls13_signature(
+            &self,
+            message: &[u8],
+            cert: &CertificateDer<'_>,
+            dss: &DigitallySignedStruct,
+        ) -> Result<HandshakeSignatureValid, rustls::Error> {
+            crypto::verify_tls13_signature(
+                message,
+                cert,
+                dss,
+                &self.0.signature_verification_algorithms,
+            )
+        }
+
+        fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+            self.0.signature_verification_algorithms.supported_schemes()
+        }
+    }
+
+    let verifier = AnyCertificate(crypto::ring::default_provider());
+    let config = rustls::ClientConfig::builder()
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(verifier))
+        .with_no_client_auth();
+    Arc::new(config)
+}
+
+/// Whether the client's first byte starts a TLS handshake record, left
+/// unread for whichever handshake follows
+#[cfg(feature = "tls")]
+async fn opens_with_tls(stream: &TcpStream) -> std::io::Result<bool> {
+    /// Content type of a TLS handshake record, which a ClientHello is sent in
+    const TLS_HANDSHAKE: u8 = 0x16;
+
+    let mut first = [0u8; 1];
+    let n = stream.peek(&mut first).await?;
+    Ok(n == 1 && first[0] == TLS_HANDSHAKE)
+}
+
+/// Address reported for peers on a Unix socket, which have none
+#[cfg(unix)]
+const UNIX_PEER: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+
+/// Socket the server accepts connections on
+enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+/// A connection accepted from a [`Listener`]
+enum Conn {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Listener {
+    async fn accept(&self) -> std::io::Result<(Conn, SocketAddr)> {
+        match self {
+            Self::Tcp(listener) => {
+                let (stream, addr) = listener.accept().await?;
+                Ok((Conn::Tcp(stream), addr))
+            }
+            #[cfg(unix)]
+            Self::Unix(listener) => {
+                let (stream, _) = listener.accept().await?;
+                Ok((Conn::Unix(stream), UNIX_PEER))
+            }
+        }
+    }
+}
 
 pub struct SessionServer {
-    listener: TcpListener,
+    listener: Listener,
+    setup: SessionSetup,
+    handshakes: Option<Arc<Semaphore>>,
+    bound_at: Instant,
+    /// Handlers `session_loop` picks by request path
+    routes: HashMap<String, ConnHandler>,
+}
+
+/// The listening socket, to pass on to a replacement process
+#[cfg(unix)]
+impl std::os::fd::AsRawFd for SessionServer {
+    fn as_raw_fd(&self) -> std::os::fd::RawFd {
+        match &self.listener {
+            Listener::Tcp(listener) => listener.as_raw_fd(),
+            Listener::Unix(listener) => listener.as_raw_fd(),
+        }
+    }
 }
 
 impl SessionServer {
     pub async fn bind(addr: &str) -> crate::Result<Self> {
-        Ok(Self {
-            listener: TcpListener::bind(addr).await?,
-        })
+        Ok(Self::from_listener(Listener::Tcp(
+            TcpListener::bind(addr).await?,
+        )))
+    }
+
+    /// Listen on a Unix domain socket at `path`, for clients on the same
+    /// host such as sidecars, see [`WebSocket::connect_unix`]. The file must
+    /// not exist yet, so remove one left by an earlier run first. Handlers
+    /// see these peers at 127.0.0.1:0, and TLS isn't used on them.
+    #[cfg(unix)]
+    pub async fn bind_unix(path: impl AsRef<std::path::Path>) -> crate::Result<Self> {
+        Ok(Self::from_listener(Listener::Unix(UnixListener::bind(
+            path,
+        )?)))
+    }
+
+    /// Serve on a listening socket bound elsewhere, e.g. one inherited from
+    /// a parent process during a restart. Must be called within a runtime.
+    pub fn from_std(listener: std::net::TcpListener) -> crate::Result<Self> {
+        listener.set_nonblocking(true)?;
+        Ok(Self::from_listener(Listener::Tcp(TcpListener::from_std(
+            listener,
+        )?)))
+    }
+
+    /// Serve on the first socket passed by systemd socket activation, or
+    /// `None` if the process was not started that way
+    #[cfg(unix)]
+    pub fn from_socket_activation() -> crate::Result<Option<Self>> {
+        use std::os::fd::FromRawFd;
+
+        /// First descriptor passed by systemd, after stdin, stdout and stderr
+        const LISTEN_FDS_START: i32 = 3;
+
+        let for_us = std::env::var("LISTEN_PID")
+            .ok()
+            .and_then(|pid| pid.parse::<u32>().ok())
+            .is_some_and(|pid| pid == std::process::id());
+        let fds = std::env::var("LISTEN_FDS")
+            .ok()
+            .and_then(|n| n.parse::<u32>().ok())
+            .unwrap_or(0);
+
+        if !for_us || fds == 0 {
+            return Ok(None);
+        }
+
+        // SAFETY: systemd hands over ownership of the descriptors from
+        // LISTEN_FDS_START on, and nothing else in the process claims them
+        let listener = unsafe { std::net::TcpListener::from_raw_fd(LISTEN_FDS_START) };
+        Self::from_std(listener).map(Some)
+    }
+
+    fn from_listener(listener: Listener) -> Self {
+        Self {
+            listener,
+            setup: SessionSetup {
+                ws_config: ws::Config::default(),
+                idempotency: None,
+                response_cache: None,
+                interceptors: Vec::new(),
+                drain_grace: None,
+                max_lifetime: None,
+                metrics: None,
+                close_stats: None,
+                buffers: None,
+                slow_request: None,
+                bulk_lane: None,
+                ndjson: false,
+                oneshot: false,
+                schema_check: None,
+                on_resume: None,
+                events: broadcast::channel(1024).0,
+                handshake: HandshakeConfig {
+                    hello: true,
+                    ..Default::default()
+                },
+                counters: Arc::new(Counters::new()),
+                bans: None,
+                warm_up: None,
+                protocols: HashMap::new(),
+                router: SharedRouter::default(),
+                tenants: None,
+                #[cfg(feature = "tls")]
+                tls: None,
+                #[cfg(feature = "tls")]
+                plaintext_fallback: false,
+            },
+            handshakes: None,
+            bound_at: Instant::now(),
+            routes: HashMap::new(),
+        }
+    }
+
+    /// Address the server listens on; an error if it's a Unix socket
+    pub fn local_addr(&self) -> crate::Result<SocketAddr> {
+        match &self.listener {
+            Listener::Tcp(listener) => Ok(listener.local_addr()?),
+            #[cfg(unix)]
+            Listener::Unix(_) => Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "Listening on a Unix socket",
+            )
+            .into()),
+        }
+    }
+
+    /// Config applied to the WebSocket of every accepted session
+    pub fn with_ws_config(mut self, config: ws::Config) -> Self {
+        self.setup.ws_config = config;
+        self
+    }
+
+    /// Deduplicate keyed requests across all sessions through `store`
+    pub fn with_idempotency_store(mut self, store: Arc<IdempotencyStore>) -> Self {
+        self.setup.idempotency = Some(store);
+        self
+    }
+
+    /// Answer requests from `cache` across all sessions, see
+    /// [`ResponseCache`]
+    pub fn with_response_cache(mut self, cache: Arc<ResponseCache>) -> Self {
+        self.setup.response_cache = Some(cache);
+        self
+    }
+
+    /// Add an outbound interceptor to every accepted session, ahead of any
+    /// the session adds itself
+    pub fn with_interceptor(mut self, interceptor: Arc<dyn Interceptor>) -> Self {
+        self.setup.interceptors.push(interceptor);
+        self
+    }
+
+    /// Grace period for handlers of closing sessions, see
+    /// [`Session::set_drain_grace`]
+    pub fn with_drain_grace(mut self, grace: Duration) -> Self {
+        self.setup.drain_grace = Some(grace);
+        self
+    }
+
+    /// Close sessions older than `max` with 1012 ([`close::SERVICE_RESTART`])
+    /// so clients reconnect, e.g. to another node or with fresh credentials.
+    /// Each deadline falls at random in the last tenth of `max`, so sessions
+    /// opened together don't all reconnect at once.
+    pub fn with_max_lifetime(mut self, max: Duration) -> Self {
+        self.setup.max_lifetime = Some(max);
+        self
+    }
+
+    /// Record request handling of all sessions in `metrics`
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.setup.metrics = Some(metrics);
+        self
+    }
+
+    /// Count close codes of all sessions in `stats`
+    pub fn with_close_stats(mut self, stats: Arc<CloseStats>) -> Self {
+        self.setup.close_stats = Some(stats);
+        self
+    }
+
+    /// Read each session's frame payloads into buffers from the source
+    /// `make` returns for it, e.g. a pool per shard, see
+    /// [`WebSocket::with_buffers`]
+    pub fn with_buffer_source(
+        mut self,
+        make: impl Fn() -> Arc<dyn BufferSource> + Send + Sync + 'static,
+    ) -> Self {
+        self.setup.buffers = Some(Arc::new(make));
+        self
+    }
+
+    /// Log requests slower than `threshold` on all sessions, see
+    /// [`Session::set_slow_request_threshold`]
+    pub fn with_slow_request_threshold(mut self, threshold: Duration) -> Self {
+        self.setup.slow_request = Some(threshold);
+        self
+    }
+
+    /// Handle large requests of every session on its own bulk lane, see
+    /// [`Session::set_bulk_lane`]
+    pub fn with_bulk_lane(mut self, lane: BulkLane) -> Self {
+        self.setup.bulk_lane = Some(lane);
+        self
+    }
+
+    /// Accept several JSON messages per text frame on every session, see
+    /// [`Session::set_ndjson`]
+    pub fn with_ndjson(mut self) -> Self {
+        self.setup.ndjson = true;
+        self
+    }
+
+    /// Serve one request per connection: each session answers the first
+    /// request with the router given to [`Self::with_router`] and closes,
+    /// see [`Session::set_oneshot`]. For webhook-like callers and health
+    /// probes that want typed calls without keeping a session open.
+    /// [`Self::with_max_lifetime`] bounds how long one may take to ask.
+    pub fn with_oneshot(mut self) -> Self {
+        self.setup.oneshot = true;
+        self
+    }
+
+    /// Check outbound messages of every session in debug builds, see
+    /// [`Session::set_schema_check`]
+    pub fn with_schema_check(mut self, check: SchemaCheck) -> Self {
+        self.setup.schema_check = Some(check);
+        self
+    }
+
+    /// Let `session_loop` run at most `max` handshakes at once. Further
+    /// connections aren't accepted until one finishes, so they wait in the
+    /// TCP backlog instead of competing with established sessions for CPU.
+    pub fn with_max_pending_handshakes(mut self, max: usize) -> Self {
+        self.handshakes = Some(Arc::new(Semaphore::new(max)));
+        self
+    }
+
+    /// Limit the rate of new sessions for `warm_up.duration` after bind.
+    /// Resuming sessions go ahead of the limit; the others wait for it after
+    /// their handshake, holding a slot of
+    /// [`Self::with_max_pending_handshakes`] while they do.
+    pub fn with_warm_up(mut self, warm_up: WarmUp) -> Self {
+        self.setup.warm_up = Some(Arc::new((
+            self.bound_at + warm_up.duration,
+            Mutex::new(TokenBucket::new(warm_up.accept_rate, Arc::new(TokioClock))),
+        )));
+        self
+    }
+
+    /// Hand sessions upgraded on `path`, e.g. `/chat`, to `handler` instead
+    /// of the closure given to `session_loop`, which keeps the paths
+    /// without a route. Paths match exactly, without the query.
+    pub fn with_route<F, Fut>(mut self, path: &str, handler: F) -> Self
+    where
+        F: Fn(Session, SocketAddr) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = crate::Result<()>> + Send + 'static,
+    {
+        self.routes.insert(
+            path.to_string(),
+            Arc::new(move |session, addr| Box::pin(handler(session, addr))),
+        );
+        self
+    }
+
+    /// Bound how long an upgrade request may take to arrive and how many
+    /// header lines and bytes it may have. The timeout also covers the TLS
+    /// handshake and upgrade hooks.
+    pub fn with_handshake_limits(mut self, limits: HandshakeLimits) -> Self {
+        self.setup.handshake.limits = limits;
+        self
+    }
+
+    /// Refuse upgrades from origins `policy` doesn't allow with 403
+    pub fn with_origin_policy(mut self, policy: OriginPolicy) -> Self {
+        self.setup.handshake.origins = Some(policy);
+        self
+    }
+
+    /// Run `hook` on every upgrade request before answering it. `Ok`
+    /// accepts with extra response headers; `Err` answers with the
+    /// rejection instead, and no session is created.
+    pub fn with_upgrade_hook<Fut>(
+        mut self,
+        hook: impl Fn(HandshakeRequest) -> Fut + Send + Sync + 'static,
+    ) -> Self
+    where
+        Fut: Future<Output = Result<Vec<(String, String)>, Rejection>> + Send + 'static,
+    {
+        self.setup.handshake.on_upgrade = Some(UpgradeHook::new(hook));
+        self
+    }
+
+    /// Accept only upgrades carrying cookie `name` with a value `validate`
+    /// maps to a principal, which becomes the session's
+    /// [`Session::principal`]. The rest are refused with 401.
+    pub fn with_cookie_auth<Fut>(
+        mut self,
+        name: &str,
+        validate: impl Fn(String) -> Fut + Send + Sync + 'static,
+    ) -> Self
+    where
+        Fut: Future<Output = Option<String>> + Send + 'static,
+    {
+        self.setup.handshake.cookie_auth = Some(CookieAuth::new(name, validate));
+        self
+    }
+
+    /// Accept only upgrades with a bearer token `auth` validates, refusing
+    /// the rest with 401. The token's claims are available to the session
+    /// as `session.ws.claims()`, and its `sub` claim becomes the session's
+    /// [`Session::principal`] unless cookie auth already set one.
+    #[cfg(feature = "jwt")]
+    pub fn with_jwt_auth(mut self, auth: crate::jwt::JwtAuth) -> Self {
+        self.setup.handshake.jwt = Some(auth);
+        self
+    }
+
+    /// Called with sessions whose client presented a session ticket, before
+    /// any of their messages are read, to restore the state the ticket
+    /// stands for. On error the session goes on as a fresh one.
+    pub fn with_resume_handler<Fut>(
+        mut self,
+        handler: impl Fn(Session, String) -> Fut + Send + Sync + 'static,
+    ) -> Self
+    where
+        Fut: Future<Output = Result<(), String>> + Send + 'static,
+    {
+        self.setup.on_resume = Some(Arc::new(move |session, ticket| {
+            Box::pin(handler(session, ticket))
+        }));
+        self
+    }
+
+    /// Handle requests of every session with `router`, unless the session
+    /// has its own handler for the method
+    pub fn with_router(self, router: Router) -> Self {
+        self.setup.router.set(router);
+        self
+    }
+
+    /// Swap the router of [`Self::with_router`] for `router` on every
+    /// session, including those already connected. Requests already being
+    /// handled finish on the old router.
+    pub fn set_router(&self, router: Router) {
+        self.setup.router.set(router);
+    }
+
+    /// Offer the `name` subprotocol during the handshake. Sessions that
+    /// negotiate it encode messages with `codec` and dispatch requests to
+    /// `router`. Protocols are preferred in the order they are added;
+    /// clients offering none of them get plain JSON.
+    pub fn with_protocol(mut self, name: &str, codec: Arc<dyn Codec>, router: Router) -> Self {
+        if !self.setup.handshake.protocols.iter().any(|p| p == name) {
+            self.setup.handshake.protocols.push(name.to_string());
+        }
+        self.setup
+            .protocols
+            .insert(name.to_string(), (codec, router));
+        self
+    }
+
+    /// Offer `codec` under its own name, see [`Self::with_protocol`]
+    pub fn with_codec(self, codec: Arc<dyn Codec>, router: Router) -> Self {
+        let name = codec.name();
+        self.with_protocol(&name, codec, router)
+    }
+
+    /// Compress messages with clients that offer permessage-deflate. Small
+    /// messages stay uncompressed per `ws::Config::compression_threshold`.
+    #[cfg(feature = "deflate")]
+    pub fn with_permessage_deflate(mut self) -> Self {
+        self.setup.handshake.permessage_deflate = true;
+        self
+    }
+
+    /// Also compress with `compression` for clients that offer it, e.g.
+    /// `ws::compression::Zstd` for clients built on this crate. Preferred
+    /// over permessage-deflate, but not over extensions added before it.
+    pub fn with_compression(mut self, compression: Arc<dyn ws::Compression>) -> Self {
+        self.setup.handshake.compression.push(compression);
+        self
+    }
+
+    /// Terminate TLS on every accepted connection before the WebSocket
+    /// handshake, so clients can connect with `wss://`
+    #[cfg(feature = "tls")]
+    pub fn with_tls(mut self, config: Arc<tokio_rustls::rustls::ServerConfig>) -> Self {
+        self.setup.tls = Some(tokio_rustls::TlsAcceptor::from(config));
+        self
+    }
+
+    /// Like [`Self::with_tls`], but connections whose first byte isn't a
+    /// TLS handshake record are served in plaintext, so one port takes both
+    /// `wss://` clients and `ws://` ones such as internal health checks
+    #[cfg(feature = "tls")]
+    pub fn with_optional_tls(mut self, config: Arc<tokio_rustls::rustls::ServerConfig>) -> Self {
+        self.setup.tls = Some(tokio_rustls::TlsAcceptor::from(config));
+        self.setup.plaintext_fallback = true;
+        self
+    }
+
+    /// Assign each session to the tenant `resolve` picks for it, enforcing
+    /// the quotas of `tenants` across all of a tenant's sessions. Sessions
+    /// resolved to `None` are not limited.
+    pub fn with_tenants(
+        mut self,
+        tenants: Arc<Tenants>,
+        resolve: impl Fn(&WebSocket) -> Option<String> + Send + Sync + 'static,
+    ) -> Self {
+        self.setup.tenants = Some((tenants, Arc::new(resolve)));
+        self
+    }
+
+    /// Close connections from peers banned in `bans` with 1008 once their
+    /// handshake completes. Peers are looked up by IP address, then by
+    /// principal if they authenticated.
+    pub fn with_bans(mut self, bans: Arc<dyn BanStore>) -> Self {
+        self.setup.bans = Some(bans);
+        self
+    }
+
+    /// Subscribe to connect/disconnect events of all sessions. A subscriber
+    /// that falls more than 1024 events behind misses the oldest ones and
+    /// is told so with [`ServerEvent::MissedMessages`].
+    pub fn events(&self) -> Events {
+        Events {
+            rx: self.setup.events.subscribe(),
+        }
+    }
+
+    /// Connection counts and memory use of the server right now
+    pub fn health(&self) -> HealthSnapshot {
+        let memory_budget = self.setup.ws_config.memory_budget.as_deref();
+        self.setup.counters.snapshot(memory_budget)
+    }
+
+    /// Every `every`, broadcast [`Self::health`] to `room` of `hub` as a
+    /// [`ServerHealth`] notification, so an admin dashboard can watch the
+    /// server by joining the room. Use [`Hub::set_room_access`] to control
+    /// who may join it. Publishing stops once the server is dropped.
+    pub fn with_health_room(self, hub: Hub, room: &str, every: Duration) -> Self {
+        let counters = Arc::downgrade(&self.setup.counters);
+        let memory_budget = self.setup.ws_config.memory_budget.clone();
+        let room = room.to_string();
+
+        tokio::spawn(async move {
+            let mut ticks = tokio::time::interval(every);
+            ticks.tick().await;
+            loop {
+                ticks.tick().await;
+                let Some(counters) = counters.upgrade() else {
+                    break;
+                };
+                let snapshot = counters.snapshot(memory_budget.as_deref());
+                drop(counters);
+
+                if let Err(e) = hub.broadcast::<ServerHealth>(&room, snapshot).await {
+                    log::log(
+                        Level::Warn,
+                        "health_publish_failed",
+                        &[("room", &room), ("error", &format!("{e:?}"))],
+                    );
+                }
+            }
+        });
+        self
+    }
+
+    /// Check the configuration for limits nothing can meet and settings that
+    /// contradict each other, then run a handshake against it over an
+    /// in-memory pipe. Call it before serving so a bad deploy fails at
+    /// startup rather than at the first client.
+    pub async fn validate(&self) -> Result<(), Vec<ConfigIssue>> {
+        let mut issues = Vec::new();
+        let mut limit = |setting, problem| issues.push(ConfigIssue::Limit { setting, problem });
+
+        let ws = &self.setup.ws_config;
+        for (setting, value) in [
+            ("fragment_size", ws.fragment_size),
+            ("max_frame_size", ws.max_frame_size),
+            ("max_message_size", ws.max_message_size),
+            ("max_decompressed_size", ws.max_decompressed_size),
+            ("max_compression_ratio", ws.max_compression_ratio),
+        ] {
+            if value == Some(0) {
+                limit(setting, "must not be zero");
+            }
+        }
+        for (setting, value) in [
+            ("write_timeout", ws.write_timeout),
+            ("close_timeout", ws.close_timeout),
+            ("pong_timeout", ws.pong_timeout),
+            ("drain_grace", self.setup.drain_grace),
+            ("max_lifetime", self.setup.max_lifetime),
+        ] {
+            if value == Some(Duration::ZERO) {
+                limit(setting, "must not be zero");
+            }
+        }
+
+        let limits = self.setup.handshake.limits;
+        if limits.timeout.is_zero() {
+            limit("handshake timeout", "must not be zero");
+        }
+        if limits.max_headers == 0 {
+            limit("max_headers", "must not be zero");
+        }
+        if limits.max_header_bytes == 0 {
+            limit("max_header_bytes", "must not be zero");
+        }
+        if self
+            .handshakes
+            .as_ref()
+            .is_some_and(|h| h.available_permits() == 0)
+        {
+            limit("max_pending_handshakes", "must not be zero");
+        }
+
+        if let (Some(frame), Some(message)) = (ws.max_frame_size, ws.max_message_size)
+            && frame > message
+        {
+            issues.push(ConfigIssue::Conflict(format!(
+                "max_frame_size {frame} exceeds max_message_size {message}"
+            )));
+        }
+        if let (Some(fragment), Some(frame)) = (ws.fragment_size, ws.max_frame_size)
+            && fragment > frame
+        {
+            issues.push(ConfigIssue::Conflict(format!(
+                "fragment_size {fragment} exceeds max_frame_size {frame}, so peers \
+                 with the same config refuse our fragments"
+            )));
+        }
+        for path in self.routes.keys().filter(|path| !path.starts_with('/')) {
+            issues.push(ConfigIssue::Conflict(format!(
+                "Route {path:?} doesn't start with '/' and never matches"
+            )));
+        }
+
+        #[cfg(all(feature = "tls", unix))]
+        if self.setup.tls.is_some() && matches!(self.listener, Listener::Unix(_)) {
+            issues.push(ConfigIssue::Tls(
+                "TLS isn't used on a Unix socket, so clients must connect without it".into(),
+            ));
+        }
+        #[cfg(feature = "tls")]
+        if let Some(tls) = &self.setup.tls {
+            let alpn = &tls.config().alpn_protocols;
+            if !alpn.is_empty() && !alpn.iter().any(|p| p == b"http/1.1") {
+                issues.push(ConfigIssue::Tls(
+                    "ALPN protocols don't include http/1.1, which WebSocket upgrades use".into(),
+                ));
+            }
+        }
+
+        // Only worth trying on settings that can work
+        if issues.is_empty()
+            && let Err(e) = self.setup.self_test().await
+        {
+            issues.push(ConfigIssue::SelfTest(e));
+        }
+
+        match issues.is_empty() {
+            true => Ok(()),
+            false => Err(issues),
+        }
     }
 
     pub async fn accept(&self) -> crate::Result<(Session, SocketAddr)> {
         let (stream, addr) = self.listener.accept().await?;
 
-        let ws = WebSocket::handshake(stream).await?;
+        let handshake = timeout(
+            self.setup.handshake.limits.timeout,
+            self.setup.handshake(stream),
+        )
+        .await;
+        if !matches!(handshake, Ok(Ok(_))) {
+            let failures = &self.setup.counters.handshake_failures;
+            failures.fetch_add(1, Ordering::Relaxed);
+        }
+        let ws = handshake.map_err(|_| {
+            std::io::Error::new(std::io::ErrorKind::TimedOut, "Handshake deadline exceeded")
+        })??;
 
-        Ok((Session::from_ws(ws), addr))
+        self.setup.pace(&ws).await;
+        Ok((self.setup.session(ws, addr).await?, addr))
     }
 
     pub async fn session_loop<F, Fut>(&self, on_conn: F) -> crate::Result<()>
@@ -29,31 +1180,72 @@ impl SessionServer {
         Fut: Future<Output = crate::Result<()>> + Send + 'static,
     {
         let conn_handler = Arc::new(on_conn);
+        let routes = Arc::new(self.routes.clone());
 
         loop {
+            // Never closed, so acquiring only waits
+            let permit = match &self.handshakes {
+                Some(handshakes) => handshakes.clone().acquire_owned().await.ok(),
+                None => None,
+            };
+
             let (stream, addr) = self.listener.accept().await?;
             let conn_handler = conn_handler.clone();
+            let routes = routes.clone();
+            let setup = self.setup.clone();
 
             tokio::spawn(async move {
-                match timeout(
-                    tokio::time::Duration::from_secs(5),
-                    WebSocket::handshake(stream),
-                )
-                .await
-                {
+                let handshake =
+                    timeout(setup.handshake.limits.timeout, setup.handshake(stream)).await;
+                if let Ok(Ok(ws)) = &handshake {
+                    setup.pace(ws).await;
+                }
+                drop(permit);
+
+                match handshake {
                     Ok(Ok(ws)) => {
-                        let session = Session::from_ws(ws);
+                        let Ok(session) = setup.session(ws, addr).await else {
+                            return;
+                        };
                         session.start_receiver();
 
-                        if let Err(e) = conn_handler(session, addr).await {
-                            eprintln!("Connection error: {:?}", e);
+                        log::log(
+                            Level::Info,
+                            "handshake",
+                            &[("addr", &addr), ("session", &session.id())],
+                        );
+
+                        let route = session
+                            .ws
+                            .request()
+                            .and_then(|request| routes.get(&request.path))
+                            .cloned();
+                        let handled = match route {
+                            Some(handler) => handler(session, addr).await,
+                            None => conn_handler(session, addr).await,
+                        };
+
+                        if let Err(e) = handled {
+                            log::log(
+                                Level::Error,
+                                "connection_error",
+                                &[("addr", &addr), ("error", &format!("{e:?}"))],
+                            );
                         }
                     }
                     Ok(Err(e)) => {
-                        eprintln!("Handshake failed from {}: {:?}", addr, e);
+                        let failures = &setup.counters.handshake_failures;
+                        failures.fetch_add(1, Ordering::Relaxed);
+                        log::log(
+                            Level::Warn,
+                            "handshake_failed",
+                            &[("addr", &addr), ("error", &format!("{e:?}"))],
+                        );
                     }
                     Err(_) => {
-                        eprintln!("Handshake failed from {}: Handshake Timeout", addr);
+                        let failures = &setup.counters.handshake_failures;
+                        failures.fetch_add(1, Ordering::Relaxed);
+                        log::log(Level::Warn, "handshake_timeout", &[("addr", &addr)]);
                     }
                 }
             });
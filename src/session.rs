@@ -1,55 +1,315 @@
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::hash::Hash;
-use std::{collections::HashMap, sync::Arc};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
 use tokio::sync::Mutex;
-use tokio::sync::broadcast;
-use tokio::time::timeout;
+use tokio::sync::{Notify, OwnedSemaphorePermit, Semaphore, broadcast, mpsc, watch};
+use tokio::task::{AbortHandle, JoinSet};
+use tokio::time::{Duration, Instant, timeout};
 
 use crate::BoxFuture;
-use crate::{GenericMethod, Method, MethodHandler, ws::WebSocket};
+use crate::call::{CallBuilder, CallOptions, Metadata, Priority};
+use crate::channel::Channel;
+use crate::clock::{Clock, TokioClock};
+use crate::close_stats::{self, Direction};
+use crate::codec::Codec;
+use crate::fair_queue::FairQueue;
+use crate::hello::{self, Capabilities, Hello};
+use crate::idempotency::{IdempotencyKey, IdempotencyStore};
+use crate::interceptor::{self, Interceptor, Verdict};
+use crate::log::{self, Level};
+use crate::metrics::Metrics;
+use crate::rate_limit::{RateLimit, TokenBucket};
+use crate::response_cache::ResponseCache;
+use crate::router::{Router, SharedRouter};
+use crate::schema::{self, SchemaCheck};
+use crate::tenant::Tenant;
+use crate::ws::{ConnectOptions, Frame, WebSocket, close};
+use crate::{GenericMethod, Method};
 
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase", tag = "type")]
 pub enum Message<M: Method> {
     Request {
-        id: u32,
+        id: u64,
         method: String,
         data: M::Request,
+        /// Idempotency key, identical across retries of the same call
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        key: Option<String>,
+        #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+        meta: Metadata,
     },
     Response {
-        id: u32,
+        id: u64,
         result: M::Response,
     },
     ErrorResponse {
-        id: u32,
+        id: u64,
         error: M::Error,
     },
     Notification {
         method: String,
         data: M::Request,
+        /// Position in its room, on broadcasts to rooms with history
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        seq: Option<u64>,
+    },
+    /// Notification the receiver acknowledges with `Ack`, see
+    /// [`Session::push`]
+    Push {
+        id: u64,
+        method: String,
+        data: M::Request,
+    },
+    Ack {
+        id: u64,
+    },
+    ChannelOpen {
+        channel: String,
+    },
+    ChannelData {
+        channel: String,
+        data: M::Request,
+    },
+    ChannelClose {
+        channel: String,
     },
 }
 
+/// Built-in notification from server to client carrying a ticket the client
+/// presents on its next connect to resume this session, see
+/// [`Session::send_ticket`]
+pub struct SessionTicket;
+
+impl Method for SessionTicket {
+    const NAME: &'static str = "session_ticket";
+    type Request = String;
+    type Response = ();
+    type Error = ();
+}
+
+/// Separate handling of large requests, see [`Session::set_bulk_lane`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BulkLane {
+    /// Requests of more bytes than this go to the lane
+    pub threshold: usize,
+    /// Requests that may wait in the lane
+    pub capacity: usize,
+}
+
+/// Round-trip times measured by [`Session::start_ping`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Latency {
+    /// Of the most recent ping
+    pub last: Duration,
+    /// Over the last 16 pings
+    pub average: Duration,
+}
+
+/// A session's state at one instant, for looking into a stuck connection,
+/// see [`Session::debug_snapshot`]
+#[derive(Debug, Clone, Serialize)]
+pub struct SessionSnapshot {
+    pub id: u64,
+    pub principal: Option<String>,
+    pub tenant: Option<String>,
+    pub protocol: Option<String>,
+    pub compression: Option<String>,
+    /// Name of the codec, `None` for plain JSON
+    pub codec: Option<String>,
+    /// Why the session closed, `None` while it is open
+    pub closed: Option<String>,
+    /// See [`Session::pause_reading`]
+    pub reading_paused: bool,
+    /// Time since the last message from the peer
+    pub idle_ms: u64,
+    /// Ids of requests to the peer still awaiting a response
+    pub in_flight: Vec<u64>,
+    /// Notifications waiting for [`Session::recv`]
+    pub inbox: usize,
+    /// Messages waiting in the fair queue, see
+    /// [`Session::enable_fair_queuing`]
+    pub fair_queued: usize,
+    /// Pushes the peer hasn't acked yet
+    pub unacked_pushes: usize,
+    /// Names of the open channels
+    pub channels: Vec<String>,
+    /// Rooms the session is in, filled in by
+    /// [`Hub::debug_snapshot`](crate::hub::Hub::debug_snapshot)
+    pub rooms: Vec<String>,
+    pub throughput: crate::ws::Throughput,
+    /// Average round trip of recent pings
+    pub latency_ms: Option<f64>,
+    pub suppressed_responses: u64,
+    pub expired_messages: u64,
+}
+
+/// Notable conditions on a session, delivered to [`Session::on_event`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum SessionEvent {
+    /// The peer sent a message of at least `size` bytes, over the configured
+    /// `limit`, and the session was closed with 1009
+    MessageTooBig { size: u64, limit: usize },
+    /// A text message that isn't a protocol message, e.g. a plain string
+    /// from a client that doesn't speak the session protocol
+    Text(String),
+}
+
+/// Why a session ended
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum CloseReason {
+    /// [`Session::close`] was called on this side
+    Local,
+    /// The peer closed the connection
+    Remote,
+    /// The peer stopped answering pings
+    PingTimeout,
+    /// Reading or writing failed, e.g. on a protocol violation
+    Error(String),
+}
+
+/// How long request handlers may keep running after their session closed,
+/// unless changed with [`Session::set_drain_grace`]
+const DEFAULT_DRAIN_GRACE: Duration = Duration::from_secs(5);
+
+/// Requests read ahead of the one being handled; past this many, reading
+/// pauses until the handlers catch up
+const REQUEST_QUEUE: usize = 64;
+
+/// Round-trip times averaged by [`Session::latency`]
+const RTT_SAMPLES: usize = 16;
+
+/// Notifications kept for [`Session::recv`] before the oldest are dropped
+const INBOX_CAPACITY: usize = 1024;
+
+/// How long to wait for the peer's hello once both agreed to exchange them
+const HELLO_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Inbox of a channel opened by the peer, waiting for `accept_channel`
+type IncomingChannel = (String, mpsc::UnboundedReceiver<serde_json::Value>);
+
+/// Size threshold of the bulk lane and the queue of its requests
+type BulkQueue = (usize, mpsc::Sender<BoxFuture<'static, ()>>);
+
+type CloseHandler = Box<dyn Fn() -> BoxFuture<'static, Result<(), String>> + Send + Sync>;
+type EventHandler =
+    Box<dyn Fn(SessionEvent) -> BoxFuture<'static, Result<(), String>> + Send + Sync>;
+
 pub struct Session {
     pub ws: WebSocket,
-    id: Arc<Mutex<u32>>,
-    methods: Arc<Mutex<HashMap<String, MethodHandler>>>,
-    on_close_fn:
-        Arc<Mutex<Option<Box<dyn Fn() -> BoxFuture<'static, Result<(), String>> + Send + Sync>>>>,
-    tx: broadcast::Sender<(u32, bool, serde_json::Value)>,
-    pong_tx: broadcast::Sender<()>,
+    id: Arc<Mutex<u64>>,
+    methods: Arc<Mutex<Router>>,
+    /// Consulted for methods `methods` has no handler for
+    shared_router: Arc<Mutex<Option<SharedRouter>>>,
+    codec: Arc<Mutex<Option<Arc<dyn Codec>>>>,
+    on_close_fn: Arc<Mutex<Option<CloseHandler>>>,
+    on_event_fn: Arc<Mutex<Option<EventHandler>>>,
+    tx: broadcast::Sender<(u64, bool, serde_json::Value)>,
+    /// Payloads of received pongs
+    pong_tx: broadcast::Sender<Vec<u8>>,
+    closed: watch::Sender<Option<CloseReason>>,
+    last_activity: Arc<Mutex<Instant>>,
+    rate_limiter: Arc<Mutex<Option<TokenBucket>>>,
+    channels: Arc<Mutex<HashMap<String, mpsc::UnboundedSender<serde_json::Value>>>>,
+    incoming_tx: mpsc::UnboundedSender<IncomingChannel>,
+    incoming_rx: Arc<Mutex<mpsc::UnboundedReceiver<IncomingChannel>>>,
+    idempotency: Arc<Mutex<Option<Arc<IdempotencyStore>>>>,
+    interceptors: Arc<Mutex<Vec<Arc<dyn Interceptor>>>>,
+    fair_queue: Arc<Mutex<Option<FairQueue>>>,
+    fair_queued: Arc<Notify>,
+    paused: watch::Sender<bool>,
+    drain_grace: Arc<Mutex<Duration>>,
+    suppressed_responses: Arc<AtomicU64>,
+    expired_messages: Arc<AtomicU64>,
+    metrics: Arc<Mutex<Option<Arc<Metrics>>>>,
+    slow_request: Arc<Mutex<Option<Duration>>>,
+    clock: Arc<Mutex<Arc<dyn Clock>>>,
+    ticket: Arc<Mutex<Option<String>>>,
+    /// Ids of outstanding requests. A std mutex so the guard can release
+    /// an id when a request future is dropped.
+    in_flight: Arc<std::sync::Mutex<HashSet<u64>>>,
+    max_in_flight: Arc<Mutex<Option<usize>>>,
+    tenant: Arc<Mutex<Option<Arc<Tenant>>>>,
+    /// Metadata of the requests being handled, by request id
+    request_meta: Arc<std::sync::Mutex<HashMap<u64, Metadata>>>,
+    response_cache: Arc<Mutex<Option<Arc<ResponseCache>>>>,
+    principal: watch::Sender<Option<String>>,
+    bulk: Arc<Mutex<Option<BulkQueue>>>,
+    /// Notifications not yet taken by `recv`, oldest first
+    inbox: Arc<std::sync::Mutex<VecDeque<Message<GenericMethod>>>>,
+    inboxed: Arc<Notify>,
+    ndjson: Arc<AtomicBool>,
+    schema_check: Arc<Mutex<Option<SchemaCheck>>>,
+    /// Most recent round-trip times, oldest first
+    rtt: Arc<std::sync::Mutex<VecDeque<Duration>>>,
+    /// Background tasks aborted when the session closes
+    tasks: Arc<std::sync::Mutex<JoinSet<()>>>,
+    /// Bounds unacknowledged pushes, see `set_push_window`
+    push_window: Arc<Mutex<Option<Arc<Semaphore>>>>,
+    /// Permits of the pushes waiting for their ack, by push id
+    unacked: Arc<std::sync::Mutex<HashMap<u64, OwnedSemaphorePermit>>>,
+    push_id: Arc<AtomicU64>,
+    /// Agreed on in the hello, see [`crate::hello`]
+    capabilities: Arc<std::sync::Mutex<Option<Capabilities>>>,
+    /// Close once the first request is answered, see `set_oneshot`
+    oneshot: Arc<AtomicBool>,
+    validate_inbound: Arc<AtomicBool>,
 }
 
-impl Session {
-    pub fn clone(&self) -> Self {
+impl Clone for Session {
+    fn clone(&self) -> Self {
         Self {
             ws: self.ws.clone(),
             id: self.id.clone(),
             methods: self.methods.clone(),
+            shared_router: self.shared_router.clone(),
+            codec: self.codec.clone(),
             on_close_fn: self.on_close_fn.clone(),
+            on_event_fn: self.on_event_fn.clone(),
             tx: self.tx.clone(),
             pong_tx: self.pong_tx.clone(),
+            closed: self.closed.clone(),
+            last_activity: self.last_activity.clone(),
+            rate_limiter: self.rate_limiter.clone(),
+            channels: self.channels.clone(),
+            incoming_tx: self.incoming_tx.clone(),
+            incoming_rx: self.incoming_rx.clone(),
+            idempotency: self.idempotency.clone(),
+            interceptors: self.interceptors.clone(),
+            fair_queue: self.fair_queue.clone(),
+            fair_queued: self.fair_queued.clone(),
+            paused: self.paused.clone(),
+            drain_grace: self.drain_grace.clone(),
+            suppressed_responses: self.suppressed_responses.clone(),
+            expired_messages: self.expired_messages.clone(),
+            metrics: self.metrics.clone(),
+            slow_request: self.slow_request.clone(),
+            clock: self.clock.clone(),
+            ticket: self.ticket.clone(),
+            in_flight: self.in_flight.clone(),
+            max_in_flight: self.max_in_flight.clone(),
+            tenant: self.tenant.clone(),
+            request_meta: self.request_meta.clone(),
+            response_cache: self.response_cache.clone(),
+            principal: self.principal.clone(),
+            bulk: self.bulk.clone(),
+            inbox: self.inbox.clone(),
+            inboxed: self.inboxed.clone(),
+            ndjson: self.ndjson.clone(),
+            schema_check: self.schema_check.clone(),
+            rtt: self.rtt.clone(),
+            tasks: self.tasks.clone(),
+            push_window: self.push_window.clone(),
+            unacked: self.unacked.clone(),
+            push_id: self.push_id.clone(),
+            capabilities: self.capabilities.clone(),
+            oneshot: self.oneshot.clone(),
+            validate_inbound: self.validate_inbound.clone(),
         }
     }
 }
@@ -58,93 +318,647 @@ impl Session {
     pub fn from_ws(ws: WebSocket) -> Self {
         let (tx, _) = broadcast::channel(8192);
         let (pong_tx, _) = broadcast::channel(16);
+        let (closed, _) = watch::channel(None);
+        let (incoming_tx, incoming_rx) = mpsc::unbounded_channel();
 
         Self {
             ws,
             id: Arc::new(Mutex::new(0)),
-            methods: Arc::new(Mutex::new(HashMap::new())),
+            methods: Arc::new(Mutex::new(Router::new())),
+            shared_router: Arc::new(Mutex::new(None)),
+            codec: Arc::new(Mutex::new(None)),
             on_close_fn: Arc::new(Mutex::new(None)),
+            on_event_fn: Arc::new(Mutex::new(None)),
             tx,
             pong_tx,
+            closed,
+            last_activity: Arc::new(Mutex::new(Instant::now())),
+            rate_limiter: Arc::new(Mutex::new(None)),
+            channels: Arc::new(Mutex::new(HashMap::new())),
+            incoming_tx,
+            incoming_rx: Arc::new(Mutex::new(incoming_rx)),
+            idempotency: Arc::new(Mutex::new(None)),
+            interceptors: Arc::new(Mutex::new(Vec::new())),
+            fair_queue: Arc::new(Mutex::new(None)),
+            fair_queued: Arc::new(Notify::new()),
+            paused: watch::Sender::new(false),
+            drain_grace: Arc::new(Mutex::new(DEFAULT_DRAIN_GRACE)),
+            suppressed_responses: Arc::new(AtomicU64::new(0)),
+            expired_messages: Arc::new(AtomicU64::new(0)),
+            metrics: Arc::new(Mutex::new(None)),
+            slow_request: Arc::new(Mutex::new(None)),
+            clock: Arc::new(Mutex::new(Arc::new(TokioClock))),
+            ticket: Arc::new(Mutex::new(None)),
+            in_flight: Arc::new(std::sync::Mutex::new(HashSet::new())),
+            max_in_flight: Arc::new(Mutex::new(None)),
+            tenant: Arc::new(Mutex::new(None)),
+            request_meta: Arc::new(std::sync::Mutex::new(HashMap::new())),
+            response_cache: Arc::new(Mutex::new(None)),
+            principal: watch::Sender::new(None),
+            bulk: Arc::new(Mutex::new(None)),
+            inbox: Arc::new(std::sync::Mutex::new(VecDeque::new())),
+            inboxed: Arc::new(Notify::new()),
+            ndjson: Arc::new(AtomicBool::new(false)),
+            schema_check: Arc::new(Mutex::new(None)),
+            rtt: Arc::new(std::sync::Mutex::new(VecDeque::new())),
+            tasks: Arc::new(std::sync::Mutex::new(JoinSet::new())),
+            push_window: Arc::new(Mutex::new(None)),
+            unacked: Arc::new(std::sync::Mutex::new(HashMap::new())),
+            push_id: Arc::new(AtomicU64::new(0)),
+            capabilities: Arc::new(std::sync::Mutex::new(None)),
+            oneshot: Arc::new(AtomicBool::new(false)),
+            validate_inbound: Arc::new(AtomicBool::new(false)),
         }
     }
 
     pub async fn connect(addr: &str, path: &str) -> crate::Result<Self> {
-        Ok(Self::from_ws(WebSocket::connect(addr, path).await?))
+        Self::connect_with(addr, path, &ConnectOptions::default()).await
+    }
+
+    pub async fn connect_with(
+        addr: &str,
+        path: &str,
+        options: &ConnectOptions,
+    ) -> crate::Result<Self> {
+        let options = ConnectOptions {
+            hello: true,
+            ..options.clone()
+        };
+        let session = Self::from_ws(WebSocket::connect_with(addr, path, &options).await?);
+        session.hello().await?;
+        Ok(session)
+    }
+
+    /// Connect over a Unix domain socket, see [`WebSocket::connect_unix`]
+    #[cfg(unix)]
+    pub async fn connect_unix(
+        path: impl AsRef<std::path::Path>,
+        request_path: &str,
+    ) -> crate::Result<Self> {
+        Self::connect_unix_with(path, request_path, &ConnectOptions::default()).await
+    }
+
+    #[cfg(unix)]
+    pub async fn connect_unix_with(
+        path: impl AsRef<std::path::Path>,
+        request_path: &str,
+        options: &ConnectOptions,
+    ) -> crate::Result<Self> {
+        let options = ConnectOptions {
+            hello: true,
+            ..options.clone()
+        };
+        let ws = WebSocket::connect_unix_with(path, request_path, &options).await?;
+        let session = Self::from_ws(ws);
+        session.hello().await?;
+        Ok(session)
+    }
+
+    /// Connect to a `ws://` or `wss://` URL, see [`WebSocket::connect_url`]
+    pub async fn connect_url(url: &str) -> crate::Result<Self> {
+        Self::connect_url_with(url, &ConnectOptions::default()).await
+    }
+
+    pub async fn connect_url_with(url: &str, options: &ConnectOptions) -> crate::Result<Self> {
+        let options = ConnectOptions {
+            hello: true,
+            ..options.clone()
+        };
+        let session = Self::from_ws(WebSocket::connect_url_with(url, &options).await?);
+        session.hello().await?;
+        Ok(session)
+    }
+
+    /// Exchange hellos with a session-rs peer that agreed to them in the
+    /// upgrade and switch on what both support. Runs before the receiver
+    /// starts, so the peer's hello is the first frame read.
+    pub(crate) async fn hello(&self) -> crate::Result<()> {
+        if !self.ws.hello {
+            return Ok(());
+        }
+
+        let ours = Capabilities::local();
+        let hello = Hello {
+            capabilities: ours.clone(),
+        };
+        self.ws.send(&serde_json::to_string(&hello)?).await?;
+
+        let frame = timeout(HELLO_TIMEOUT, self.ws.read())
+            .await
+            .map_err(|_| crate::ws::Error::HandshakeFailed("No hello from the peer".into()))??;
+        let Frame::Text(text) = frame else {
+            return Err(crate::ws::Error::HandshakeFailed("Expected a hello".into()).into());
+        };
+        let Hello {
+            capabilities: theirs,
+        } = serde_json::from_str(&text)?;
+
+        let common = match self.ws.is_client {
+            true => Capabilities::common(&theirs, &ours),
+            false => Capabilities::common(&ours, &theirs),
+        };
+        // A codec picked through the subprotocol stays
+        if self.ws.protocol().is_none()
+            && let Some(codec) = common.codecs.first().and_then(|name| hello::codec(name))
+        {
+            self.set_codec(Some(codec)).await;
+        }
+
+        log::log(
+            Level::Debug,
+            "hello",
+            &[
+                ("session", &self.id()),
+                ("codecs", &common.codecs.join(",")),
+                ("features", &common.features.join(",")),
+            ],
+        );
+        *self.capabilities.lock().unwrap_or_else(|e| e.into_inner()) = Some(common);
+        Ok(())
+    }
+
+    /// What this session and its peer both support, agreed on in the hello.
+    /// `None` when the peer isn't session-rs or didn't ask for one.
+    pub fn capabilities(&self) -> Option<Capabilities> {
+        self.capabilities
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    /// Connect presenting `ticket` from an earlier session, so the server
+    /// restores its state before the first message
+    pub async fn connect_with_session_ticket(
+        addr: &str,
+        path: &str,
+        ticket: &str,
+    ) -> crate::Result<Self> {
+        Self::connect_with(addr, path, &ConnectOptions::default().ticket(ticket)).await
+    }
+
+    /// Give the client a ticket to resume this session with after a
+    /// reconnect. What it stands for is up to the server's resume handler.
+    pub async fn send_ticket(&self, ticket: &str) -> crate::Result<()> {
+        self.notify::<SessionTicket>(ticket.to_string()).await
+    }
+
+    /// Latest ticket the server sent with [`Self::send_ticket`]
+    pub async fn ticket(&self) -> Option<String> {
+        self.ticket.lock().await.clone()
+    }
+
+    /// Tenant the server assigned this session to, see
+    /// [`crate::server::SessionServer::with_tenants`]
+    pub async fn tenant(&self) -> Option<String> {
+        let tenant = self.tenant.lock().await;
+        tenant.as_ref().map(|t| t.name().to_string())
+    }
+
+    pub(crate) async fn set_tenant(&self, tenant: Arc<Tenant>) {
+        *self.tenant.lock().await = Some(tenant);
     }
 }
 
 impl Session {
+    /// Identifier unique to this connection, shared by its clones
+    pub fn id(&self) -> u64 {
+        self.ws.id
+    }
+
+    /// Stop reading from the socket after the current message, so the peer
+    /// is slowed down by TCP backpressure instead of messages piling up in
+    /// memory. Pongs aren't read either, so a ping timeout may close a
+    /// session that stays paused too long.
+    pub fn pause_reading(&self) {
+        self.paused.send_replace(true);
+    }
+
+    pub fn resume_reading(&self) {
+        self.paused.send_replace(false);
+    }
+
+    /// Resolves once the session has closed, with the reason
+    pub async fn closed(&self) -> CloseReason {
+        let mut rx = self.closed.subscribe();
+        match rx.wait_for(Option::is_some).await {
+            Ok(reason) => reason.clone().unwrap_or(CloseReason::Local),
+            // The sender lives as long as `self`
+            Err(_) => unreachable!(),
+        }
+    }
+
+    /// Why the session ended, or `None` while it is open
+    pub fn close_reason(&self) -> Option<CloseReason> {
+        self.closed.borrow().clone()
+    }
+
+    /// How long request handlers still running when the session closes may
+    /// take to finish before they are dropped. Handlers that hold on to the
+    /// session can watch [`Self::closed`] to stop early.
+    pub async fn set_drain_grace(&self, grace: Duration) {
+        *self.drain_grace.lock().await = grace;
+    }
+
+    /// Responses not sent because the session closed before its handler
+    /// finished
+    pub fn suppressed_responses(&self) -> u64 {
+        self.suppressed_responses.load(Ordering::Relaxed)
+    }
+
+    /// Run a request handler, dropping it if the session closes and it
+    /// doesn't finish within the drain grace
+    async fn drain(
+        &self,
+        method: &str,
+        handler: impl Future<Output = Option<(bool, serde_json::Value)>>,
+    ) -> Option<(bool, serde_json::Value)> {
+        let grace = *self.drain_grace.lock().await;
+        let clock = self.clock().await;
+
+        tokio::select! {
+            outcome = handler => outcome,
+            _ = async {
+                self.closed().await;
+                clock.sleep(grace).await;
+            } => {
+                log::log(
+                    Level::Warn,
+                    "handler_abandoned",
+                    &[("session", &self.id()), ("method", &method)],
+                );
+                None
+            }
+        }
+    }
+
+    fn response_failed(&self, id: u64, e: crate::Error) {
+        if self.close_reason().is_some() {
+            self.suppressed_responses.fetch_add(1, Ordering::Relaxed);
+            log::log(
+                Level::Debug,
+                "response_suppressed",
+                &[("session", &self.id()), ("request", &id)],
+            );
+        } else {
+            log::log(
+                Level::Warn,
+                "response_failed",
+                &[
+                    ("session", &self.id()),
+                    ("request", &id),
+                    ("error", &format!("{e:?}")),
+                ],
+            );
+        }
+    }
+}
+
+impl Session {
+    /// Run the handler of a request and send its response
+    async fn handle_request(
+        &self,
+        id: u64,
+        method: String,
+        data: serde_json::Value,
+        key: Option<String>,
+        meta: Metadata,
+        size: usize,
+    ) {
+        let handler = self.methods.lock().await.get(&method);
+        let handler = match handler {
+            Some(handler) => Some(handler),
+            None => self
+                .shared_router
+                .lock()
+                .await
+                .as_ref()
+                .and_then(|router| router.get(&method)),
+        };
+        let store = self.idempotency.lock().await.clone();
+
+        let Some(m) = handler else {
+            return;
+        };
+
+        let _meta = (!meta.is_empty()).then(|| RequestMeta::new(&self.request_meta, id, meta));
+
+        if let Some(metrics) = self.metrics.lock().await.as_ref() {
+            metrics.record_request_size(&method, size).await;
+        }
+
+        let cache = self.response_cache.lock().await.clone();
+        let principal = self.principal.borrow().clone();
+        let cached = cache.and_then(|cache| {
+            let key = cache.key(principal.as_deref(), &method, &data)?;
+            Some((cache, key))
+        });
+
+        let handler = async {
+            match (store, key) {
+                (Some(store), Some(key)) => {
+                    let key = IdempotencyKey::new(principal.as_deref(), self.id(), &method, &key);
+                    store.run(key, (m)(id, data)).await
+                }
+                _ => (m)(id, data).await,
+            }
+        };
+        let handler = async {
+            match cached {
+                Some((cache, key)) => cache.run(key, handler).await,
+                None => handler.await,
+            }
+        };
+
+        let started = Instant::now();
+        let outcome = self.drain(&method, handler).await;
+        self.record_call(&method, started.elapsed(), &outcome).await;
+
+        if let Some((err, res)) = outcome {
+            let sent = if err {
+                self.respond_error(id, res).await
+            } else {
+                self.respond(id, res).await
+            };
+            if let Err(e) = sent {
+                self.response_failed(id, e);
+            }
+        }
+    }
+
+    /// Handle requests larger than `lane.threshold` bytes one at a time on a
+    /// separate task, so a huge upload doesn't hold up the small requests
+    /// behind it. Once `lane.capacity` of them are waiting, reading pauses
+    /// until the lane catches up. `None` handles everything in order again.
+    pub async fn set_bulk_lane(&self, lane: Option<BulkLane>) {
+        let Some(lane) = lane else {
+            *self.bulk.lock().await = None;
+            return;
+        };
+
+        let (tx, mut rx) = mpsc::channel::<BoxFuture<'static, ()>>(lane.capacity.max(1));
+        *self.bulk.lock().await = Some((lane.threshold, tx));
+
+        let s = self.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    job = rx.recv() => match job {
+                        Some(job) => job.await,
+                        None => break,
+                    },
+                    _ = s.closed() => break,
+                }
+            }
+        });
+    }
+
     pub fn start_receiver(&self) {
+        // Requests are handled in order on a task of their own, so reading
+        // goes on and a peer leaving mid-request starts the drain grace
+        let (queue, mut requests) = mpsc::channel::<BoxFuture<'static, ()>>(REQUEST_QUEUE);
+        let s = self.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    biased;
+                    _ = s.closed() => break,
+                    job = requests.recv() => match job {
+                        Some(job) => job.await,
+                        None => break,
+                    },
+                }
+            }
+        });
+
         let s = self.clone();
         tokio::spawn(async move {
+            let mut paused = s.paused.subscribe();
+
             loop {
+                tokio::select! {
+                    _ = paused.wait_for(|p| !*p) => {}
+                    _ = s.closed() => break,
+                }
+
                 match s.ws.read().await {
-                    Ok(crate::ws::Frame::Text(text)) => {
-                        let Ok(msg) = serde_json::from_str::<Message<GenericMethod>>(&text) else {
-                            continue;
+                    Ok(frame @ (Frame::Text(_) | Frame::Binary(_))) => {
+                        s.touch().await;
+
+                        let tenant = s.tenant.lock().await.clone();
+                        if let Some(tenant) = tenant {
+                            tenant.throttle().await;
+                        }
+
+                        let size = match &frame {
+                            Frame::Text(text) => text.len(),
+                            Frame::Binary(bytes) => bytes.len(),
+                            _ => 0,
                         };
+                        let metrics = s.metrics.lock().await.clone();
+                        if let Some(metrics) = &metrics {
+                            metrics.record_inbound(size);
+                        }
+
+                        let msgs = s.decode_all(&frame).await;
+                        if msgs.is_empty() {
+                            if let Frame::Text(text) = frame {
+                                s.emit(SessionEvent::Text(text)).await;
+                            }
+                            continue;
+                        }
 
-                        match msg {
-                            Message::Request { id, method, data } => {
-                                let handler = {
-                                    let methods = s.methods.lock().await;
-                                    methods.get(&method).cloned()
-                                };
-
-                                if let Some(m) = handler {
-                                    if let Some((err, res)) = (m)(id, data).await {
-                                        if err {
-                                            s.respond_error(id, res)
-                                                .await
-                                                .expect("Failed to respond");
-                                        } else {
-                                            s.respond(id, res).await.expect("Failed to respond");
+                        for msg in msgs {
+                            match msg {
+                                Message::Request {
+                                    id,
+                                    method,
+                                    data,
+                                    key,
+                                    meta,
+                                } => {
+                                    let oneshot = s.oneshot.load(Ordering::Relaxed);
+                                    let bulk = s.bulk.lock().await.clone();
+                                    let job: BoxFuture<'static, ()> = {
+                                        let s = s.clone();
+                                        Box::pin(async move {
+                                            s.handle_request(id, method, data, key, meta, size)
+                                                .await;
+                                        })
+                                    };
+                                    // Sends wait while the queue is full, holding back reads
+                                    match bulk {
+                                        _ if oneshot => job.await,
+                                        Some((threshold, lane)) if size > threshold => {
+                                            let _ = lane.send(job).await;
+                                        }
+                                        _ => {
+                                            let _ = queue.send(job).await;
                                         }
                                     }
+
+                                    if oneshot {
+                                        // The rest of the batch goes unanswered
+                                        let _ = s.close().await;
+                                        break;
+                                    }
+                                }
+                                Message::Response { id, result } => {
+                                    s.resolve(id, false, result);
+                                }
+                                Message::ErrorResponse { id, error } => {
+                                    s.resolve(id, true, error);
+                                }
+                                Message::ChannelOpen { channel } => {
+                                    let (tx, rx) = mpsc::unbounded_channel();
+                                    s.channels.lock().await.insert(channel.clone(), tx);
+                                    let _ = s.incoming_tx.send((channel, rx));
+                                }
+                                Message::ChannelData { channel, data } => {
+                                    if let Some(tx) = s.channels.lock().await.get(&channel) {
+                                        let _ = tx.send(data);
+                                    }
+                                }
+                                Message::ChannelClose { channel } => {
+                                    s.remove_channel(&channel).await;
+                                }
+                                Message::Notification { method, data, .. }
+                                    if method == SessionTicket::NAME =>
+                                {
+                                    if let Ok(ticket) = serde_json::from_value(data) {
+                                        *s.ticket.lock().await = Some(ticket);
+                                    }
+                                }
+                                Message::Ack { id } => {
+                                    // Dropping the permit lets the next push through
+                                    s.unacked
+                                        .lock()
+                                        .unwrap_or_else(|e| e.into_inner())
+                                        .remove(&id);
+                                }
+                                msg @ (Message::Notification { .. } | Message::Push { .. }) => {
+                                    s.push_inbox(msg)
                                 }
                             }
-                            Message::Response { id, result } => {
-                                s.tx.send((id, false, result)).unwrap();
-                            }
-                            Message::ErrorResponse { id, error } => {
-                                s.tx.send((id, true, error)).unwrap();
-                            }
-                            _ => {}
                         }
                     }
-                    Ok(crate::ws::Frame::Pong) => {
-                        let _ = s.pong_tx.send(());
+                    Ok(Frame::Pong(payload)) => {
+                        let _ = s.pong_tx.send(payload);
+                    }
+                    Ok(Frame::Close) => {
+                        s.trigger_close(CloseReason::Remote).await;
+                        break;
                     }
                     Ok(_) => {}
-                    Err(_) => {
-                        s.trigger_close().await;
+                    Err(e) => {
+                        match &e {
+                            &crate::ws::Error::MessageTooBig { size, limit } => {
+                                log::log(
+                                    Level::Warn,
+                                    "message_too_big",
+                                    &[("session", &s.id()), ("size", &size), ("limit", &limit)],
+                                );
+                                s.emit(SessionEvent::MessageTooBig { size, limit }).await;
+                            }
+                            crate::ws::Error::InvalidFrame(reason) => {
+                                log::log(
+                                    Level::Warn,
+                                    "protocol_violation",
+                                    &[("session", &s.id()), ("reason", reason)],
+                                );
+                            }
+                            crate::ws::Error::Utf8(e) => {
+                                log::log(
+                                    Level::Warn,
+                                    "protocol_violation",
+                                    &[("session", &s.id()), ("reason", e)],
+                                );
+                            }
+                            crate::ws::Error::BudgetExceeded => {
+                                log::log(Level::Warn, "budget_exceeded", &[("session", &s.id())]);
+                            }
+                            _ => {}
+                        }
+
+                        let reason = match e {
+                            crate::ws::Error::ConnectionClosed => CloseReason::Remote,
+                            crate::ws::Error::PongTimeout => CloseReason::PingTimeout,
+                            crate::ws::Error::Io(e)
+                                if matches!(
+                                    e.kind(),
+                                    std::io::ErrorKind::UnexpectedEof
+                                        | std::io::ErrorKind::ConnectionReset
+                                ) =>
+                            {
+                                // Dropped without a close frame
+                                s.ws.record_close(Direction::Received, close_stats::ABNORMAL);
+                                CloseReason::Remote
+                            }
+                            e => CloseReason::Error(format!("{e:?}")),
+                        };
+
+                        s.trigger_close(reason).await;
                         break;
                     }
                 }
             }
         });
     }
+    /// Round-trip times of the pings sent by [`Self::start_ping`], or
+    /// `None` before the first pong
+    pub fn latency(&self) -> Option<Latency> {
+        let samples = self.rtt.lock().unwrap_or_else(|e| e.into_inner());
+        let last = *samples.back()?;
+        let average = samples.iter().sum::<Duration>() / samples.len() as u32;
+
+        Some(Latency { last, average })
+    }
+
+    fn record_rtt(&self, rtt: Duration) {
+        let mut samples = self.rtt.lock().unwrap_or_else(|e| e.into_inner());
+        if samples.len() == RTT_SAMPLES {
+            samples.pop_front();
+        }
+        samples.push_back(rtt);
+    }
+
+    /// Ping the peer every `interval` and close the session if a pong
+    /// doesn't arrive within `timeout_dur`. Each ping carries a counter so
+    /// its pong can be timed, see [`Self::latency`].
     pub fn start_ping(&self, interval: tokio::time::Duration, timeout_dur: tokio::time::Duration) {
         let s = self.clone();
 
         tokio::spawn(async move {
             let mut pong_rx = s.pong_tx.subscribe();
+            let mut seq: u64 = 0;
 
             loop {
-                tokio::time::sleep(interval).await;
+                let clock = s.clock().await;
+                clock.sleep(interval).await;
 
-                if s.ws.send_ping().await.is_err() {
-                    s.trigger_close().await;
+                seq += 1;
+                let payload = seq.to_be_bytes();
+                let sent = clock.now();
+                if let Err(e) = s.ws.send_ping(&payload).await {
+                    s.trigger_close(CloseReason::Error(format!("{e:?}"))).await;
                     break;
                 }
 
-                let result = timeout(timeout_dur, pong_rx.recv()).await;
+                let timed_out = tokio::select! {
+                    pong = pong_rx.recv() => {
+                        // Any pong shows the peer is alive, but only the
+                        // answer to this ping gives a round-trip time
+                        if pong.is_ok_and(|p| p == payload) {
+                            s.record_rtt(clock.now().saturating_duration_since(sent));
+                        }
+                        false
+                    }
+                    _ = clock.sleep(timeout_dur) => true,
+                };
 
-                if result.is_err() {
+                if timed_out {
                     // timeout expired
-                    let _ = s.close().await;
-                    s.trigger_close().await;
+                    s.trigger_close(CloseReason::PingTimeout).await;
+                    let _ = s.ws.close().await;
                     break;
                 }
             }
@@ -156,25 +970,71 @@ impl Session {
         Fut: Future<Output = Result<M::Response, M::Error>> + Send + 'static,
     >(
         &self,
-        handler: impl Fn(u32, M::Request) -> Fut + Send + Sync + 'static,
+        handler: impl Fn(u64, M::Request) -> Fut + Send + Sync + 'static,
     ) {
+        self.methods.lock().await.insert::<M, Fut>(handler);
+    }
+
+    /// Replace all request handlers, including those added by `on_request`.
+    /// Requests already being handled finish on the old handlers.
+    pub async fn set_router(&self, router: Router) {
+        *self.methods.lock().await = router;
+    }
+
+    /// Fall back to `router` for methods without a handler of this session
+    pub async fn set_shared_router(&self, router: Option<SharedRouter>) {
+        *self.shared_router.lock().await = router;
+    }
+
+    /// Calls `handler` with the idle duration once the session has seen no
+    /// application traffic for `threshold`. It fires again only after new
+    /// traffic followed by another idle period; the session stays open.
+    pub fn on_idle<Fut>(
+        &self,
+        threshold: Duration,
+        handler: impl Fn(Duration) -> Fut + Send + Sync + 'static,
+    ) where
+        Fut: Future<Output = Result<(), String>> + Send + 'static,
+    {
+        let s = self.clone();
+        let mut closed = self.closed.subscribe();
+
+        tokio::spawn(async move {
+            let mut fired_for = None;
+
+            loop {
+                let clock = s.clock().await;
+                let last = *s.last_activity.lock().await;
+                let idle = clock.now().saturating_duration_since(last);
+
+                let wait = if idle >= threshold {
+                    if fired_for != Some(last) {
+                        fired_for = Some(last);
+                        let _ = handler(idle).await;
+                    }
+                    threshold
+                } else {
+                    threshold - idle
+                };
+
+                tokio::select! {
+                    _ = clock.sleep(wait) => {}
+                    _ = closed.wait_for(Option::is_some) => break,
+                }
+            }
+        });
+    }
+
+    pub async fn on_event<Fut>(&self, handler: impl Fn(SessionEvent) -> Fut + Send + Sync + 'static)
+    where
+        Fut: Future<Output = Result<(), String>> + Send + 'static,
+    {
         let handler = Arc::new(handler);
 
-        self.methods.lock().await.insert(
-            M::NAME.to_string(),
-            Arc::new(move |id, value| {
-                let handler = Arc::clone(&handler);
-
-                Box::pin(async move {
-                    Some(
-                        match handler(id, serde_json::from_value(value).ok()?).await {
-                            Ok(v) => (false, serde_json::to_value(v).ok()?),
-                            Err(v) => (true, serde_json::to_value(v).ok()?),
-                        },
-                    )
-                })
-            }),
-        );
+        *self.on_event_fn.lock().await = Some(Box::new(move |event| {
+            let handler = handler.clone();
+            Box::pin(async move { handler(event).await })
+        }));
     }
 
     pub async fn on_close<Fut>(&self, handler: impl Fn() -> Fut + Send + Sync + 'static)
@@ -192,47 +1052,572 @@ impl Session {
 
 impl Session {
     pub async fn send<M: Method>(&self, data: &Message<M>) -> crate::Result<()> {
-        self.ws
-            .send_text_payload(&serde_json::to_vec(&data)?)
-            .await?;
+        self.send_with(data, true).await
+    }
+
+    /// Like [`Self::send`], but skips permessage-deflate, for payloads that
+    /// are already compressed or encrypted
+    pub async fn send_uncompressed<M: Method>(&self, data: &Message<M>) -> crate::Result<()> {
+        self.send_with(data, false).await
+    }
+
+    /// Like [`Self::send`], but drops the message if it is still waiting
+    /// behind other writes `ttl` from now, e.g. for a slow client, failing
+    /// with `ws::Error::Expired`. For updates that are worthless once stale,
+    /// such as positions.
+    pub async fn send_with_ttl<M: Method>(
+        &self,
+        data: &Message<M>,
+        ttl: Duration,
+    ) -> crate::Result<()> {
+        let deadline = Instant::now() + ttl;
+        let (opcode, payload) = self.encode_outbound(data).await?;
+
+        match self.ws.send_before(opcode, &payload, deadline).await {
+            Ok(()) => {}
+            Err(crate::ws::Error::Expired) => {
+                self.expired_messages.fetch_add(1, Ordering::Relaxed);
+                log::log(
+                    Level::Debug,
+                    "message_expired",
+                    &[("session", &self.id()), ("method", &M::NAME)],
+                );
+                return Err(crate::ws::Error::Expired.into());
+            }
+            Err(e) => return Err(e.into()),
+        }
+        self.record_outbound(&[payload]).await;
+        self.touch().await;
         Ok(())
     }
 
-    pub async fn use_id(&self) -> u32 {
+    /// Messages [`Self::send_with_ttl`] dropped because their TTL ran out
+    /// before they could be written
+    pub fn expired_messages(&self) -> u64 {
+        self.expired_messages.load(Ordering::Relaxed)
+    }
+
+    async fn send_with<M: Method>(&self, data: &Message<M>, compress: bool) -> crate::Result<()> {
+        let (opcode, payload) = self.encode_outbound(data).await?;
+
+        match compress {
+            true => self.ws.send_batch(opcode, &[&payload]).await?,
+            false => self.ws.send_batch_uncompressed(opcode, &[&payload]).await?,
+        }
+        self.record_outbound(&[payload]).await;
+        self.touch().await;
+        Ok(())
+    }
+
+    /// Opcode and payload of an outbound message, checked and encoded
+    async fn encode_outbound<M: Method>(&self, data: &Message<M>) -> crate::Result<(u8, Vec<u8>)> {
+        self.ensure_open()?;
+        self.check_schema(data).await;
+
+        let codec = self.codec.lock().await.clone();
+
+        let Some(payload) = self.encode(data, codec.as_deref()).await? else {
+            return Err(crate::Error::Vetoed);
+        };
+
+        Ok((opcode(codec.as_deref()), payload))
+    }
+
+    /// Decode responses and [`Self::recv_typed`] notifications through a
+    /// check that fails with `Error::SchemaMismatch`, naming the method and
+    /// the path of the first field that doesn't match, instead of a bare
+    /// `Error::Json`. For noticing a peer that changed its types mid-rollout.
+    pub fn set_validate_inbound(&self, enabled: bool) {
+        self.validate_inbound.store(enabled, Ordering::Relaxed);
+    }
+
+    fn decode_inbound<T: DeserializeOwned>(
+        &self,
+        method: &str,
+        value: serde_json::Value,
+    ) -> crate::Result<T> {
+        match self.validate_inbound.load(Ordering::Relaxed) {
+            true => schema::decode(method, value),
+            false => Ok(serde_json::from_value(value)?),
+        }
+    }
+
+    /// Check outbound typed messages against the types of their method.
+    /// Only debug builds check; release builds ignore the setting.
+    pub async fn set_schema_check(&self, check: Option<SchemaCheck>) {
+        *self.schema_check.lock().await = check;
+    }
+
+    async fn check_schema<M: Method>(&self, data: &Message<M>) {
+        if !cfg!(debug_assertions) {
+            return;
+        }
+        let Some(check) = *self.schema_check.lock().await else {
+            return;
+        };
+        let Some(reason) = schema::mismatch(data) else {
+            return;
+        };
+
+        match check {
+            SchemaCheck::Log => log::log(
+                Level::Warn,
+                "schema_mismatch",
+                &[
+                    ("session", &self.id()),
+                    ("method", &M::NAME),
+                    ("reason", &reason),
+                ],
+            ),
+            SchemaCheck::Panic => panic!("Outbound {} message: {reason}", M::NAME),
+        }
+    }
+
+    async fn record_outbound<P: AsRef<[u8]>>(&self, payloads: &[P]) {
+        if let Some(metrics) = self.metrics.lock().await.as_ref() {
+            for payload in payloads {
+                metrics.record_outbound(payload.as_ref().len());
+            }
+        }
+    }
+
+    /// Writing to a closed session fails up front with
+    /// `ws::Error::ConnectionClosed` instead of whatever the socket reports
+    fn ensure_open(&self) -> crate::Result<()> {
+        match self.close_reason() {
+            Some(_) => Err(crate::ws::Error::ConnectionClosed.into()),
+            None => Ok(()),
+        }
+    }
+
+    /// Serialize an outbound message through the interceptors and codec.
+    /// `None` means an interceptor vetoed it.
+    async fn encode(
+        &self,
+        data: &impl Serialize,
+        codec: Option<&dyn Codec>,
+    ) -> crate::Result<Option<Vec<u8>>> {
+        let chain = self.interceptors.lock().await.clone();
+
+        if chain.is_empty() && codec.is_none() {
+            return Ok(Some(serde_json::to_vec(data)?));
+        }
+
+        let mut value = serde_json::to_value(data)?;
+        if interceptor::run(&chain, &mut value) == Verdict::Veto {
+            return Ok(None);
+        }
+
+        Ok(Some(match codec {
+            Some(codec) => codec.encode(&value)?,
+            None => serde_json::to_vec(&value)?,
+        }))
+    }
+
+    /// Every message in `frame`: one, or with [`Self::set_ndjson`] each
+    /// JSON document of a text frame up to the first malformed one
+    async fn decode_all(&self, frame: &Frame) -> Vec<Message<GenericMethod>> {
+        if let Frame::Text(text) = frame
+            && self.ndjson.load(Ordering::Relaxed)
+            && self.codec.lock().await.is_none()
+        {
+            return serde_json::Deserializer::from_str(text)
+                .into_iter()
+                .map_while(Result::ok)
+                .collect();
+        }
+
+        self.decode(frame).await.into_iter().collect()
+    }
+
+    async fn decode(&self, frame: &Frame) -> Option<Message<GenericMethod>> {
+        let codec = self.codec.lock().await.clone();
+
+        let value = match (frame, codec) {
+            (Frame::Text(text), None) => return serde_json::from_str(text).ok(),
+            (Frame::Text(text), Some(codec)) if !codec.binary() => {
+                codec.decode(text.as_bytes()).ok()?
+            }
+            (Frame::Binary(bytes), Some(codec)) if codec.binary() => codec.decode(bytes).ok()?,
+            _ => return None,
+        };
+
+        serde_json::from_value(value).ok()
+    }
+
+    /// Accept text frames holding several JSON messages, separated by
+    /// whitespace such as newlines, from peers that batch them that way.
+    /// Has no effect while a codec is set.
+    pub fn set_ndjson(&self, enabled: bool) {
+        self.ndjson.store(enabled, Ordering::Relaxed);
+    }
+
+    /// Answer the first request the peer sends, then close with 1000.
+    /// Requests after it, even in the same frame, go unanswered.
+    pub fn set_oneshot(&self, enabled: bool) {
+        self.oneshot.store(enabled, Ordering::Relaxed);
+    }
+
+    /// Encoding used for messages in both directions; `None` is JSON
+    pub async fn set_codec(&self, codec: Option<Arc<dyn Codec>>) {
+        *self.codec.lock().await = codec;
+    }
+
+    /// Append to the chain every outbound message passes through
+    pub async fn add_interceptor(&self, interceptor: Arc<dyn Interceptor>) {
+        self.interceptors.lock().await.push(interceptor);
+    }
+
+    /// Deduplicate requests carrying an idempotency key through `store`
+    pub async fn set_idempotency_store(&self, store: Option<Arc<IdempotencyStore>>) {
+        *self.idempotency.lock().await = store;
+    }
+
+    /// Answer requests to the methods `cache` covers from it when possible
+    pub async fn set_response_cache(&self, cache: Option<Arc<ResponseCache>>) {
+        *self.response_cache.lock().await = cache;
+    }
+
+    /// Who the session acts for, e.g. the authenticated user. Cached
+    /// responses are only shared between sessions with the same principal.
+    /// A change makes every [`crate::hub::Hub`] with room access rules
+    /// re-check the session's rooms.
+    pub async fn set_principal(&self, principal: Option<String>) {
+        self.principal.send_if_modified(|current| {
+            let changed = *current != principal;
+            *current = principal;
+            changed
+        });
+    }
+
+    pub async fn principal(&self) -> Option<String> {
+        self.principal.borrow().clone()
+    }
+
+    /// Sees each change of [`Self::principal`]
+    pub(crate) fn watch_principal(&self) -> watch::Receiver<Option<String>> {
+        self.principal.subscribe()
+    }
+
+    /// Time source for heartbeats, idle detection, draining and rate
+    /// limiting. Set it before starting any of them.
+    pub async fn set_clock(&self, clock: Arc<dyn Clock>) {
+        *self.last_activity.lock().await = clock.now();
+        *self.clock.lock().await = clock;
+    }
+
+    async fn clock(&self) -> Arc<dyn Clock> {
+        self.clock.lock().await.clone()
+    }
+
+    /// Record calls to this session's request handlers in `metrics`
+    pub async fn set_metrics(&self, metrics: Option<Arc<Metrics>>) {
+        *self.metrics.lock().await = metrics;
+    }
+
+    async fn record_call(
+        &self,
+        method: &str,
+        elapsed: Duration,
+        outcome: &Option<(bool, serde_json::Value)>,
+    ) {
+        let error = outcome.as_ref().is_none_or(|(err, _)| *err);
+
+        if let Some(metrics) = self.metrics.lock().await.as_ref() {
+            metrics.record(method, elapsed, error).await;
+        }
+
+        let threshold = *self.slow_request.lock().await;
+        if threshold.is_some_and(|t| elapsed > t) {
+            log::log(
+                Level::Warn,
+                "slow_request",
+                &[
+                    ("session", &self.id()),
+                    ("method", &method),
+                    ("elapsed_ms", &elapsed.as_millis()),
+                ],
+            );
+        }
+    }
+
+    /// Log a `slow_request` warning for every request whose handler takes
+    /// longer than `threshold`; `None` turns it off
+    pub async fn set_slow_request_threshold(&self, threshold: Option<Duration>) {
+        *self.slow_request.lock().await = threshold;
+    }
+
+    /// Limit used by [`Self::send_rate_limited`]; `None` removes the limit
+    pub async fn set_rate_limit(&self, limit: Option<RateLimit>) {
+        let clock = self.clock().await;
+        *self.rate_limiter.lock().await = limit.map(|limit| TokenBucket::new(limit, clock));
+    }
+
+    /// Like [`Self::send`], but waits for the session's rate limit to allow
+    /// another message instead of sending immediately
+    pub async fn send_rate_limited<M: Method>(&self, data: &Message<M>) -> crate::Result<()> {
+        if let Some(bucket) = self.rate_limiter.lock().await.as_mut() {
+            bucket.acquire().await;
+        }
+
+        self.send(data).await
+    }
+
+    /// Rolling-window bytes/sec and messages/sec for both directions
+    pub fn throughput(&self) -> crate::ws::Throughput {
+        self.ws.throughput()
+    }
+
+    /// Like [`Self::send`], but fails with `ws::Error::Elapsed` if the message
+    /// isn't written within `dur`, e.g. because the peer stopped reading
+    pub async fn send_timeout<M: Method>(
+        &self,
+        data: &Message<M>,
+        dur: Duration,
+    ) -> crate::Result<()> {
+        match timeout(dur, self.send(data)).await {
+            Ok(res) => res,
+            Err(e) => Err(crate::ws::Error::from(e).into()),
+        }
+    }
+
+    /// Send already serialized JSON messages in one coalesced write,
+    /// leaving out any an interceptor vetoed
+    pub(crate) async fn send_payloads(&self, payloads: &[Arc<[u8]>]) -> crate::Result<()> {
+        self.ensure_open()?;
+
+        let codec = self.codec.lock().await.clone();
+        let plain = codec.is_none() && self.interceptors.lock().await.is_empty();
+
+        if plain {
+            self.ws.send_batch(0x1, payloads).await?;
+            self.record_outbound(payloads).await;
+        } else {
+            let mut encoded = Vec::with_capacity(payloads.len());
+            for payload in payloads {
+                let value: serde_json::Value = serde_json::from_slice(payload)?;
+                encoded.extend(self.encode(&value, codec.as_deref()).await?);
+            }
+            self.ws
+                .send_batch(opcode(codec.as_deref()), &encoded)
+                .await?;
+            self.record_outbound(&encoded).await;
+        }
+
+        self.touch().await;
+        Ok(())
+    }
+
+    /// Send hub broadcasts tagged with the room they were sent to, queued
+    /// per room if fair queuing is enabled
+    pub(crate) async fn send_topic_payloads(
+        &self,
+        payloads: &[(Arc<str>, Arc<[u8]>)],
+    ) -> crate::Result<()> {
+        if let Some(queue) = self.fair_queue.lock().await.as_mut() {
+            for (topic, payload) in payloads {
+                queue.push(topic, payload.clone());
+            }
+            self.fair_queued.notify_one();
+            return Ok(());
+        }
+
+        let payloads: Vec<_> = payloads.iter().map(|(_, p)| p.clone()).collect();
+        self.send_payloads(&payloads).await
+    }
+
+    /// Interleave hub broadcasts from different rooms by weighted round robin
+    /// instead of sending them in arrival order, so a high-volume room can't
+    /// delay a quiet one. Every room has weight 1 unless set otherwise.
+    pub async fn enable_fair_queuing(&self) {
+        let mut queue = self.fair_queue.lock().await;
+        if queue.is_some() {
+            return;
+        }
+        *queue = Some(FairQueue::new());
+
+        let s = self.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    _ = s.fair_queued.notified() => {}
+                    _ = s.closed() => break,
+                }
+
+                loop {
+                    let round = match s.fair_queue.lock().await.as_mut() {
+                        Some(queue) => queue.next_round(),
+                        None => break,
+                    };
+                    if round.is_empty() || s.send_payloads(&round).await.is_err() {
+                        break;
+                    }
+                }
+            }
+        });
+    }
+
+    /// How many messages `topic` may send per round relative to other rooms.
+    /// Has no effect unless fair queuing is enabled.
+    pub async fn set_topic_weight(&self, topic: &str, weight: u32) {
+        if let Some(queue) = self.fair_queue.lock().await.as_mut() {
+            queue.set_weight(topic, weight);
+        }
+    }
+
+    async fn touch(&self) {
+        *self.last_activity.lock().await = self.clock().await.now();
+    }
+
+    /// Next request id. Ids wrap around and skip 0 and any id still
+    /// awaiting its response, so a response can't be matched to the wrong
+    /// call.
+    pub async fn use_id(&self) -> u64 {
         let mut id = self.id.lock().await;
-        *id += 1;
-        *id
+        let in_flight = self.in_flight.lock().unwrap_or_else(|e| e.into_inner());
+
+        loop {
+            *id = id.wrapping_add(1);
+            if *id != 0 && !in_flight.contains(&*id) {
+                return *id;
+            }
+        }
+    }
+
+    /// Requests sent by this side that are still waiting for a response
+    pub fn in_flight(&self) -> usize {
+        self.in_flight
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .len()
+    }
+
+    /// Cap on [`Self::in_flight`]; a request beyond it fails with
+    /// `Error::InFlightLimit` without being sent. `None` removes the cap.
+    pub async fn set_max_in_flight(&self, max: Option<usize>) {
+        *self.max_in_flight.lock().await = max;
     }
 
     pub async fn request<M: Method>(
         &self,
         req: M::Request,
     ) -> crate::Result<std::result::Result<M::Response, M::Error>> {
+        self.call::<M>(req, CallOptions::default()).await
+    }
+
+    /// Request with per-call options, e.g.
+    /// `session.call_builder::<M>().timeout(d).metadata("trace", id).send(req)`
+    pub fn call_builder<M: Method>(&self) -> CallBuilder<'_, M> {
+        CallBuilder::new(self)
+    }
+
+    /// Metadata the peer sent with request `id`, while its handler runs
+    pub fn request_metadata(&self, id: u64) -> Option<Metadata> {
+        let meta = self.request_meta.lock().unwrap_or_else(|e| e.into_inner());
+        meta.get(&id).cloned()
+    }
+
+    pub(crate) async fn request_with_key<M: Method>(
+        &self,
+        req: M::Request,
+        key: Option<String>,
+    ) -> crate::Result<std::result::Result<M::Response, M::Error>> {
+        let options = CallOptions {
+            key,
+            ..Default::default()
+        };
+        self.call::<M>(req, options).await
+    }
+
+    pub(crate) async fn call<M: Method>(
+        &self,
+        req: M::Request,
+        options: CallOptions,
+    ) -> crate::Result<std::result::Result<M::Response, M::Error>> {
+        match options.timeout {
+            Some(dur) => match timeout(dur, self.call_inner::<M>(req, options)).await {
+                Ok(res) => res,
+                Err(e) => Err(crate::ws::Error::from(e).into()),
+            },
+            None => self.call_inner::<M>(req, options).await,
+        }
+    }
+
+    /// Hands a response to the waiting call. One nobody waits for anymore
+    /// (timed out, cancelled or never sent) is logged and dropped.
+    fn resolve(&self, id: u64, is_error: bool, value: serde_json::Value) {
+        let waiting = self
+            .in_flight
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .contains(&id);
+
+        if !waiting || self.tx.send((id, is_error, value)).is_err() {
+            log::log(
+                Level::Debug,
+                "unmatched_response",
+                &[("session", &self.id()), ("id", &id)],
+            );
+        }
+    }
+
+    /// Fails with `ws::Error::ConnectionClosed` if the session closes before
+    /// the response arrives
+    async fn call_inner<M: Method>(
+        &self,
+        req: M::Request,
+        options: CallOptions,
+    ) -> crate::Result<std::result::Result<M::Response, M::Error>> {
+        let max = *self.max_in_flight.lock().await;
+        if options.priority < Priority::High && max.is_some_and(|max| self.in_flight() >= max) {
+            return Err(crate::Error::InFlightLimit);
+        }
+
         let id = self.use_id().await;
+        let _pending = InFlight::new(&self.in_flight, id);
+
+        // Subscribe first so a fast response can't slip past
+        let mut rx = self.tx.subscribe();
 
-        self.send::<M>(&Message::Request {
+        let msg = Message::<M>::Request {
             id,
             method: M::NAME.to_string(),
             data: req,
-        })
-        .await?;
-
-        let mut rx = self.tx.subscribe();
+            key: options.key,
+            meta: options.metadata,
+        };
+        if options.priority == Priority::Low
+            && let Some(bucket) = self.rate_limiter.lock().await.as_mut()
+        {
+            bucket.acquire().await;
+        }
+        self.send_with(&msg, options.compress).await?;
 
         loop {
-            let r = rx.recv().await?;
+            let r = tokio::select! {
+                r = rx.recv() => r.inspect_err(|e| {
+                    log::log(
+                        Level::Warn,
+                        "response_queue_overflow",
+                        &[("session", &self.id()), ("error", e)],
+                    );
+                })?,
+                _ = self.closed() => {
+                    return Err(crate::ws::Error::ConnectionClosed.into());
+                }
+            };
 
             if r.0 == id {
                 break Ok(if r.1 {
-                    Err(serde_json::from_value(r.2)?)
+                    Err(self.decode_inbound(M::NAME, r.2)?)
                 } else {
-                    Ok(serde_json::from_value(r.2)?)
+                    Ok(self.decode_inbound(M::NAME, r.2)?)
                 });
             }
         }
     }
 
-    pub async fn respond(&self, to: u32, val: serde_json::Value) -> crate::Result<()> {
+    pub async fn respond(&self, to: u64, val: serde_json::Value) -> crate::Result<()> {
         self.send::<GenericMethod>(&Message::Response {
             id: to,
             result: val,
@@ -240,7 +1625,7 @@ impl Session {
         .await
     }
 
-    pub async fn respond_error(&self, to: u32, val: serde_json::Value) -> crate::Result<()> {
+    pub async fn respond_error(&self, to: u64, val: serde_json::Value) -> crate::Result<()> {
         self.send::<GenericMethod>(&Message::ErrorResponse { id: to, error: val })
             .await
     }
@@ -249,20 +1634,344 @@ impl Session {
         self.send::<M>(&Message::Notification {
             method: M::NAME.to_string(),
             data,
+            seq: None,
         })
         .await
     }
 
-    async fn trigger_close(&self) {
+    /// Keep at most `window` pushes unacknowledged: [`Self::push`] waits
+    /// while that many are, until the peer acks one. For peers that can't
+    /// process pushes as fast as the network delivers them. `None` lifts the
+    /// limit.
+    pub async fn set_push_window(&self, window: Option<usize>) {
+        *self.push_window.lock().await = window.map(|n| Arc::new(Semaphore::new(n)));
+        // Pushes under a replaced window no longer count
+        self.unacked
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clear();
+    }
+
+    /// Notification the peer acknowledges with [`Self::ack`] once it has
+    /// processed it, arriving at its [`Self::recv`] as `Message::Push`.
+    /// Waits for room in the push window, failing with
+    /// `ws::Error::ConnectionClosed` if the session closes meanwhile. Peers
+    /// that said in the hello they can't ack get a plain notification.
+    pub async fn push<M: Method>(&self, data: M::Request) -> crate::Result<()> {
+        if self.capabilities().is_some_and(|c| !c.has(hello::ACKS)) {
+            return self.notify::<M>(data).await;
+        }
+
+        let window = self.push_window.lock().await.clone();
+        let permit = match window {
+            Some(window) => tokio::select! {
+                permit = window.acquire_owned() => permit.ok(),
+                _ = self.closed() => return Err(crate::ws::Error::ConnectionClosed.into()),
+            },
+            None => None,
+        };
+
+        let id = self.push_id.fetch_add(1, Ordering::Relaxed) + 1;
+        if let Some(permit) = permit {
+            let mut unacked = self.unacked.lock().unwrap_or_else(|e| e.into_inner());
+            unacked.insert(id, permit);
+        }
+
+        let sent = self
+            .send::<M>(&Message::Push {
+                id,
+                method: M::NAME.to_string(),
+                data,
+            })
+            .await;
+        if sent.is_err() {
+            let mut unacked = self.unacked.lock().unwrap_or_else(|e| e.into_inner());
+            unacked.remove(&id);
+        }
+        sent
+    }
+
+    /// Acknowledge push `id` from the peer, making room in its push window
+    pub async fn ack(&self, id: u64) -> crate::Result<()> {
+        self.send::<GenericMethod>(&Message::Ack { id }).await
+    }
+
+    /// Pushes sent by this side under a push window that the peer hasn't
+    /// acknowledged yet
+    pub fn unacked(&self) -> usize {
+        self.unacked.lock().unwrap_or_else(|e| e.into_inner()).len()
+    }
+
+    /// Everything support needs to look into a stuck connection, taken now.
+    /// Serializes to JSON for handing out of an admin endpoint.
+    pub async fn debug_snapshot(&self) -> SessionSnapshot {
+        let mut in_flight: Vec<u64> = self
+            .in_flight
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .iter()
+            .copied()
+            .collect();
+        in_flight.sort_unstable();
+        let mut channels: Vec<String> = self.channels.lock().await.keys().cloned().collect();
+        channels.sort_unstable();
+
+        let inbox = self.inbox.lock().unwrap_or_else(|e| e.into_inner()).len();
+        let fair_queued = self.fair_queue.lock().await.as_ref().map_or(0, |q| q.len());
+        let last_activity = *self.last_activity.lock().await;
+        let idle = self
+            .clock()
+            .await
+            .now()
+            .saturating_duration_since(last_activity);
+
+        SessionSnapshot {
+            id: self.id(),
+            principal: self.principal().await,
+            tenant: self.tenant().await,
+            protocol: self.ws.protocol().map(str::to_string),
+            compression: self.ws.compression().map(str::to_string),
+            codec: self.codec.lock().await.as_ref().map(|c| c.name()),
+            closed: self.close_reason().map(|reason| format!("{reason:?}")),
+            reading_paused: *self.paused.borrow(),
+            idle_ms: idle.as_millis() as u64,
+            in_flight,
+            inbox,
+            fair_queued,
+            unacked_pushes: self.unacked(),
+            channels,
+            rooms: Vec::new(),
+            throughput: self.throughput(),
+            latency_ms: self
+                .latency()
+                .map(|latency| latency.average.as_secs_f64() * 1000.0),
+            suppressed_responses: self.suppressed_responses(),
+            expired_messages: self.expired_messages(),
+        }
+    }
+
+    /// Open a named channel of `T` items that the peer picks up with
+    /// [`Self::accept_channel`]. Opening a name that is already open replaces
+    /// the existing channel.
+    pub async fn channel<T>(&self, name: &str) -> crate::Result<Channel<T>>
+    where
+        T: Serialize + for<'de> Deserialize<'de> + Send + Sync,
+    {
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.channels.lock().await.insert(name.to_string(), tx);
+
+        self.send::<GenericMethod>(&Message::ChannelOpen {
+            channel: name.to_string(),
+        })
+        .await?;
+
+        Ok(Channel::new(self.clone(), name.to_string(), rx))
+    }
+
+    /// Wait for the peer to open a channel, or `None` once the session closed.
+    /// Items sent before the channel is accepted are kept for it.
+    pub async fn accept_channel<T>(&self) -> Option<Channel<T>>
+    where
+        T: Serialize + for<'de> Deserialize<'de> + Send + Sync,
+    {
+        let mut incoming = self.incoming_rx.lock().await;
+
+        tokio::select! {
+            opened = incoming.recv() => {
+                let (name, rx) = opened?;
+                Some(Channel::new(self.clone(), name, rx))
+            }
+            _ = self.closed() => None,
+        }
+    }
+
+    /// Wait for the next notification from the peer, or `None` once the
+    /// session closed and none are left
+    pub async fn recv(&self) -> Option<Message<GenericMethod>> {
+        self.recv_matching(|_| true).await
+    }
+
+    /// Wait for the first notification `matches` accepts, or `None` once
+    /// the session closed and none are left. Notifications it skips stay
+    /// buffered, in order, for later calls; past 1024 the oldest are dropped.
+    pub async fn recv_matching(
+        &self,
+        matches: impl Fn(&Message<GenericMethod>) -> bool,
+    ) -> Option<Message<GenericMethod>> {
+        loop {
+            // Registered before checking, so a push in between isn't missed
+            let inboxed = self.inboxed.notified();
+            tokio::pin!(inboxed);
+            inboxed.as_mut().enable();
+
+            {
+                let mut inbox = self.inbox.lock().unwrap_or_else(|e| e.into_inner());
+                if let Some(i) = inbox.iter().position(&matches) {
+                    return inbox.remove(i);
+                }
+            }
+            if self.close_reason().is_some() {
+                return None;
+            }
+
+            tokio::select! {
+                _ = inboxed => {}
+                _ = self.closed() => {}
+            }
+        }
+    }
+
+    /// Wait for the next notification of `M` and decode its data, or `None`
+    /// once the session closed and none are left. Other notifications stay
+    /// buffered, as with [`Self::recv_matching`].
+    pub async fn recv_typed<M: Method>(&self) -> Option<crate::Result<M::Request>> {
+        let is_ours = |msg: &Message<GenericMethod>| match msg {
+            Message::Notification { method, .. } => method == M::NAME,
+            _ => false,
+        };
+
+        match self.recv_matching(is_ours).await? {
+            Message::Notification { data, .. } => Some(self.decode_inbound(M::NAME, data)),
+            _ => None,
+        }
+    }
+
+    fn push_inbox(&self, msg: Message<GenericMethod>) {
+        let mut inbox = self.inbox.lock().unwrap_or_else(|e| e.into_inner());
+        if inbox.len() == INBOX_CAPACITY {
+            inbox.pop_front();
+            log::log(Level::Warn, "inbox_overflow", &[("session", &self.id())]);
+        }
+        inbox.push_back(msg);
+        drop(inbox);
+
+        self.inboxed.notify_waiters();
+    }
+
+    pub(crate) async fn remove_channel(&self, name: &str) {
+        self.channels.lock().await.remove(name);
+    }
+
+    async fn emit(&self, event: SessionEvent) {
+        if let Some(handler) = self.on_event_fn.lock().await.as_ref() {
+            let _ = handler(event).await;
+        }
+    }
+
+    /// Run `task` in the background for as long as the session is open; it
+    /// is aborted when the session closes, or right away if it already has.
+    /// For per-connection workers such as tickers and watchers.
+    pub fn spawn(&self, task: impl Future<Output = ()> + Send + 'static) -> AbortHandle {
+        let mut tasks = self.tasks.lock().unwrap_or_else(|e| e.into_inner());
+        // Reap finished tasks so long-lived sessions don't accumulate them
+        while tasks.try_join_next().is_some() {}
+
+        let handle = tasks.spawn(task);
+        if self.close_reason().is_some() {
+            handle.abort();
+        }
+        handle
+    }
+
+    /// Mark the session closed and run the close handler, once; later calls
+    /// are ignored so the first reason sticks
+    async fn trigger_close(&self, reason: CloseReason) {
+        let first = self.closed.send_if_modified(|closed| {
+            if closed.is_some() {
+                return false;
+            }
+            *closed = Some(reason.clone());
+            true
+        });
+        if !first {
+            return;
+        }
+
+        log::log(
+            Level::Info,
+            "close",
+            &[("session", &self.id()), ("reason", &format!("{reason:?}"))],
+        );
+        self.tasks
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .abort_all();
+        self.channels.lock().await.clear();
+
         if let Some(handler) = self.on_close_fn.lock().await.as_ref() {
             let _ = handler().await;
         }
     }
 
+    /// Close with 1000, see [`Self::close_with`]
     pub async fn close(&self) -> crate::Result<()> {
-        let res = self.ws.close().await;
-        self.trigger_close().await;
-        Ok(res?)
+        self.close_with(close::NORMAL, "").await
+    }
+
+    /// Run the closing handshake with `code` and `reason`: the close frame
+    /// is sent, the session is marked closed, and the connection is shut down
+    /// once the peer answers or [`crate::ws::Config::close_timeout`] passes
+    pub async fn close_with(&self, code: u16, reason: &str) -> crate::Result<()> {
+        let sent = self.ws.send_close(code, reason).await;
+        self.trigger_close(CloseReason::Local).await;
+        sent?;
+
+        Ok(self.ws.close_with(code, reason).await?)
+    }
+}
+
+/// Marks a request id as outstanding until dropped
+struct InFlight<'a> {
+    ids: &'a std::sync::Mutex<HashSet<u64>>,
+    id: u64,
+}
+
+impl<'a> InFlight<'a> {
+    fn new(ids: &'a std::sync::Mutex<HashSet<u64>>, id: u64) -> Self {
+        ids.lock().unwrap_or_else(|e| e.into_inner()).insert(id);
+        Self { ids, id }
+    }
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.ids
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(&self.id);
+    }
+}
+
+/// Makes a request's metadata available to [`Session::request_metadata`]
+/// until dropped
+struct RequestMeta<'a> {
+    meta: &'a std::sync::Mutex<HashMap<u64, Metadata>>,
+    id: u64,
+}
+
+impl<'a> RequestMeta<'a> {
+    fn new(meta: &'a std::sync::Mutex<HashMap<u64, Metadata>>, id: u64, data: Metadata) -> Self {
+        meta.lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(id, data);
+        Self { meta, id }
+    }
+}
+
+impl Drop for RequestMeta<'_> {
+    fn drop(&mut self) {
+        self.meta
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(&self.id);
+    }
+}
+
+/// Frame opcode carrying payloads of `codec`
+fn opcode(codec: Option<&dyn Codec>) -> u8 {
+    match codec {
+        Some(codec) if codec.binary() => 0x2,
+        _ => 0x1,
     }
 }
 
@@ -279,3 +1988,55 @@ impl PartialEq for Session {
 }
 
 impl Eq for Session {}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::duplex;
+
+    use super::*;
+    use crate::ws::Upgrade;
+
+    struct Slow;
+
+    impl Method for Slow {
+        const NAME: &'static str = "slow";
+        type Request = u64;
+        type Response = u64;
+        type Error = ();
+    }
+
+    /// A server side session and the client talking to it
+    fn pair() -> (Session, Session) {
+        let (ours, theirs) = duplex(1 << 16);
+        let server = Session::from_ws(WebSocket::from_stream(ours, false, Upgrade::default()));
+        let client = Session::from_ws(WebSocket::from_stream(theirs, true, Upgrade::default()));
+        (server, client)
+    }
+
+    #[tokio::test]
+    async fn peer_closing_mid_request_starts_the_drain() {
+        let (server, client) = pair();
+        server
+            .on_request::<Slow, _>(async |_, ms| {
+                tokio::time::sleep(Duration::from_millis(ms)).await;
+                Ok(ms)
+            })
+            .await;
+        server.set_drain_grace(Duration::from_secs(5)).await;
+        server.start_receiver();
+        client.start_receiver();
+
+        let call = client.clone();
+        tokio::spawn(async move { call.request::<Slow>(300).await });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        client.ws.close().await.unwrap();
+
+        // Seen while the handler still sleeps
+        let reason = timeout(Duration::from_millis(200), server.closed()).await;
+        assert_eq!(reason.ok(), Some(CloseReason::Remote));
+
+        // The handler finishes within the grace, its response suppressed
+        tokio::time::sleep(Duration::from_millis(400)).await;
+        assert_eq!(server.suppressed_responses(), 1);
+    }
+}
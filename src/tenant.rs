@@ -0,0 +1,220 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use tokio::sync::Mutex;
+
+use crate::clock::TokioClock;
+use crate::rate_limit::{RateLimit, RateLimitStore, TokenBucket};
+use crate::ws::{MemoryBudget, Pressure};
+
+/// Caps shared by all sessions of one tenant. `None` leaves a resource
+/// unlimited.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct TenantQuota {
+    /// Open sessions; further connections are closed with 1013
+    pub max_connections: Option<usize>,
+    /// Inbound data messages; reading pauses while the tenant is over it
+    pub message_rate: Option<RateLimit>,
+    /// Inbound bytes being read; reading pauses while the tenant is over it
+    pub max_buffered_bytes: Option<usize>,
+}
+
+/// Snapshot of one tenant's resource use
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TenantUsage {
+    pub connections: usize,
+    pub buffered_bytes: usize,
+    /// Messages whose reading was delayed by the message rate
+    pub throttled: u64,
+    /// Connections closed for exceeding `max_connections`
+    pub rejected: u64,
+}
+
+/// State shared by the sessions of one tenant
+pub(crate) struct Tenant {
+    name: String,
+    connections: AtomicUsize,
+    rate: Option<Mutex<TokenBucket>>,
+    /// Replaces `rate` when the message rate is kept in a shared store
+    shared_rate: Option<(Arc<dyn RateLimitStore>, RateLimit)>,
+    budget: Option<Arc<MemoryBudget>>,
+    throttled: AtomicU64,
+    rejected: AtomicU64,
+}
+
+impl Tenant {
+    fn new(name: &str, quota: &TenantQuota, store: Option<&Arc<dyn RateLimitStore>>) -> Self {
+        let (rate, shared_rate) = match (quota.message_rate, store) {
+            (Some(limit), Some(store)) => (None, Some((store.clone(), limit))),
+            (limit, _) => (
+                limit.map(|limit| Mutex::new(TokenBucket::new(limit, Arc::new(TokioClock)))),
+                None,
+            ),
+        };
+
+        Self {
+            name: name.to_string(),
+            connections: AtomicUsize::new(0),
+            rate,
+            shared_rate,
+            budget: quota
+                .max_buffered_bytes
+                .map(|limit| Arc::new(MemoryBudget::new(limit, Pressure::PauseReads))),
+            throttled: AtomicU64::new(0),
+            rejected: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub(crate) fn budget(&self) -> Option<Arc<MemoryBudget>> {
+        self.budget.clone()
+    }
+
+    /// Wait for the tenant's message rate to allow one more message
+    pub(crate) async fn throttle(&self) {
+        let throttled = match (&self.rate, &self.shared_rate) {
+            (Some(rate), _) => rate.lock().await.acquire().await,
+            (None, Some((store, limit))) => {
+                let wait = store.acquire(&self.name, *limit).await;
+                tokio::time::sleep(wait).await;
+                !wait.is_zero()
+            }
+            (None, None) => false,
+        };
+
+        if throttled {
+            self.throttled.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Give back the connection taken by [`Tenants::admit`]
+    pub(crate) fn release(&self) {
+        self.connections.fetch_sub(1, Ordering::AcqRel);
+    }
+
+    fn usage(&self) -> TenantUsage {
+        TenantUsage {
+            connections: self.connections.load(Ordering::Acquire),
+            buffered_bytes: self.budget.as_ref().map_or(0, |b| b.used()),
+            throttled: self.throttled.load(Ordering::Relaxed),
+            rejected: self.rejected.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Enforces one [`TenantQuota`] per tenant across every session a server
+/// assigns to it, see [`crate::server::SessionServer::with_tenants`]
+pub struct Tenants {
+    quota: TenantQuota,
+    tenants: Mutex<HashMap<String, Arc<Tenant>>>,
+    rate_store: Option<Arc<dyn RateLimitStore>>,
+}
+
+impl Tenants {
+    pub fn new(quota: TenantQuota) -> Self {
+        Self {
+            quota,
+            tenants: Mutex::new(HashMap::new()),
+            rate_store: None,
+        }
+    }
+
+    /// Keep the message rate of each tenant in `store`, keyed by tenant
+    /// name, so instances sharing the store enforce one rate between them
+    pub fn with_rate_store(mut self, store: Arc<dyn RateLimitStore>) -> Self {
+        self.rate_store = Some(store);
+        self
+    }
+
+    /// Count a new connection of `name`, or `None` if it already has its
+    /// maximum
+    pub(crate) async fn admit(&self, name: &str) -> Option<Arc<Tenant>> {
+        let mut tenants = self.tenants.lock().await;
+        let tenant = tenants
+            .entry(name.to_string())
+            .or_insert_with(|| Arc::new(Tenant::new(name, &self.quota, self.rate_store.as_ref())));
+
+        let connections = tenant.connections.load(Ordering::Acquire);
+        if self
+            .quota
+            .max_connections
+            .is_some_and(|max| connections >= max)
+        {
+            tenant.rejected.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+
+        tenant.connections.fetch_add(1, Ordering::AcqRel);
+        Some(tenant.clone())
+    }
+
+    pub async fn usage(&self, name: &str) -> Option<TenantUsage> {
+        self.tenants.lock().await.get(name).map(|t| t.usage())
+    }
+
+    /// Usage of every tenant seen so far
+    pub async fn snapshot(&self) -> HashMap<String, TenantUsage> {
+        let tenants = self.tenants.lock().await;
+        tenants
+            .iter()
+            .map(|(name, tenant)| (name.clone(), tenant.usage()))
+            .collect()
+    }
+
+    /// Render the usage in the Prometheus text exposition format
+    pub async fn prometheus(&self) -> String {
+        let mut snapshot: Vec<_> = self.snapshot().await.into_iter().collect();
+        snapshot.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let mut out = String::new();
+
+        write_series(
+            &mut out,
+            "session_tenant_connections",
+            "gauge",
+            &snapshot,
+            |u| u.connections as u64,
+        );
+        write_series(
+            &mut out,
+            "session_tenant_buffered_bytes",
+            "gauge",
+            &snapshot,
+            |u| u.buffered_bytes as u64,
+        );
+        write_series(
+            &mut out,
+            "session_tenant_throttled_total",
+            "counter",
+            &snapshot,
+            |u| u.throttled,
+        );
+        write_series(
+            &mut out,
+            "session_tenant_rejected_total",
+            "counter",
+            &snapshot,
+            |u| u.rejected,
+        );
+
+        out
+    }
+}
+
+fn write_series(
+    out: &mut String,
+    name: &str,
+    kind: &str,
+    snapshot: &[(String, TenantUsage)],
+    value: impl Fn(&TenantUsage) -> u64,
+) {
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+    for (tenant, usage) in snapshot {
+        let _ = writeln!(out, "{name}{{tenant=\"{tenant}\"}} {}", value(usage));
+    }
+}
@@ -0,0 +1,547 @@
+//! Byte-level test vectors of this crate's wire behaviour, with a runner
+//! checking the crate against them. Other implementations can check
+//! themselves against the same vectors, e.g. exported with [`fixtures`].
+//!
+//! Frame vectors are the bytes one peer sends after the handshake and what
+//! the receiving side makes of them. Mask keys are fixed, so the bytes are
+//! the same on every run.
+
+use serde::{Serialize, Serializer};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+use tokio::time::{Duration, timeout};
+
+use crate::ws::handshake::accept_key;
+use crate::ws::{ConnectOptions, Frame, WebSocket, close, encode_header};
+
+/// Mask key of the RFC 6455 section 5.7 examples, used by every vector
+const MASK: [u8; 4] = [0x37, 0xfa, 0x21, 0x3d];
+
+/// `Sec-WebSocket-Key` of the RFC 6455 section 1.3 example
+const KEY: &str = "dGhlIHNhbXBsZSBub25jZQ==";
+const ACCEPT: &str = "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=";
+
+/// How long the runner waits on a peer that should have answered
+const WAIT: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Peer {
+    Client,
+    Server,
+}
+
+/// What the receiving side does with a frame vector's bytes
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Expect {
+    Text {
+        text: String,
+    },
+    Binary {
+        #[serde(serialize_with = "hex")]
+        payload: Vec<u8>,
+    },
+    /// A ping, answered with a pong carrying the same payload
+    Ping {
+        #[serde(serialize_with = "hex")]
+        payload: Vec<u8>,
+    },
+    Pong {
+        #[serde(serialize_with = "hex")]
+        payload: Vec<u8>,
+    },
+    /// A close, answered by the server with exactly `reply`
+    Close {
+        #[serde(serialize_with = "hex")]
+        reply: Vec<u8>,
+    },
+    /// The connection is failed with a close frame carrying `code`
+    Fail {
+        code: u16,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FrameVector {
+    pub name: &'static str,
+    pub from: Peer,
+    #[serde(serialize_with = "hex")]
+    pub bytes: Vec<u8>,
+    pub expect: Expect,
+}
+
+/// An upgrade request and the server's answer to it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct HandshakeVector {
+    pub name: &'static str,
+    pub request: String,
+    pub status: u16,
+    /// `Sec-WebSocket-Accept` of a 101
+    pub accept: Option<String>,
+}
+
+/// A frame with the fixed mask key, as a client sends it
+fn masked(first: u8, payload: &[u8]) -> Vec<u8> {
+    let (header, len) = encode_header(true, 0, payload.len(), Some(MASK));
+    let mut out = header[..len].to_vec();
+    out[0] = first;
+    out.extend(payload.iter().zip(MASK.iter().cycle()).map(|(b, m)| b ^ m));
+    out
+}
+
+/// A frame as a server sends it
+fn unmasked(first: u8, payload: &[u8]) -> Vec<u8> {
+    let (header, len) = encode_header(true, 0, payload.len(), None);
+    let mut out = header[..len].to_vec();
+    out[0] = first;
+    out.extend_from_slice(payload);
+    out
+}
+
+fn close_payload(code: u16, reason: &[u8]) -> Vec<u8> {
+    [&code.to_be_bytes()[..], reason].concat()
+}
+
+pub fn frame_vectors() -> Vec<FrameVector> {
+    let text = |text: &str| Expect::Text {
+        text: text.to_string(),
+    };
+    let fail = |code| Expect::Fail { code };
+    let bytes_256: Vec<u8> = (0..=255).collect();
+    let bytes_64k: Vec<u8> = (0..65536).map(|i| i as u8).collect();
+
+    let v = |name, from, bytes, expect| FrameVector {
+        name,
+        from,
+        bytes,
+        expect,
+    };
+    use Peer::{Client, Server};
+
+    vec![
+        // RFC 6455 section 5.7
+        v(
+            "rfc_masked_text",
+            Client,
+            vec![
+                0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58,
+            ],
+            text("Hello"),
+        ),
+        v(
+            "rfc_unmasked_text",
+            Server,
+            vec![0x81, 0x05, 0x48, 0x65, 0x6c, 0x6c, 0x6f],
+            text("Hello"),
+        ),
+        v(
+            "rfc_unmasked_fragmented_text",
+            Server,
+            vec![0x01, 0x03, 0x48, 0x65, 0x6c, 0x80, 0x02, 0x6c, 0x6f],
+            text("Hello"),
+        ),
+        v(
+            "rfc_unmasked_ping",
+            Server,
+            vec![0x89, 0x05, 0x48, 0x65, 0x6c, 0x6c, 0x6f],
+            Expect::Ping {
+                payload: b"Hello".to_vec(),
+            },
+        ),
+        v(
+            "rfc_masked_pong",
+            Client,
+            vec![
+                0x8a, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58,
+            ],
+            Expect::Pong {
+                payload: b"Hello".to_vec(),
+            },
+        ),
+        // Length encodings
+        v("empty_text", Client, masked(0x81, b""), text("")),
+        v(
+            "binary_16bit_length",
+            Client,
+            masked(0x82, &bytes_256),
+            Expect::Binary {
+                payload: bytes_256.clone(),
+            },
+        ),
+        v(
+            "binary_64bit_length",
+            Client,
+            masked(0x82, &bytes_64k),
+            Expect::Binary { payload: bytes_64k },
+        ),
+        v(
+            "masked_fragmented_text",
+            Client,
+            [masked(0x01, b"Hel"), masked(0x80, b"lo")].concat(),
+            text("Hello"),
+        ),
+        v(
+            "ping_between_fragments",
+            Client,
+            [
+                masked(0x01, b"Hel"),
+                masked(0x89, b"p"),
+                masked(0x80, b"lo"),
+            ]
+            .concat(),
+            Expect::Ping {
+                payload: b"p".to_vec(),
+            },
+        ),
+        // Close sequences
+        v(
+            "close_echoes_code",
+            Client,
+            masked(0x88, &close_payload(close::NORMAL, b"bye")),
+            Expect::Close {
+                reply: unmasked(0x88, &close_payload(close::NORMAL, b"")),
+            },
+        ),
+        v(
+            "close_without_code",
+            Client,
+            masked(0x88, b""),
+            Expect::Close {
+                reply: unmasked(0x88, b""),
+            },
+        ),
+        v(
+            "close_application_code",
+            Client,
+            masked(0x88, &close_payload(4000, b"")),
+            Expect::Close {
+                reply: unmasked(0x88, &close_payload(4000, b"")),
+            },
+        ),
+        v(
+            "close_reserved_code",
+            Client,
+            masked(0x88, &close_payload(1005, b"")),
+            fail(close::PROTOCOL_ERROR),
+        ),
+        v(
+            "close_one_byte",
+            Client,
+            masked(0x88, &[0x03]),
+            fail(close::PROTOCOL_ERROR),
+        ),
+        v(
+            "close_invalid_utf8_reason",
+            Client,
+            masked(0x88, &close_payload(close::NORMAL, &[0xff])),
+            fail(close::INVALID_PAYLOAD),
+        ),
+        // Protocol violations
+        v(
+            "unmasked_from_client",
+            Client,
+            unmasked(0x81, b"Hello"),
+            fail(close::PROTOCOL_ERROR),
+        ),
+        v(
+            "masked_from_server",
+            Server,
+            masked(0x81, b"Hello"),
+            fail(close::PROTOCOL_ERROR),
+        ),
+        v(
+            "fragmented_ping",
+            Client,
+            masked(0x09, b"p"),
+            fail(close::PROTOCOL_ERROR),
+        ),
+        v(
+            "oversized_ping",
+            Client,
+            masked(0x89, &[0; 126]),
+            fail(close::PROTOCOL_ERROR),
+        ),
+        v(
+            "stray_continuation",
+            Client,
+            masked(0x80, b"lo"),
+            fail(close::PROTOCOL_ERROR),
+        ),
+        v(
+            "interleaved_messages",
+            Client,
+            [masked(0x01, b"Hel"), masked(0x81, b"lo")].concat(),
+            fail(close::PROTOCOL_ERROR),
+        ),
+        v(
+            "reserved_opcode",
+            Client,
+            masked(0x83, b""),
+            fail(close::PROTOCOL_ERROR),
+        ),
+        v(
+            "rsv1_without_extension",
+            Client,
+            masked(0xc1, b"Hello"),
+            fail(close::PROTOCOL_ERROR),
+        ),
+        v(
+            "invalid_utf8_text",
+            Client,
+            masked(0x81, &[0xce, 0xba, 0xff]),
+            fail(close::INVALID_PAYLOAD),
+        ),
+    ]
+}
+
+fn upgrade_request(method: &str, version: &str) -> String {
+    format!(
+        "{method} /chat HTTP/1.1\r\n\
+         Host: server.example.com\r\n\
+         Upgrade: websocket\r\n\
+         Connection: Upgrade\r\n\
+         Sec-WebSocket-Key: {KEY}\r\n\
+         Sec-WebSocket-Version: {version}\r\n\
+         \r\n"
+    )
+}
+
+pub fn handshake_vectors() -> Vec<HandshakeVector> {
+    vec![
+        // RFC 6455 section 1.3
+        HandshakeVector {
+            name: "rfc_upgrade",
+            request: upgrade_request("GET", "13"),
+            status: 101,
+            accept: Some(ACCEPT.to_string()),
+        },
+        HandshakeVector {
+            name: "old_version",
+            request: upgrade_request("GET", "8"),
+            status: 426,
+            accept: None,
+        },
+        HandshakeVector {
+            name: "missing_version",
+            request: upgrade_request("GET", "13").replace("Sec-WebSocket-Version: 13\r\n", ""),
+            status: 426,
+            accept: None,
+        },
+        HandshakeVector {
+            name: "not_get",
+            request: upgrade_request("POST", "13"),
+            status: 405,
+            accept: None,
+        },
+        HandshakeVector {
+            name: "http_1_0",
+            request: upgrade_request("GET", "13").replace("HTTP/1.1", "HTTP/1.0"),
+            status: 400,
+            accept: None,
+        },
+        HandshakeVector {
+            name: "no_connection_upgrade",
+            request: upgrade_request("GET", "13")
+                .replace("Connection: Upgrade", "Connection: close"),
+            status: 400,
+            accept: None,
+        },
+        HandshakeVector {
+            name: "missing_key",
+            request: upgrade_request("GET", "13")
+                .replace(&format!("Sec-WebSocket-Key: {KEY}\r\n"), ""),
+            status: 400,
+            accept: None,
+        },
+        // Base64, but of 5 bytes rather than 16
+        HandshakeVector {
+            name: "short_key",
+            request: upgrade_request("GET", "13").replace(KEY, "c2hvcnQ="),
+            status: 400,
+            accept: None,
+        },
+    ]
+}
+
+/// Every vector as JSON, byte strings in hex
+pub fn fixtures() -> serde_json::Value {
+    serde_json::json!({
+        "frames": frame_vectors(),
+        "handshakes": handshake_vectors(),
+    })
+}
+
+/// Check this crate against every vector, returning the name and outcome
+/// of each
+pub async fn run_all() -> Vec<(&'static str, Result<(), String>)> {
+    let mut outcomes = Vec::new();
+    for vector in handshake_vectors() {
+        outcomes.push((vector.name, run_handshake(&vector).await));
+    }
+    for vector in frame_vectors() {
+        outcomes.push((vector.name, run_frame(&vector).await));
+    }
+    outcomes
+}
+
+pub async fn run_handshake(vector: &HandshakeVector) -> Result<(), String> {
+    let (mut peer, stream) = tokio::io::duplex(64 * 1024);
+    let server = tokio::spawn(WebSocket::handshake(stream));
+
+    peer.write_all(vector.request.as_bytes())
+        .await
+        .map_err(|e| e.to_string())?;
+    let head = read_head(&mut peer).await?;
+    server.abort();
+
+    let status = head
+        .split_whitespace()
+        .nth(1)
+        .and_then(|s| s.parse::<u16>().ok());
+    if status != Some(vector.status) {
+        return Err(format!("expected status {}, got {head:?}", vector.status));
+    }
+
+    let accept = head.lines().find_map(|line| {
+        let (name, value) = line.split_once(':')?;
+        name.eq_ignore_ascii_case("sec-websocket-accept")
+            .then(|| value.trim().to_string())
+    });
+    if accept != vector.accept {
+        return Err(format!(
+            "expected accept {:?}, got {accept:?}",
+            vector.accept
+        ));
+    }
+    Ok(())
+}
+
+/// Feed a vector's bytes to this crate as the receiving side, then compare
+/// the first frame read and the bytes sent back
+pub async fn run_frame(vector: &FrameVector) -> Result<(), String> {
+    let (mut peer, stream) = tokio::io::duplex(1 << 20);
+
+    let receiver = match vector.from {
+        Peer::Client => tokio::spawn(async move {
+            let ws = WebSocket::handshake(stream).await?;
+            ws.read().await
+        }),
+        Peer::Server => tokio::spawn(async move {
+            let options = ConnectOptions::default();
+            let ws = WebSocket::client_handshake(stream, "localhost", "/", &options).await?;
+            ws.read().await
+        }),
+    };
+
+    match vector.from {
+        Peer::Client => {
+            peer.write_all(upgrade_request("GET", "13").as_bytes())
+                .await
+                .map_err(|e| e.to_string())?;
+            read_head(&mut peer).await?;
+        }
+        Peer::Server => {
+            let request = read_head(&mut peer).await?;
+            let key = request
+                .lines()
+                .find_map(|line| {
+                    let (name, value) = line.split_once(':')?;
+                    name.eq_ignore_ascii_case("sec-websocket-key")
+                        .then(|| value.trim())
+                })
+                .ok_or("no Sec-WebSocket-Key in the client's request")?;
+            let response = format!(
+                "HTTP/1.1 101 Switching Protocols\r\n\
+                 Upgrade: websocket\r\n\
+                 Connection: Upgrade\r\n\
+                 Sec-WebSocket-Accept: {}\r\n\r\n",
+                accept_key(key)
+            );
+            peer.write_all(response.as_bytes())
+                .await
+                .map_err(|e| e.to_string())?;
+        }
+    }
+    peer.write_all(&vector.bytes)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    let frame = timeout(WAIT, receiver)
+        .await
+        .map_err(|_| "no frame read".to_string())?
+        .map_err(|e| e.to_string())?;
+
+    // Whatever the receiver wrote back before dropping the connection
+    let mut replies = Vec::new();
+    let _ = timeout(WAIT, peer.read_to_end(&mut replies)).await;
+    let reply = first_frame(&replies);
+
+    match (&vector.expect, frame) {
+        (Expect::Text { text }, Ok(Frame::Text(got))) if got == *text => Ok(()),
+        (Expect::Binary { payload }, Ok(Frame::Binary(got))) if got == *payload => Ok(()),
+        (Expect::Pong { payload }, Ok(Frame::Pong(got))) if got == *payload => Ok(()),
+        (Expect::Ping { payload }, Ok(Frame::Ping(got))) if got == *payload => match reply {
+            Some((0xA, pong)) if pong == *payload => Ok(()),
+            other => Err(format!("expected a pong echoing the ping, got {other:?}")),
+        },
+        (Expect::Close { reply: expected }, Ok(Frame::Close)) if replies == *expected => Ok(()),
+        (Expect::Fail { code }, Err(_)) => match reply {
+            Some((0x8, payload)) if payload.get(..2) == Some(&code.to_be_bytes()[..]) => Ok(()),
+            other => Err(format!("expected a close with {code}, got {other:?}")),
+        },
+        (expect, frame) => Err(format!(
+            "expected {expect:?}, read {}, sent back {replies:02x?}",
+            describe(&frame)
+        )),
+    }
+}
+
+fn describe(frame: &crate::ws::Result<Frame>) -> String {
+    match frame {
+        Ok(Frame::Text(text)) => format!("text {text:?}"),
+        Ok(Frame::Binary(payload)) => format!("binary of {} bytes", payload.len()),
+        Ok(Frame::Ping(payload)) => format!("ping {payload:02x?}"),
+        Ok(Frame::Pong(payload)) => format!("pong {payload:02x?}"),
+        Ok(Frame::Close) => "close".to_string(),
+        Err(e) => format!("error {e:?}"),
+    }
+}
+
+/// Opcode and unmasked payload of the first frame in `bytes`, if whole
+fn first_frame(bytes: &[u8]) -> Option<(u8, Vec<u8>)> {
+    let [b0, b1, rest @ ..] = bytes else {
+        return None;
+    };
+    let len = (b1 & 0x7F) as usize;
+    if len > 125 {
+        return None;
+    }
+
+    let (mask, payload) = match b1 & 0x80 != 0 {
+        true => (rest.get(..4)?, rest.get(4..4 + len)?),
+        false => (&[0u8; 4][..], rest.get(..len)?),
+    };
+    let payload = payload
+        .iter()
+        .zip(mask.iter().cycle())
+        .map(|(b, m)| b ^ m)
+        .collect();
+    Some((b0 & 0x0F, payload))
+}
+
+async fn read_head(stream: &mut DuplexStream) -> Result<String, String> {
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        let byte = timeout(WAIT, stream.read_u8())
+            .await
+            .map_err(|_| "no handshake response".to_string())?
+            .map_err(|e| e.to_string())?;
+        head.push(byte);
+    }
+    Ok(String::from_utf8_lossy(&head).into_owned())
+}
+
+fn hex<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+    let hex: String = bytes.iter().map(|b| format!("{b:02x}")).collect();
+    serializer.serialize_str(&hex)
+}
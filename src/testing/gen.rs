@@ -0,0 +1,285 @@
+//! Generators of WebSocket frame byte sequences, both valid and near-valid,
+//! for fuzzing handlers and the frame decoder. Every generator takes the
+//! random source as an argument, so a seeded rng such as
+//! `rand::rngs::StdRng::seed_from_u64` replays a failing case.
+
+use rand::{Rng, RngExt};
+
+use crate::ws::encode_header;
+
+/// Largest payload a control frame may carry (RFC 6455 section 5.5)
+const MAX_CONTROL_PAYLOAD: usize = 125;
+
+/// Payload lengths on either side of where the length encoding changes
+const EDGE_LENGTHS: [usize; 7] = [0, 1, 125, 126, 127, 65535, 65536];
+
+/// A single frame, before encoding
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub fin: bool,
+    /// RSV1, RSV2 and RSV3 in the low three bits
+    pub rsv: u8,
+    pub opcode: u8,
+    /// Mask key; frames from a client must have one, frames from a server
+    /// must not
+    pub mask: Option<[u8; 4]>,
+    /// Payload before masking
+    pub payload: Vec<u8>,
+}
+
+impl Frame {
+    /// A final, unmasked frame without reserved bits
+    pub fn new(opcode: u8, payload: impl Into<Vec<u8>>) -> Self {
+        Self {
+            fin: true,
+            rsv: 0,
+            opcode,
+            mask: None,
+            payload: payload.into(),
+        }
+    }
+
+    pub fn fin(mut self, fin: bool) -> Self {
+        self.fin = fin;
+        self
+    }
+
+    pub fn masked(mut self, mask: [u8; 4]) -> Self {
+        self.mask = Some(mask);
+        self
+    }
+
+    /// Wire bytes, with the shortest length encoding
+    pub fn encode(&self) -> Vec<u8> {
+        let (mut header, header_len) =
+            encode_header(self.fin, self.opcode, self.payload.len(), self.mask);
+        header[0] |= (self.rsv & 0x7) << 4;
+
+        let mut out = Vec::with_capacity(header_len + self.payload.len());
+        out.extend_from_slice(&header[..header_len]);
+        match self.mask {
+            Some(mask) => out.extend(
+                self.payload
+                    .iter()
+                    .zip(mask.iter().cycle())
+                    .map(|(b, m)| b ^ m),
+            ),
+            None => out.extend_from_slice(&self.payload),
+        }
+        out
+    }
+}
+
+/// Concatenated wire bytes of `frames`
+pub fn encode(frames: &[Frame]) -> Vec<u8> {
+    frames.iter().flat_map(Frame::encode).collect()
+}
+
+pub fn mask<R: Rng + ?Sized>(rng: &mut R) -> [u8; 4] {
+    rng.random()
+}
+
+/// A payload length, half the time one at the edge of a length encoding
+/// and otherwise up to `max`
+pub fn payload_len<R: Rng + ?Sized>(rng: &mut R, max: usize) -> usize {
+    let edges: Vec<usize> = EDGE_LENGTHS.into_iter().filter(|&n| n <= max).collect();
+    match rng.random_bool(0.5) {
+        true if !edges.is_empty() => edges[rng.random_range(..edges.len())],
+        _ => rng.random_range(..=max),
+    }
+}
+
+/// Random bytes, or random UTF-8 text if `text`
+pub fn payload<R: Rng + ?Sized>(rng: &mut R, len: usize, text: bool) -> Vec<u8> {
+    if !text {
+        let mut bytes = vec![0; len];
+        rng.fill(&mut bytes[..]);
+        return bytes;
+    }
+
+    // Mixes one to four byte characters, so splits land inside them
+    let mut out = String::with_capacity(len);
+    while out.len() < len {
+        let c = match rng.random_range(0..4) {
+            0 => rng.random_range('\u{20}'..='\u{7e}'),
+            1 => rng.random_range('\u{80}'..='\u{7ff}'),
+            2 => rng.random_range('\u{800}'..='\u{d7ff}'),
+            _ => rng.random_range('\u{10000}'..='\u{10ffff}'),
+        };
+        if out.len() + c.len_utf8() > len {
+            out.push('a');
+            continue;
+        }
+        out.push(c);
+    }
+    out.into_bytes()
+}
+
+/// Split a message into a random number of frames at random points, with
+/// pings between some of them as the protocol allows. Frames are masked
+/// with random keys if `masked`.
+pub fn fragment<R: Rng + ?Sized>(
+    rng: &mut R,
+    opcode: u8,
+    payload: &[u8],
+    masked: bool,
+) -> Vec<Frame> {
+    let pieces = rng.random_range(1..=payload.len().clamp(1, 8));
+    let mut cuts: Vec<usize> = (1..pieces)
+        .map(|_| rng.random_range(0..=payload.len()))
+        .collect();
+    cuts.sort_unstable();
+    cuts.insert(0, 0);
+    cuts.push(payload.len());
+
+    let mut frames = Vec::new();
+    for (i, bounds) in cuts.windows(2).enumerate() {
+        if i > 0 && rng.random_bool(0.2) {
+            let len = rng.random_range(..=MAX_CONTROL_PAYLOAD.min(16));
+            let ping = self::payload(rng, len, false);
+            frames.push(with_mask(rng, Frame::new(0x9, ping), masked));
+        }
+
+        let opcode = if i == 0 { opcode } else { 0x0 };
+        let frame = Frame::new(opcode, &payload[bounds[0]..bounds[1]]).fin(i == pieces - 1);
+        frames.push(with_mask(rng, frame, masked));
+    }
+    frames
+}
+
+/// A random text or binary message of up to `max_len` bytes, fragmented
+/// as by [`fragment`]
+pub fn message<R: Rng + ?Sized>(rng: &mut R, max_len: usize, masked: bool) -> Vec<Frame> {
+    let text = rng.random_bool(0.5);
+    let len = payload_len(rng, max_len);
+    let payload = payload(rng, len, text);
+    fragment(rng, if text { 0x1 } else { 0x2 }, &payload, masked)
+}
+
+/// One way a frame sequence breaks RFC 6455, each of which a conforming
+/// peer fails with a close
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Defect {
+    /// An RSV bit set without an extension that defines it
+    ReservedBits,
+    /// An opcode of 0x3-0x7 or 0xB-0xF
+    ReservedOpcode,
+    /// A ping without FIN
+    FragmentedControl,
+    /// A ping with more than 125 bytes of payload
+    OversizedControl,
+    /// A continuation frame with no message to continue
+    StrayContinuation,
+    /// A new message before the fragmented one finished
+    InterleavedMessage,
+    /// A text message that isn't UTF-8
+    InvalidUtf8,
+    /// A frame masked when it shouldn't be, or the other way around
+    WrongMasking,
+    /// A close frame with a one byte payload
+    TruncatedClose,
+}
+
+impl Defect {
+    pub const ALL: [Defect; 9] = [
+        Defect::ReservedBits,
+        Defect::ReservedOpcode,
+        Defect::FragmentedControl,
+        Defect::OversizedControl,
+        Defect::StrayContinuation,
+        Defect::InterleavedMessage,
+        Defect::InvalidUtf8,
+        Defect::WrongMasking,
+        Defect::TruncatedClose,
+    ];
+}
+
+/// A valid message changed to have `defect`, with frames masked if `masked`
+/// apart from any the defect is about
+pub fn near_valid<R: Rng + ?Sized>(
+    rng: &mut R,
+    defect: Defect,
+    max_len: usize,
+    masked: bool,
+) -> Vec<Frame> {
+    let mut frames = message(rng, max_len, masked);
+    let at = rng.random_range(..frames.len());
+
+    match defect {
+        Defect::ReservedBits => frames[at].rsv = rng.random_range(1..8),
+        Defect::ReservedOpcode => {
+            frames[at].opcode = match rng.random_bool(0.5) {
+                true => rng.random_range(0x3..=0x7),
+                false => rng.random_range(0xB..=0xF),
+            }
+        }
+        Defect::FragmentedControl => {
+            let ping = Frame::new(0x9, payload(rng, 4, false)).fin(false);
+            frames.insert(at, with_mask(rng, ping, masked));
+        }
+        Defect::OversizedControl => {
+            let len = rng.random_range(MAX_CONTROL_PAYLOAD + 1..=MAX_CONTROL_PAYLOAD * 4);
+            let ping = Frame::new(0x9, payload(rng, len, false));
+            frames.insert(at, with_mask(rng, ping, masked));
+        }
+        Defect::StrayContinuation => {
+            let len = payload_len(rng, max_len);
+            let stray = Frame::new(0x0, payload(rng, len, false));
+            frames.insert(0, with_mask(rng, stray, masked));
+        }
+        Defect::InterleavedMessage => {
+            let first = frames.remove(0).fin(false);
+            let second = message(rng, max_len, masked);
+            frames = std::iter::once(first).chain(second).collect();
+        }
+        Defect::InvalidUtf8 => {
+            // 0xFF never appears in UTF-8
+            let len = payload_len(rng, max_len);
+            let mut bytes = payload(rng, len, true);
+            let at = rng.random_range(..=bytes.len());
+            bytes.insert(at, 0xFF);
+            frames = fragment(rng, 0x1, &bytes, masked);
+        }
+        Defect::WrongMasking => {
+            let frame = &mut frames[at];
+            frame.mask = match frame.mask {
+                Some(_) => None,
+                None => Some(mask(rng)),
+            };
+        }
+        Defect::TruncatedClose => {
+            let close = Frame::new(0x8, [0x03]);
+            frames.push(with_mask(rng, close, masked));
+        }
+    }
+    frames
+}
+
+/// `frames` encoded and cut short at a random point, for exercising reads
+/// that end mid-frame
+pub fn truncated<R: Rng + ?Sized>(rng: &mut R, frames: &[Frame]) -> Vec<u8> {
+    let mut bytes = encode(frames);
+    let len = rng.random_range(..bytes.len().max(1));
+    bytes.truncate(len);
+    bytes
+}
+
+/// Split `bytes` into random chunks, as a stream might deliver them
+pub fn chunks<R: Rng + ?Sized>(rng: &mut R, bytes: &[u8]) -> Vec<Vec<u8>> {
+    let mut out = Vec::new();
+    let mut rest = bytes;
+    while !rest.is_empty() {
+        let n = rng.random_range(1..=rest.len().min(64));
+        let (chunk, tail) = rest.split_at(n);
+        out.push(chunk.to_vec());
+        rest = tail;
+    }
+    out
+}
+
+fn with_mask<R: Rng + ?Sized>(rng: &mut R, frame: Frame, masked: bool) -> Frame {
+    match masked {
+        true => frame.masked(mask(rng)),
+        false => frame,
+    }
+}
@@ -0,0 +1,6 @@
+//! Helpers for testing code built on this crate. Enabled by the `testing`
+//! feature.
+
+pub mod conformance;
+pub mod r#gen;
+pub mod router;
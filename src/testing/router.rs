@@ -0,0 +1,122 @@
+//! Unit testing of request handlers without a server, network or timers.
+//!
+//! ```ignore
+//! let mut tester = RouterTester::new();
+//! let session = tester.session();
+//! tester.set_router(Router::new().route::<Join, _>(move |_, room| {
+//!     let session = session.clone();
+//!     async move {
+//!         session.notify::<Joined>(room.clone()).await.map_err(|_| ())?;
+//!         Ok(room)
+//!     }
+//! }));
+//!
+//! assert_eq!(tester.call::<Join>("lobby".into()), Ok("lobby".into()));
+//! assert_eq!(tester.sent().len(), 1);
+//! ```
+
+use std::time::Duration;
+
+use tokio::io::DuplexStream;
+use tokio::runtime::Runtime;
+
+use crate::router::Router;
+use crate::session::{Message, Session};
+use crate::ws::{Frame, Upgrade, WebSocket};
+use crate::{GenericMethod, Method};
+
+/// Room for messages handlers send before [`RouterTester::sent`] drains
+/// them; a handler sending more blocks
+const CAPTURE_CAPACITY: usize = 16 * 1024 * 1024;
+
+/// Calls the handlers of a [`Router`] directly and captures what they send
+/// through [`Self::session`]. Every method blocks on a runtime of its own,
+/// so tests are plain `#[test]` functions and must not call it from within
+/// another runtime.
+pub struct RouterTester {
+    runtime: Runtime,
+    router: Router,
+    session: Session,
+    /// Receiving end of everything `session` sends
+    peer: WebSocket,
+    next_id: u64,
+}
+
+impl Default for RouterTester {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RouterTester {
+    pub fn new() -> Self {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()
+            .expect("Failed to build the tester's runtime");
+        let (ours, theirs): (DuplexStream, DuplexStream) = tokio::io::duplex(CAPTURE_CAPACITY);
+
+        Self {
+            runtime,
+            router: Router::new(),
+            session: Session::from_ws(WebSocket::from_stream(ours, false, Upgrade::default())),
+            peer: WebSocket::from_stream(theirs, true, Upgrade::default()),
+            next_id: 0,
+        }
+    }
+
+    /// Session whose sends are captured, for handlers to hold on to
+    pub fn session(&self) -> Session {
+        self.session.clone()
+    }
+
+    /// Handlers to call, replacing those set before
+    pub fn set_router(&mut self, router: Router) {
+        self.router = router;
+    }
+
+    /// Run the handler of `M` on `req` to completion, as if the peer had
+    /// sent it. Panics if there is no handler for `M` or the request or
+    /// response doesn't convert to and from JSON.
+    pub fn call<M: Method>(&mut self, req: M::Request) -> Result<M::Response, M::Error> {
+        let handler = self
+            .router
+            .get(M::NAME)
+            .unwrap_or_else(|| panic!("No handler for {}", M::NAME));
+
+        self.next_id += 1;
+        let data = serde_json::to_value(req).expect("Request doesn't serialize");
+        let (is_error, value) = self
+            .runtime
+            .block_on(handler(self.next_id, data))
+            .unwrap_or_else(|| panic!("Handler for {} gave no response", M::NAME));
+
+        match is_error {
+            false => Ok(serde_json::from_value(value).expect("Response doesn't deserialize")),
+            true => Err(serde_json::from_value(value).expect("Error doesn't deserialize")),
+        }
+    }
+
+    /// Messages sent through [`Self::session`] since the last call, oldest
+    /// first
+    pub fn sent(&mut self) -> Vec<Message<GenericMethod>> {
+        let mut sent = Vec::new();
+
+        // Sends are written out before the handler returns, so whatever is
+        // there is ready on the first poll; a zero timeout stops at the end
+        let peer = &self.peer;
+        while let Ok(Ok(frame)) = self
+            .runtime
+            .block_on(async { tokio::time::timeout(Duration::ZERO, peer.read()).await })
+        {
+            let msg = match frame {
+                Frame::Text(text) => serde_json::from_str(&text).ok(),
+                Frame::Binary(bytes) => serde_json::from_slice(&bytes).ok(),
+                _ => None,
+            };
+            sent.extend(msg);
+        }
+
+        sent
+    }
+}
@@ -0,0 +1,105 @@
+use std::sync::{
+    Arc,
+    atomic::{AtomicUsize, Ordering},
+};
+
+use tokio::sync::Notify;
+
+use super::{Error, Result};
+
+/// What a connection does when buffering a frame would exceed the budget
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pressure {
+    /// Stop reading until other connections release enough bytes
+    PauseReads,
+    /// Close the connection whose read would exceed the budget
+    Close,
+}
+
+/// Byte budget shared by every connection it is configured on, covering
+/// inbound payload bytes from the moment they are read until the message
+/// they belong to is handed to the caller.
+#[derive(Debug)]
+pub struct MemoryBudget {
+    limit: usize,
+    pressure: Pressure,
+    used: AtomicUsize,
+    released: Notify,
+}
+
+impl MemoryBudget {
+    pub fn new(limit: usize, pressure: Pressure) -> Self {
+        Self {
+            limit,
+            pressure,
+            used: AtomicUsize::new(0),
+            released: Notify::new(),
+        }
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    /// Bytes currently buffered across all connections
+    pub fn used(&self) -> usize {
+        self.used.load(Ordering::Acquire)
+    }
+
+    /// Charge `bytes` more for a connection already holding `held`. Bytes it
+    /// holds itself never make it wait, as only it could release them.
+    pub(crate) async fn reserve(
+        self: &Arc<Self>,
+        bytes: usize,
+        held: &[Reservation],
+    ) -> Result<Reservation> {
+        let own: usize = held
+            .iter()
+            .filter(|r| Arc::ptr_eq(&r.budget, self))
+            .map(|r| r.bytes)
+            .sum();
+
+        loop {
+            let released = self.released.notified();
+            tokio::pin!(released);
+            released.as_mut().enable();
+
+            let used = self.used();
+
+            // A single message larger than the whole budget may still proceed
+            // once nothing else is buffered, otherwise it could never be read.
+            let fits = used <= own || used.checked_add(bytes).is_some_and(|n| n <= self.limit);
+            if fits && let Some(total) = used.checked_add(bytes) {
+                if self
+                    .used
+                    .compare_exchange(used, total, Ordering::AcqRel, Ordering::Acquire)
+                    .is_ok()
+                {
+                    return Ok(Reservation {
+                        budget: self.clone(),
+                        bytes,
+                    });
+                }
+                continue;
+            }
+
+            match self.pressure {
+                Pressure::PauseReads => released.await,
+                Pressure::Close => return Err(Error::BudgetExceeded),
+            }
+        }
+    }
+}
+
+/// Bytes held against a [`MemoryBudget`], released on drop
+pub(crate) struct Reservation {
+    budget: Arc<MemoryBudget>,
+    bytes: usize,
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        self.budget.used.fetch_sub(self.bytes, Ordering::AcqRel);
+        self.budget.released.notify_waiters();
+    }
+}
@@ -0,0 +1,61 @@
+//! Where a connection gets the buffers it reads frame payloads into
+
+use std::sync::Mutex;
+
+/// Source of a connection's payload buffers, see
+/// [`super::WebSocket::with_buffers`]. Stable Rust can't give a `Vec` its
+/// own allocator, so a source hands out buffers it obtained however it
+/// likes, e.g. from a NUMA-local pool or under its own accounting, and gets
+/// back the ones the connection is done with. Buffers that leave as part of
+/// a `Frame` belong to the caller and aren't given back.
+pub trait BufferSource: Send + Sync {
+    /// An empty buffer with room for at least `capacity` bytes. Payloads
+    /// longer than that grow it as they arrive.
+    fn take(&self, capacity: usize) -> Vec<u8>;
+
+    /// A buffer the connection no longer needs
+    fn give_back(&self, buf: Vec<u8>) {
+        drop(buf);
+    }
+}
+
+/// Keeps buffers given back for reuse, up to `max_buffers` of them
+#[derive(Debug)]
+pub struct BufferPool {
+    max_buffers: usize,
+    free: Mutex<Vec<Vec<u8>>>,
+}
+
+impl BufferPool {
+    pub fn new(max_buffers: usize) -> Self {
+        Self {
+            max_buffers,
+            free: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Buffers waiting to be reused
+    pub fn idle(&self) -> usize {
+        self.lock().len()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Vec<Vec<u8>>> {
+        self.free.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl BufferSource for BufferPool {
+    fn take(&self, capacity: usize) -> Vec<u8> {
+        let mut buf = self.lock().pop().unwrap_or_default();
+        buf.reserve(capacity);
+        buf
+    }
+
+    fn give_back(&self, mut buf: Vec<u8>) {
+        buf.clear();
+        let mut free = self.lock();
+        if free.len() < self.max_buffers {
+            free.push(buf);
+        }
+    }
+}
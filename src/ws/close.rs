@@ -0,0 +1,18 @@
+//! Close status codes from RFC 6455 section 7.4.1
+
+pub const NORMAL: u16 = 1000;
+pub const GOING_AWAY: u16 = 1001;
+pub const PROTOCOL_ERROR: u16 = 1002;
+pub const INVALID_PAYLOAD: u16 = 1007;
+pub const POLICY_VIOLATION: u16 = 1008;
+pub const MESSAGE_TOO_BIG: u16 = 1009;
+/// The server is restarting or rebalancing; the client should reconnect
+pub const SERVICE_RESTART: u16 = 1012;
+pub const TRY_AGAIN_LATER: u16 = 1013;
+
+/// Whether a peer may send `code` in a close frame. 1005, 1006 and 1015 are
+/// reserved for reporting and never go on the wire; 3000-4999 belong to
+/// libraries and applications.
+pub fn is_valid(code: u16) -> bool {
+    matches!(code, 1000..=1003 | 1007..=1014 | 3000..=4999)
+}
@@ -0,0 +1,82 @@
+//! Per-message compression extensions. Each is negotiated by its token in
+//! `Sec-WebSocket-Extensions` and marks the messages it compressed with
+//! RSV1, so at most one is in use on a connection. permessage-deflate is
+//! the one browsers speak; others, such as `Zstd`, only work between
+//! peers that both know them.
+
+use super::Result;
+
+pub trait Compression: Send + Sync + std::fmt::Debug {
+    /// Extension token, e.g. `permessage-deflate`
+    fn token(&self) -> &str;
+
+    /// Extension with its parameters, as offered by the client and
+    /// answered by the server
+    fn params(&self) -> &str;
+
+    /// Whether one offered extension, token and parameters, can be honoured
+    fn accepts(&self, offer: &str) -> bool {
+        offer.split(';').next().map(str::trim) == Some(self.token())
+    }
+
+    fn compress(&self, payload: &[u8]) -> Result<Vec<u8>>;
+
+    /// Fails with `Error::MessageTooBig` once the output exceeds `limit`
+    /// bytes
+    fn decompress(&self, payload: &[u8], limit: Option<usize>) -> Result<Vec<u8>>;
+}
+
+/// Zstandard compression of each message on its own, for peers that both
+/// run this crate. Cheaper on CPU than deflate at a similar ratio.
+#[cfg(feature = "zstd")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Zstd {
+    /// 1 (fastest) to 22 (smallest)
+    pub level: i32,
+}
+
+#[cfg(feature = "zstd")]
+impl Default for Zstd {
+    fn default() -> Self {
+        Self { level: 3 }
+    }
+}
+
+#[cfg(feature = "zstd")]
+impl Compression for Zstd {
+    fn token(&self) -> &str {
+        "x-permessage-zstd"
+    }
+
+    fn params(&self) -> &str {
+        "x-permessage-zstd"
+    }
+
+    fn compress(&self, payload: &[u8]) -> Result<Vec<u8>> {
+        Ok(zstd::bulk::compress(payload, self.level)?)
+    }
+
+    fn decompress(&self, payload: &[u8], limit: Option<usize>) -> Result<Vec<u8>> {
+        use std::io::Read;
+
+        let decoder = zstd::stream::read::Decoder::with_buffer(payload)?;
+        let mut out = Vec::new();
+        match limit {
+            Some(limit) => {
+                // One byte past the limit tells an oversized message apart
+                decoder.take(limit as u64 + 1).read_to_end(&mut out)?;
+                if out.len() > limit {
+                    return Err(super::Error::MessageTooBig {
+                        size: out.len() as u64,
+                        limit,
+                    });
+                }
+            }
+            None => {
+                let mut decoder = decoder;
+                decoder.read_to_end(&mut out)?;
+            }
+        }
+        Ok(out)
+    }
+}
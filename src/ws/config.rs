@@ -0,0 +1,50 @@
+use std::sync::Arc;
+
+use tokio::time::Duration;
+
+use super::budget::MemoryBudget;
+
+#[derive(Debug, Clone, Default)]
+pub struct Config {
+    /// Data messages with a larger payload are split into continuation frames
+    /// of at most this many bytes. `None` sends every message as one frame.
+    pub fragment_size: Option<usize>,
+    /// Largest inbound frame. A peer exceeding it is closed with 1009
+    /// before the frame is buffered.
+    pub max_frame_size: Option<usize>,
+    /// Largest inbound message, summed over its fragments. A peer exceeding
+    /// it is closed with 1009 before the oversized frame is buffered.
+    pub max_message_size: Option<usize>,
+    /// Largest message once the compression extension inflates it; `None`
+    /// falls back to `max_message_size`. Inflating stops, and the peer is
+    /// closed with 1009, as soon as the output passes it.
+    pub max_decompressed_size: Option<usize>,
+    /// Largest ratio of a message's inflated size to its compressed size,
+    /// e.g. 100, so a tiny frame can't inflate into gigabytes. Messages
+    /// inflating to 64 KiB or less are exempt, since short repetitive text
+    /// legitimately compresses that well.
+    pub max_compression_ratio: Option<usize>,
+    /// Budget charged for inbound messages while they are being read. Share
+    /// one budget across connections to bound their combined buffering.
+    pub memory_budget: Option<Arc<MemoryBudget>>,
+    /// Deadline for each send, including waiting for other senders. A send
+    /// that times out mid-write leaves the connection unusable.
+    pub write_timeout: Option<Duration>,
+    /// With a compression extension negotiated, data messages smaller than
+    /// this many bytes are sent uncompressed, as compressing them rarely
+    /// pays off
+    pub compression_threshold: usize,
+    /// Hand text messages that aren't valid UTF-8 over as `Frame::Binary`
+    /// instead of closing the connection with 1007
+    pub lenient_utf8: bool,
+    /// Close with 1002 on frames with an RSV bit no negotiated extension
+    /// defines, or with a reserved opcode, as soon as their header arrives.
+    /// Otherwise RSV2 and RSV3 are ignored.
+    pub strict: bool,
+    /// How long [`super::WebSocket::close_with`] waits for the peer's close
+    /// frame before shutting down anyway. `None` waits 5 seconds.
+    pub close_timeout: Option<Duration>,
+    /// How long [`super::WebSocket::start_ping_loop`] waits for a pong
+    /// before closing the connection. `None` keeps pinging regardless.
+    pub pong_timeout: Option<Duration>,
+}
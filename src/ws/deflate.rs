@@ -0,0 +1,101 @@
+//! permessage-deflate (RFC 7692) without context takeover: every message is
+//! compressed on its own, so neither side keeps a window between messages
+
+use std::io::Write;
+
+use flate2::write::DeflateEncoder;
+use flate2::{Compression as Level, Decompress, FlushDecompress};
+
+use super::compression::Compression;
+use super::{Error, Result};
+
+/// Extension token in `Sec-WebSocket-Extensions`
+const EXTENSION: &str = "permessage-deflate";
+
+/// What the client offers and the server answers
+const PARAMS: &str = "permessage-deflate; server_no_context_takeover; client_no_context_takeover";
+
+/// Trailer of a sync flush, left off the wire
+const TAIL: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+
+/// Output grows by this much at a time while inflating
+const CHUNK: usize = 32 * 1024;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct PermessageDeflate;
+
+impl Compression for PermessageDeflate {
+    fn token(&self) -> &str {
+        EXTENSION
+    }
+
+    fn params(&self) -> &str {
+        PARAMS
+    }
+
+    /// Offers limiting our window size are declined, since the encoder
+    /// always uses the full window
+    fn accepts(&self, offer: &str) -> bool {
+        let mut params = offer.split(';').map(str::trim);
+        params.next() == Some(EXTENSION)
+            && params.all(|p| !p.starts_with("server_max_window_bits=") || p.ends_with("=15"))
+    }
+
+    fn compress(&self, payload: &[u8]) -> Result<Vec<u8>> {
+        compress(payload)
+    }
+
+    fn decompress(&self, payload: &[u8], limit: Option<usize>) -> Result<Vec<u8>> {
+        decompress(payload, limit)
+    }
+}
+
+fn compress(payload: &[u8]) -> Result<Vec<u8>> {
+    let mut encoder = DeflateEncoder::new(Vec::new(), Level::default());
+    encoder.write_all(payload)?;
+    encoder.flush()?;
+
+    // Taken before the encoder is dropped, which would finish the stream
+    let mut out = std::mem::take(encoder.get_mut());
+    if out.ends_with(&TAIL) {
+        out.truncate(out.len() - TAIL.len());
+    }
+    Ok(out)
+}
+
+/// Inflate a message, failing with `Error::MessageTooBig` once it exceeds
+/// `limit` bytes
+fn decompress(payload: &[u8], limit: Option<usize>) -> Result<Vec<u8>> {
+    let input = [payload, &TAIL].concat();
+    let mut inflater = Decompress::new(false);
+    let mut out = Vec::with_capacity(payload.len().saturating_mul(2).min(CHUNK));
+
+    loop {
+        if out.len() == out.capacity() {
+            out.reserve(CHUNK);
+        }
+
+        let (before_in, before_out) = (inflater.total_in(), inflater.total_out());
+        let consumed = before_in as usize;
+        inflater
+            .decompress_vec(&input[consumed..], &mut out, FlushDecompress::Sync)
+            .map_err(std::io::Error::from)?;
+
+        if let Some(limit) = limit
+            && out.len() > limit
+        {
+            return Err(Error::MessageTooBig {
+                size: out.len() as u64,
+                limit,
+            });
+        }
+
+        // Done once all input is consumed and the output had room to spare
+        if inflater.total_in() as usize == input.len() && out.len() < out.capacity() {
+            return Ok(out);
+        }
+        if inflater.total_in() == before_in && inflater.total_out() == before_out {
+            return Err(Error::InvalidFrame("Truncated deflate data".into()));
+        }
+    }
+}
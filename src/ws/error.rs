@@ -10,6 +10,24 @@ pub enum Error {
     Utf8(FromUtf8Error),
     ConnectionClosed,
     Elapsed,
+    BudgetExceeded,
+    /// The message's deadline passed while it waited to be written, so it
+    /// was dropped unsent
+    Expired,
+    /// No pong arrived within `Config::pong_timeout` of a ping
+    PongTimeout,
+    /// The peer sent a message or frame of at least `size` bytes, over
+    /// `limit`
+    MessageTooBig {
+        size: u64,
+        limit: usize,
+    },
+    /// The server refused the upgrade with 401 or 403. `headers` of the
+    /// response, such as `WWW-Authenticate`, say what it wants instead.
+    Unauthorized {
+        status: u16,
+        headers: Vec<(String, String)>,
+    },
 }
 
 impl From<std::io::Error> for Error {
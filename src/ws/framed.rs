@@ -0,0 +1,124 @@
+//! WebSocket framing as a `tokio_util` codec, for driving the protocol over
+//! any transport with `Framed`. It works frame by frame: assembling
+//! fragments, answering pings and the closing handshake are left to the
+//! caller, which [`WebSocket`](super::WebSocket) does on top of the same
+//! encoding.
+
+use bytes::{Buf, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+
+use super::reader::{FrameHeader, unmask};
+use super::{Error, Result, put_frame};
+
+/// One frame as it goes over the wire, unmasked
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RawFrame {
+    pub fin: bool,
+    /// RSV1, RSV2 and RSV3 in the low three bits
+    pub rsv: u8,
+    pub opcode: u8,
+    pub payload: Vec<u8>,
+}
+
+impl RawFrame {
+    /// A final frame with no RSV bits set
+    pub fn new(opcode: u8, payload: Vec<u8>) -> Self {
+        Self {
+            fin: true,
+            rsv: 0,
+            opcode,
+            payload,
+        }
+    }
+}
+
+/// Encodes and decodes [`RawFrame`]s for one side of a connection. Clients
+/// mask what they send and refuse masked frames; servers do the opposite.
+#[derive(Debug, Clone)]
+pub struct WebSocketCodec {
+    client: bool,
+    max_frame_size: Option<usize>,
+}
+
+impl WebSocketCodec {
+    pub fn client() -> Self {
+        Self {
+            client: true,
+            max_frame_size: None,
+        }
+    }
+
+    pub fn server() -> Self {
+        Self {
+            client: false,
+            max_frame_size: None,
+        }
+    }
+
+    /// Fail on frames with a larger payload, as `Config::max_frame_size`
+    /// does, before buffering them
+    pub fn max_frame_size(mut self, limit: usize) -> Self {
+        self.max_frame_size = Some(limit);
+        self
+    }
+}
+
+impl Decoder for WebSocketCodec {
+    type Item = RawFrame;
+    type Error = Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<RawFrame>> {
+        let Some(header) = FrameHeader::parse(src) else {
+            return Ok(None);
+        };
+
+        if let Some((_, message)) = header.violation(!self.client) {
+            return Err(Error::InvalidFrame(message));
+        }
+        if let Some(limit) = self.max_frame_size
+            && header.payload_len > limit as u64
+        {
+            return Err(Error::MessageTooBig {
+                size: header.payload_len,
+                limit,
+            });
+        }
+
+        let len = header.len + header.payload_len as usize;
+        if src.len() < len {
+            src.reserve(len - src.len());
+            return Ok(None);
+        }
+
+        src.advance(header.len);
+        let mut payload = src.split_to(header.payload_len as usize).to_vec();
+        if let Some(mask) = header.mask {
+            unmask(&mut payload, mask);
+        }
+
+        Ok(Some(RawFrame {
+            fin: header.fin,
+            rsv: header.rsv,
+            opcode: header.opcode,
+            payload,
+        }))
+    }
+}
+
+impl Encoder<RawFrame> for WebSocketCodec {
+    type Error = Error;
+
+    fn encode(&mut self, frame: RawFrame, dst: &mut BytesMut) -> Result<()> {
+        let RawFrame {
+            fin,
+            rsv,
+            opcode,
+            payload,
+        } = frame;
+
+        dst.reserve(super::reader::MAX_HEADER + payload.len());
+        let mask = self.client.then(rand::random);
+        put_frame(dst, fin, ((rsv & 0x7) << 4) | opcode, &payload, mask);
+        Ok(())
+    }
+}
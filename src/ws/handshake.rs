@@ -1,66 +1,556 @@
 use base64::Engine;
 use base64::engine::general_purpose::STANDARD as Base64;
 use sha1::{Digest, Sha1};
-use std::{collections::HashMap, sync::Arc};
+use std::{collections::HashMap, net::SocketAddr, sync::Arc};
 use tokio::{
-    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    io::{
+        AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt,
+        BufReader,
+    },
     net::TcpStream,
     sync::Mutex,
-    time::{Duration, timeout},
+    time::{Duration, Instant, timeout, timeout_at},
 };
 
-use super::WebSocket;
+use super::{
+    Config, Stream, WebSocket, Writer, compression::Compression, reader::Reader, throughput::Meter,
+};
+use crate::{
+    BoxFuture,
+    hello::{HELLO_HEADER, VERSION},
+};
+
+/// Server side handshake settings
+#[derive(Debug, Clone, Default)]
+pub struct HandshakeConfig {
+    /// Subprotocols the server speaks, most preferred first. The first one
+    /// the client also offers in `Sec-WebSocket-Protocol` is selected.
+    pub protocols: Vec<String>,
+    /// Accept permessage-deflate when the client offers it
+    #[cfg(feature = "deflate")]
+    pub permessage_deflate: bool,
+    /// Other compression extensions to accept, most preferred first and
+    /// ahead of permessage-deflate
+    pub compression: Vec<Arc<dyn Compression>>,
+    /// Origins browsers may open connections from; `None` accepts any
+    pub origins: Option<OriginPolicy>,
+    /// Requires a valid session cookie, checked after `origins`
+    pub cookie_auth: Option<CookieAuth>,
+    /// Requires a valid bearer token, checked after `cookie_auth`
+    #[cfg(feature = "jwt")]
+    pub jwt: Option<crate::jwt::JwtAuth>,
+    /// Decides on each upgrade before it is answered
+    pub on_upgrade: Option<UpgradeHook>,
+    /// Bounds on reading the upgrade request
+    pub limits: HandshakeLimits,
+    /// Agree to a hello after the upgrade, see [`crate::hello`]. Only for
+    /// connections a `Session` takes over, which `SessionServer` sets it for.
+    pub hello: bool,
+}
+
+/// How long and how large an upgrade request may be. A client exceeding
+/// them has its socket closed, after a 431 for oversized headers, so one
+/// trickling bytes can't hold a connection open indefinitely.
+#[derive(Debug, Clone, Copy)]
+pub struct HandshakeLimits {
+    /// Time allowed for the whole request to arrive
+    pub timeout: Duration,
+    /// Most header lines accepted
+    pub max_headers: usize,
+    /// Most bytes accepted for the request line and headers together
+    pub max_header_bytes: usize,
+}
+
+impl Default for HandshakeLimits {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(5),
+            max_headers: 100,
+            max_header_bytes: 16 * 1024,
+        }
+    }
+}
+
+type OriginCheck = dyn Fn(&str) -> bool + Send + Sync;
+
+/// Which `Origin`s may upgrade, refusing the rest with 403 to stop
+/// cross-site WebSocket hijacking. Patterns are full origins such as
+/// `https://app.example.com`, where `*.` stands for one or more subdomain
+/// labels (`https://*.example.com`) and `*` alone for any origin.
+#[derive(Clone)]
+pub struct OriginPolicy {
+    patterns: Vec<String>,
+    check: Option<Arc<OriginCheck>>,
+    allow_missing: bool,
+}
+
+impl OriginPolicy {
+    /// Allow origins matching any of `patterns`. Requests without an
+    /// `Origin`, which browsers always send, are allowed unless
+    /// [`Self::allow_missing`] says otherwise.
+    pub fn allow<S: AsRef<str>>(patterns: impl IntoIterator<Item = S>) -> Self {
+        Self {
+            patterns: patterns
+                .into_iter()
+                .map(|p| p.as_ref().to_ascii_lowercase())
+                .collect(),
+            check: None,
+            allow_missing: true,
+        }
+    }
+
+    /// Allow origins `check` returns true for
+    pub fn check(check: impl Fn(&str) -> bool + Send + Sync + 'static) -> Self {
+        Self {
+            patterns: Vec::new(),
+            check: Some(Arc::new(check)),
+            allow_missing: true,
+        }
+    }
+
+    pub fn allow_missing(mut self, allow: bool) -> Self {
+        self.allow_missing = allow;
+        self
+    }
+
+    pub fn allows(&self, origin: Option<&str>) -> bool {
+        let Some(origin) = origin else {
+            return self.allow_missing;
+        };
+        let origin = origin.to_ascii_lowercase();
+
+        self.patterns.iter().any(|p| origin_matches(p, &origin))
+            || self.check.as_ref().is_some_and(|check| check(&origin))
+    }
+}
+
+impl std::fmt::Debug for OriginPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("OriginPolicy")
+            .field("patterns", &self.patterns)
+            .field("check", &self.check.is_some())
+            .field("allow_missing", &self.allow_missing)
+            .finish()
+    }
+}
+
+fn origin_matches(pattern: &str, origin: &str) -> bool {
+    if pattern == "*" {
+        return true;
+    }
+    let Some((scheme, domain)) = pattern.split_once("*.") else {
+        return pattern == origin;
+    };
+
+    origin
+        .strip_prefix(scheme)
+        .and_then(|rest| rest.strip_suffix(domain))
+        .and_then(|labels| labels.strip_suffix('.'))
+        .is_some_and(|labels| !labels.is_empty() && !labels.contains(['/', ':']))
+}
+
+type UpgradeFn = dyn Fn(
+        HandshakeRequest,
+    ) -> BoxFuture<'static, std::result::Result<Vec<(String, String)>, Rejection>>
+    + Send
+    + Sync;
+
+/// Decides on an upgrade from the client's request: `Ok` accepts, adding
+/// the given headers to the 101 response, and `Err` refuses it with the
+/// rejection, e.g. 401, 403 or 429
+#[derive(Clone)]
+pub struct UpgradeHook(Arc<UpgradeFn>);
+
+impl UpgradeHook {
+    pub fn new<Fut>(hook: impl Fn(HandshakeRequest) -> Fut + Send + Sync + 'static) -> Self
+    where
+        Fut:
+            Future<Output = std::result::Result<Vec<(String, String)>, Rejection>> + Send + 'static,
+    {
+        Self(Arc::new(move |request| Box::pin(hook(request))))
+    }
+}
+
+impl std::fmt::Debug for UpgradeHook {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("UpgradeHook")
+    }
+}
+
+type CookieCheck = dyn Fn(String) -> BoxFuture<'static, Option<String>> + Send + Sync;
+
+/// Authenticates upgrades by a session cookie, refusing with 401 those
+/// without one or with one `validate` rejects. Browsers can't set headers on
+/// a WebSocket, but they do send their cookies with it.
+#[derive(Clone)]
+pub struct CookieAuth {
+    /// Name of the session cookie
+    pub name: String,
+    validate: Arc<CookieCheck>,
+}
+
+impl CookieAuth {
+    /// `validate` gets the cookie's value and returns the principal it
+    /// belongs to, or `None` to refuse the upgrade
+    pub fn new<Fut>(name: &str, validate: impl Fn(String) -> Fut + Send + Sync + 'static) -> Self
+    where
+        Fut: Future<Output = Option<String>> + Send + 'static,
+    {
+        Self {
+            name: name.to_string(),
+            validate: Arc::new(move |value| Box::pin(validate(value))),
+        }
+    }
+}
+
+impl std::fmt::Debug for CookieAuth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CookieAuth")
+            .field("name", &self.name)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Header carrying a session ticket on the upgrade request
+pub const SESSION_TICKET_HEADER: &str = "Session-Ticket";
+
+/// Client side handshake settings
+#[derive(Debug, Clone, Default)]
+pub struct ConnectOptions {
+    /// Subprotocols offered in `Sec-WebSocket-Protocol`, most preferred first
+    pub protocols: Vec<String>,
+    /// Ticket of an earlier session to resume, sent in `Session-Ticket`
+    pub ticket: Option<String>,
+    /// Extra headers of the upgrade request, e.g. `Authorization` or
+    /// `User-Agent`. Headers the handshake sets itself are refused.
+    pub headers: Vec<(String, String)>,
+    /// Connect over TLS (`wss://`). The certificate is checked against the
+    /// host part of the address.
+    #[cfg(feature = "tls")]
+    pub tls: Option<Arc<tokio_rustls::rustls::ClientConfig>>,
+    /// Offer permessage-deflate
+    #[cfg(feature = "deflate")]
+    pub permessage_deflate: bool,
+    /// Other compression extensions to offer, most preferred first and
+    /// ahead of permessage-deflate
+    pub compression: Vec<Arc<dyn Compression>>,
+    /// Ask for a hello after the upgrade, see [`crate::hello`]. The
+    /// `Session` connects set it; the peer's hello is the first frame read.
+    pub hello: bool,
+    /// Redirects followed before giving up; with the default of 0 a
+    /// redirect fails the handshake
+    pub max_redirects: usize,
+}
+
+impl ConnectOptions {
+    pub fn protocol(mut self, name: &str) -> Self {
+        self.protocols.push(name.to_string());
+        self
+    }
+
+    pub fn ticket(mut self, ticket: &str) -> Self {
+        self.ticket = Some(ticket.to_string());
+        self
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// Send `value` as the `Authorization` header, replacing any set before
+    pub fn authorization(mut self, value: &str) -> Self {
+        self.headers
+            .retain(|(name, _)| !name.eq_ignore_ascii_case("authorization"));
+        self.header("Authorization", value)
+    }
+
+    /// Authenticate with a bearer token, such as the ones
+    /// `HandshakeConfig::jwt` checks
+    pub fn bearer_auth(self, token: &str) -> Self {
+        self.authorization(&format!("Bearer {token}"))
+    }
+
+    /// Authenticate with HTTP Basic credentials. `user` may not contain `:`.
+    pub fn basic_auth(self, user: &str, password: &str) -> Self {
+        let credentials = Base64.encode(format!("{user}:{password}"));
+        self.authorization(&format!("Basic {credentials}"))
+    }
+
+    #[cfg(feature = "deflate")]
+    pub fn permessage_deflate(mut self) -> Self {
+        self.permessage_deflate = true;
+        self
+    }
+
+    pub fn compression(mut self, compression: Arc<dyn Compression>) -> Self {
+        self.compression.push(compression);
+        self
+    }
+
+    /// Follow up to `max` redirects (301, 302, 303, 307 and 308) answering
+    /// the upgrade, as gateways pointing to a canonical host send. A
+    /// redirect from `wss://` to `ws://` is refused, and `Authorization`
+    /// and `Cookie` headers aren't sent on to another host.
+    pub fn follow_redirects(mut self, max: usize) -> Self {
+        self.max_redirects = max;
+        self
+    }
+
+    #[cfg(feature = "tls")]
+    pub fn tls(mut self, config: Arc<tokio_rustls::rustls::ClientConfig>) -> Self {
+        self.tls = Some(config);
+        self
+    }
+
+    /// Connect over TLS, trusting the Mozilla root certificates
+    #[cfg(feature = "tls")]
+    pub fn secure(self) -> Self {
+        use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+
+        let roots = RootCertStore {
+            roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+        };
+        let config = ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        self.tls(Arc::new(config))
+    }
+}
+
+/// What the server agreed to in the handshake
+#[derive(Debug, Clone, Default)]
+pub struct Upgrade {
+    pub protocol: Option<String>,
+    /// Session ticket the client presented
+    pub ticket: Option<String>,
+    /// Compression extension negotiated
+    pub compression: Option<Arc<dyn Compression>>,
+    /// The client's upgrade request
+    pub request: HandshakeRequest,
+    /// Who the client authenticated as, see [`HandshakeConfig::cookie_auth`]
+    pub principal: Option<String>,
+    /// Claims of the client's bearer token
+    pub claims: Option<serde_json::Value>,
+    /// Both sides agreed to exchange hellos, see [`crate::hello`]
+    pub hello: bool,
+}
+
+/// HTTP request a client opened a WebSocket with, kept by the server side
+/// for routing and authentication, see [`WebSocket::request`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HandshakeRequest {
+    pub method: String,
+    /// Request target without its query
+    pub path: String,
+    /// What followed `?` in the request target
+    pub query: Option<String>,
+    /// Headers as received, in order
+    pub headers: Vec<(String, String)>,
+    /// Filled in by [`crate::server::SessionServer`]; `None` for a handshake
+    /// over some other stream
+    pub peer_addr: Option<SocketAddr>,
+}
 
-pub async fn handle_websocket_handshake(stream: &mut TcpStream) -> std::io::Result<()> {
-    let (read_half, mut write_half) = stream.split();
+impl HandshakeRequest {
+    /// First header named `name`, ignoring case
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// First value of query parameter `name`, not percent-decoded
+    pub fn query_param(&self, name: &str) -> Option<&str> {
+        self.query
+            .as_deref()?
+            .split('&')
+            .map(|pair| pair.split_once('=').unwrap_or((pair, "")))
+            .find(|(k, _)| *k == name)
+            .map(|(_, v)| v)
+    }
+
+    /// Cookies of all `Cookie` headers by name; of a name sent twice, the
+    /// first value wins
+    pub fn cookies(&self) -> HashMap<&str, &str> {
+        let mut cookies = HashMap::new();
+        for pair in self.cookie_pairs() {
+            cookies.entry(pair.0).or_insert(pair.1);
+        }
+        cookies
+    }
+
+    /// Value of cookie `name`, without surrounding quotes
+    pub fn cookie(&self, name: &str) -> Option<&str> {
+        self.cookie_pairs()
+            .find(|(k, _)| *k == name)
+            .map(|(_, v)| v)
+    }
+
+    fn cookie_pairs(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.headers
+            .iter()
+            .filter(|(k, _)| k.eq_ignore_ascii_case("cookie"))
+            .flat_map(|(_, v)| v.split(';'))
+            .filter_map(|pair| {
+                let (name, value) = pair.split_once('=')?;
+                let value = value.trim();
+                let value = value
+                    .strip_prefix('"')
+                    .and_then(|v| v.strip_suffix('"'))
+                    .unwrap_or(value);
+                Some((name.trim(), value))
+            })
+    }
+}
+
+/// HTTP response refusing an upgrade. A JSON `body` lets browser clients
+/// show why they were turned away.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rejection {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Option<serde_json::Value>,
+}
+
+impl Rejection {
+    pub fn new(status: u16) -> Self {
+        Self {
+            status,
+            headers: Vec::new(),
+            body: None,
+        }
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// Send `body` as `application/json`
+    pub fn json(mut self, body: serde_json::Value) -> Self {
+        self.body = Some(body);
+        self
+    }
+
+    pub(crate) async fn write<W: AsyncWrite + Unpin>(&self, w: &mut W) -> std::io::Result<()> {
+        let body = match &self.body {
+            Some(body) => serde_json::to_vec(body)?,
+            None => Vec::new(),
+        };
+
+        let mut head = format!("HTTP/1.1 {} {}\r\n", self.status, reason(self.status));
+        for (name, value) in &self.headers {
+            head.push_str(&format!("{name}: {value}\r\n"));
+        }
+        if self.body.is_some() {
+            head.push_str("Content-Type: application/json\r\n");
+        }
+        head.push_str(&format!(
+            "Content-Length: {}\r\nConnection: close\r\n\r\n",
+            body.len()
+        ));
+
+        w.write_all(head.as_bytes()).await?;
+        w.write_all(&body).await?;
+        w.flush().await?;
+        w.shutdown().await
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        426 => "Upgrade Required",
+        429 => "Too Many Requests",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        503 => "Service Unavailable",
+        _ => "Error",
+    }
+}
+
+pub async fn handle_websocket_handshake<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    config: &HandshakeConfig,
+) -> std::io::Result<Upgrade> {
+    upgrade(stream, config, None).await
+}
+
+/// Server side of the handshake, for a client at `peer_addr` if known
+async fn upgrade<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    config: &HandshakeConfig,
+    peer_addr: Option<SocketAddr>,
+) -> std::io::Result<Upgrade> {
+    let (read_half, mut write_half) = tokio::io::split(stream);
     let mut reader = BufReader::new(read_half);
 
-    // ---- 1. Read request line with timeout ----
-    let mut request_line = String::new();
-    timeout(Duration::from_secs(5), reader.read_line(&mut request_line)).await??;
+    let limits = config.limits;
+    let deadline = Instant::now() + limits.timeout;
+    let mut budget = limits.max_header_bytes;
+
+    // ---- 1. Read request line before the deadline ----
+    let Some(request_line) = read_request_line(&mut reader, deadline, &mut budget).await? else {
+        Rejection::new(431).write(&mut write_half).await?;
+        return Err(header_limit());
+    };
 
     let request_line = request_line.trim_end();
 
     if !request_line.starts_with("GET") {
-        write_half
-            .write_all(
-                b"HTTP/1.1 405 Method Not Allowed\r\n\
-                Content-Length: 0\r\n\
-                Connection: close\r\n\r\n",
-            )
-            .await?;
-        write_half.shutdown().await?;
-        return Ok(());
+        return refuse(
+            &mut write_half,
+            Rejection::new(405).header("Allow", "GET"),
+            "Upgrade request is not a GET",
+        )
+        .await;
     }
 
-    // ---- 2. Read headers with timeout ----
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next().unwrap_or_default().to_string();
+    let target = parts.next().unwrap_or("/");
+    let http_version = parts.next().unwrap_or_default();
+    let (path, query) = match target.split_once('?') {
+        Some((path, query)) => (path.to_string(), Some(query.to_string())),
+        None => (target.to_string(), None),
+    };
+
+    // ---- 2. Read headers before the deadline ----
     let mut headers = HashMap::new();
+    let mut received = Vec::new();
 
     loop {
-        let mut line = String::new();
-        timeout(Duration::from_secs(5), reader.read_line(&mut line)).await??;
+        let Some(line) = read_request_line(&mut reader, deadline, &mut budget).await? else {
+            Rejection::new(431).write(&mut write_half).await?;
+            return Err(header_limit());
+        };
 
         if line == "\r\n" {
             break;
         }
 
+        if received.len() >= limits.max_headers {
+            Rejection::new(431).write(&mut write_half).await?;
+            return Err(header_limit());
+        }
+
         if let Some((k, v)) = line.split_once(':') {
+            received.push((k.trim().to_string(), v.trim().to_string()));
             headers.insert(k.trim().to_lowercase(), v.trim().to_string());
         }
     }
 
     // ---- 3. Check if this is a WebSocket upgrade ----
-    let is_upgrade = headers
-        .get("upgrade")
-        .map(|v| v.eq_ignore_ascii_case("websocket"))
-        .unwrap_or(false);
+    let attempted = headers.contains_key("upgrade") || headers.contains_key("sec-websocket-key");
 
-    let has_connection_upgrade = headers
-        .get("connection")
-        .map(|v| v.to_lowercase().contains("upgrade"))
-        .unwrap_or(false);
-
-    if !is_upgrade || !has_connection_upgrade {
+    if !attempted {
         // Normal HTTP response (important for browsers)
         let body = b"OK";
 
@@ -82,13 +572,49 @@ pub async fn handle_websocket_handshake(stream: &mut TcpStream) -> std::io::Resu
         write_half.flush().await?;
         write_half.shutdown().await?;
 
-        return Ok(());
+        return Ok(Upgrade::default());
     }
 
+    let is_upgrade = headers
+        .get("upgrade")
+        .map(|v| v.eq_ignore_ascii_case("websocket"))
+        .unwrap_or(false);
+
+    let has_connection_upgrade = headers
+        .get("connection")
+        .map(|v| v.to_lowercase().contains("upgrade"))
+        .unwrap_or(false);
+
     // ---- 4. Validate required headers ----
-    let key = headers.get("sec-websocket-key").ok_or_else(|| {
-        std::io::Error::new(std::io::ErrorKind::InvalidData, "Missing Sec-WebSocket-Key")
-    })?;
+    if http_version != "HTTP/1.1" {
+        return refuse(
+            &mut write_half,
+            Rejection::new(400),
+            "Upgrade request is not HTTP/1.1",
+        )
+        .await;
+    }
+
+    if !is_upgrade || !has_connection_upgrade {
+        return refuse(
+            &mut write_half,
+            Rejection::new(400),
+            "Missing Upgrade: websocket or Connection: Upgrade",
+        )
+        .await;
+    }
+
+    let Some(key) = headers
+        .get("sec-websocket-key")
+        .filter(|key| valid_key(key))
+    else {
+        return refuse(
+            &mut write_half,
+            Rejection::new(400),
+            "Missing or invalid Sec-WebSocket-Key",
+        )
+        .await;
+    };
 
     let version_ok = headers
         .get("sec-websocket-version")
@@ -96,65 +622,398 @@ pub async fn handle_websocket_handshake(stream: &mut TcpStream) -> std::io::Resu
         .unwrap_or(false);
 
     if !version_ok {
-        write_half
-            .write_all(
-                b"HTTP/1.1 426 Upgrade Required\r\n\
-                Sec-WebSocket-Version: 13\r\n\
-                Content-Length: 0\r\n\
-                Connection: close\r\n\r\n",
-            )
-            .await?;
-        write_half.shutdown().await?;
-        return Ok(());
+        return refuse(
+            &mut write_half,
+            Rejection::new(426).header("Sec-WebSocket-Version", "13"),
+            "Unsupported Sec-WebSocket-Version",
+        )
+        .await;
     }
 
-    // ---- 5. Generate Sec-WebSocket-Accept ----
-    let mut hasher = Sha1::new();
-    hasher.update(key.as_bytes());
-    hasher.update(b"258EAFA5-E914-47DA-95CA-C5AB0DC85B11");
+    let request = HandshakeRequest {
+        method,
+        path,
+        query,
+        headers: received,
+        peer_addr,
+    };
 
-    let accept = Base64.encode(hasher.finalize());
+    // ---- 5. Let the server accept or refuse ----
+    if let Some(origins) = &config.origins
+        && !origins.allows(request.header("origin"))
+    {
+        let origin = request.header("origin").unwrap_or_default().to_string();
+        Rejection::new(403).write(&mut write_half).await?;
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::PermissionDenied,
+            format!("Origin not allowed: {origin:?}"),
+        ));
+    }
+
+    let mut principal = None;
+    if let Some(auth) = &config.cookie_auth {
+        if let Some(value) = request.cookie(&auth.name) {
+            principal = (auth.validate)(value.to_string()).await;
+        }
+        if principal.is_none() {
+            Rejection::new(401).write(&mut write_half).await?;
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::PermissionDenied,
+                "Missing or invalid session cookie",
+            ));
+        }
+    }
+
+    #[cfg_attr(not(feature = "jwt"), allow(unused_mut))]
+    let mut claims = None;
+    #[cfg(feature = "jwt")]
+    if let Some(jwt) = &config.jwt {
+        match jwt.verify(&request) {
+            Ok(verified) => {
+                if principal.is_none() {
+                    principal = verified["sub"].as_str().map(str::to_string);
+                }
+                claims = Some(verified);
+            }
+            Err(rejection) => {
+                rejection.write(&mut write_half).await?;
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::PermissionDenied,
+                    "Missing or invalid bearer token",
+                ));
+            }
+        }
+    }
+
+    let mut hook_headers = String::new();
+    if let Some(hook) = &config.on_upgrade {
+        let refused = match (hook.0)(request.clone()).await {
+            Ok(headers) => match extra_headers(&headers) {
+                Ok(lines) => {
+                    hook_headers = lines;
+                    None
+                }
+                Err(e) => Some((Rejection::new(500), format!("{e:?}"))),
+            },
+            Err(rejection) => {
+                let why = format!("Upgrade rejected with {}", rejection.status);
+                Some((rejection, why))
+            }
+        };
+        if let Some((rejection, why)) = refused {
+            rejection.write(&mut write_half).await?;
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::PermissionDenied,
+                why,
+            ));
+        }
+    }
+
+    // ---- 6. Generate Sec-WebSocket-Accept ----
+    let accept = accept_key(key);
+
+    // ---- 7. Select a subprotocol ----
+    let offered: Vec<&str> = headers
+        .get("sec-websocket-protocol")
+        .map(|v| v.split(',').map(str::trim).collect())
+        .unwrap_or_default();
+
+    let protocol = config
+        .protocols
+        .iter()
+        .find(|p| offered.contains(&p.as_str()))
+        .cloned();
+
+    // ---- 8. Accept the most preferred compression offered ----
+    let offers: Vec<&str> = headers
+        .get("sec-websocket-extensions")
+        .map(|v| v.split(',').map(str::trim).collect())
+        .unwrap_or_default();
+    let compression = compressions(
+        &config.compression,
+        #[cfg(feature = "deflate")]
+        config.permessage_deflate,
+    )
+    .find(|c| offers.iter().any(|offer| c.accepts(offer)));
+
+    // ---- 9. Send upgrade response ----
+    let protocol_header = match &protocol {
+        Some(p) => format!("Sec-WebSocket-Protocol: {p}\r\n"),
+        None => String::new(),
+    };
+    let extensions_header = extensions_header(compression.iter());
+    let hello = config.hello && headers.contains_key("session-hello");
+    let hello_header = match hello {
+        true => format!("{HELLO_HEADER}: {VERSION}\r\n"),
+        false => String::new(),
+    };
 
-    // ---- 6. Send upgrade response ----
     let response = format!(
         "HTTP/1.1 101 Switching Protocols\r\n\
          Upgrade: websocket\r\n\
          Connection: Upgrade\r\n\
          Sec-WebSocket-Accept: {}\r\n\
+         {}{}{}{}\
          \r\n",
-        accept
+        accept, protocol_header, extensions_header, hello_header, hook_headers
     );
 
     write_half.write_all(response.as_bytes()).await?;
     write_half.flush().await?;
 
-    Ok(())
+    Ok(Upgrade {
+        protocol,
+        ticket: headers.remove("session-ticket"),
+        compression,
+        request,
+        principal,
+        claims,
+        hello,
+    })
 }
 
 impl WebSocket {
-    pub async fn handshake(mut stream: TcpStream) -> super::Result<Self> {
-        handle_websocket_handshake(&mut stream).await?;
-
-        let (read, write) = stream.into_split();
+    pub(crate) fn from_stream(stream: impl Stream, is_client: bool, upgrade: Upgrade) -> Self {
+        let (read, write) = tokio::io::split(stream);
 
-        Ok(Self {
+        Self {
             id: rand::random(),
-            reader: Arc::new(Mutex::new(read)),
-            writer: Arc::new(Mutex::new(write)),
-            is_server: false,
-        })
+            reader: Arc::new(Mutex::new(Reader::new(Box::new(read)))),
+            writer: Arc::new(Mutex::new(Writer::new(Box::new(write)))),
+            is_client,
+            inbound: Arc::new(Meter::new()),
+            outbound: Arc::new(Meter::new()),
+            config: Arc::new(Config::default()),
+            protocol: upgrade.protocol,
+            ticket: upgrade.ticket,
+            principal: upgrade.principal,
+            claims: upgrade.claims.map(Arc::new),
+            hello: upgrade.hello,
+            tenant_budget: None,
+            compression: upgrade.compression,
+            close_stats: None,
+            closing: Arc::default(),
+            buffers: None,
+            request: None,
+        }
+    }
+
+    /// Server side handshake over an accepted stream, e.g. a `TcpStream` or
+    /// a TLS stream wrapping one
+    pub async fn handshake(stream: impl Stream) -> super::Result<Self> {
+        Self::handshake_with(stream, &HandshakeConfig::default()).await
+    }
+
+    pub async fn handshake_with(
+        stream: impl Stream,
+        config: &HandshakeConfig,
+    ) -> super::Result<Self> {
+        Self::handshake_from(stream, config, None).await
+    }
+
+    /// Server side handshake with a client at `peer_addr`
+    pub(crate) async fn handshake_from(
+        mut stream: impl Stream,
+        config: &HandshakeConfig,
+        peer_addr: Option<SocketAddr>,
+    ) -> super::Result<Self> {
+        let mut upgrade = upgrade(&mut stream, config, peer_addr).await?;
+        let request = std::mem::take(&mut upgrade.request);
+
+        let mut ws = Self::from_stream(stream, false, upgrade);
+        ws.request = Some(Arc::new(request));
+        Ok(ws)
     }
 
     /// Connect to a WebSocket server and perform the handshake
     pub async fn connect(addr: &str, path: &str) -> super::Result<Self> {
-        // 1. TCP connect
-        let mut stream = TcpStream::connect(addr).await?;
+        Self::connect_with(addr, path, &ConnectOptions::default()).await
+    }
+
+    pub async fn connect_with(
+        addr: &str,
+        path: &str,
+        options: &ConnectOptions,
+    ) -> super::Result<Self> {
+        Self::connect_to(addr, addr, path, options).await
+    }
 
+    /// Connect over the Unix domain socket at `path`, e.g. to a
+    /// [`SessionServer::bind_unix`](crate::server::SessionServer::bind_unix)
+    /// on the same host, requesting `request_path`
+    #[cfg(unix)]
+    pub async fn connect_unix(
+        path: impl AsRef<std::path::Path>,
+        request_path: &str,
+    ) -> super::Result<Self> {
+        Self::connect_unix_with(path, request_path, &ConnectOptions::default()).await
+    }
+
+    /// Like [`Self::connect_unix`]. TLS and redirects in `options` aren't
+    /// used.
+    #[cfg(unix)]
+    pub async fn connect_unix_with(
+        path: impl AsRef<std::path::Path>,
+        request_path: &str,
+        options: &ConnectOptions,
+    ) -> super::Result<Self> {
+        let stream = tokio::net::UnixStream::connect(path).await?;
+        Self::client_handshake(stream, "localhost", request_path, options).await
+    }
+
+    /// Connect to a `ws://` or `wss://` URL such as
+    /// `wss://example.com/chat?room=1`. The port defaults to 80 or 443 and
+    /// the path to `/`. `wss://` without a TLS config in `options` trusts
+    /// the Mozilla root certificates, as [`ConnectOptions::secure`] does.
+    pub async fn connect_url(url: &str) -> super::Result<Self> {
+        Self::connect_url_with(url, &ConnectOptions::default()).await
+    }
+
+    pub async fn connect_url_with(url: &str, options: &ConnectOptions) -> super::Result<Self> {
+        let url = WsUrl::parse(url)?;
+
+        #[cfg(feature = "tls")]
+        let options = &{
+            let options = options.clone();
+            match (url.secure, options.tls.is_some()) {
+                (true, false) => options.secure(),
+                (true, true) => options,
+                (false, _) => ConnectOptions {
+                    tls: None,
+                    ..options
+                },
+            }
+        };
+        #[cfg(not(feature = "tls"))]
+        if url.secure {
+            return Err(super::Error::HandshakeFailed(
+                "wss:// needs the tls feature".into(),
+            ));
+        }
+
+        Self::connect_to(&url.addr, &url.host, &url.path, options).await
+    }
+
+    /// Connect to `addr`, naming `host` in the `Host` header, following
+    /// redirects as far as `options` allows
+    async fn connect_to(
+        addr: &str,
+        host: &str,
+        path: &str,
+        options: &ConnectOptions,
+    ) -> super::Result<Self> {
+        let mut target = WsUrl {
+            #[cfg(feature = "tls")]
+            secure: options.tls.is_some(),
+            #[cfg(not(feature = "tls"))]
+            secure: false,
+            addr: addr.to_string(),
+            host: host.to_string(),
+            path: path.to_string(),
+        };
+        let mut options = options.clone();
+
+        for _ in 0..=options.max_redirects {
+            let location = match Self::connect_once(&target, &options).await? {
+                Handshake::Upgraded(ws) => return Ok(ws),
+                Handshake::Redirect(location) => location,
+            };
+
+            let next = target.redirect(&location)?;
+            if next.addr != target.addr {
+                options.headers.retain(|(name, _)| {
+                    !name.eq_ignore_ascii_case("authorization")
+                        && !name.eq_ignore_ascii_case("cookie")
+                });
+            }
+            #[cfg(feature = "tls")]
+            if next.secure && options.tls.is_none() {
+                options = options.secure();
+            }
+            target = next;
+        }
+
+        Err(super::Error::HandshakeFailed(format!(
+            "More than {} redirects",
+            options.max_redirects
+        )))
+    }
+
+    async fn connect_once(target: &WsUrl, options: &ConnectOptions) -> super::Result<Handshake> {
+        let WsUrl {
+            addr, host, path, ..
+        } = target;
+
+        // 1. TCP connect, then TLS if asked for
+        let stream = TcpStream::connect(addr).await?;
+
+        #[cfg(feature = "tls")]
+        if let Some(config) = &options.tls {
+            let name = host_of(addr);
+            let name = tokio_rustls::rustls::pki_types::ServerName::try_from(name.to_string())
+                .map_err(|e| super::Error::HandshakeFailed(format!("Invalid host {name}: {e}")))?;
+            let stream = tokio_rustls::TlsConnector::from(config.clone())
+                .connect(name, stream)
+                .await?;
+            return Self::client_upgrade(stream, host, path, options).await;
+        }
+
+        Self::client_upgrade(stream, host, path, options).await
+    }
+
+    pub(crate) async fn client_handshake(
+        stream: impl Stream,
+        host: &str,
+        path: &str,
+        options: &ConnectOptions,
+    ) -> super::Result<Self> {
+        match Self::client_upgrade(stream, host, path, options).await? {
+            Handshake::Upgraded(ws) => Ok(ws),
+            Handshake::Redirect(location) => Err(super::Error::HandshakeFailed(format!(
+                "Redirected to {location}"
+            ))),
+        }
+    }
+
+    async fn client_upgrade(
+        mut stream: impl Stream,
+        host: &str,
+        path: &str,
+        options: &ConnectOptions,
+    ) -> super::Result<Handshake> {
         // 2. Generate Sec-WebSocket-Key
         let key_bytes: [u8; 16] = rand::random();
-        let key = base64::prelude::BASE64_STANDARD.encode(&key_bytes);
+        let key = base64::prelude::BASE64_STANDARD.encode(key_bytes);
 
         // 3. Send HTTP Upgrade request
+        let protocol_header = match options.protocols.is_empty() {
+            true => String::new(),
+            false => format!(
+                "Sec-WebSocket-Protocol: {}\r\n",
+                options.protocols.join(", ")
+            ),
+        };
+
+        let ticket_header = match &options.ticket {
+            Some(ticket) => format!("{SESSION_TICKET_HEADER}: {ticket}\r\n"),
+            None => String::new(),
+        };
+
+        let hello_header = match options.hello {
+            true => format!("{HELLO_HEADER}: {VERSION}\r\n"),
+            false => String::new(),
+        };
+
+        let extra_headers = extra_headers(&options.headers)?;
+
+        let offered: Vec<_> = compressions(
+            &options.compression,
+            #[cfg(feature = "deflate")]
+            options.permessage_deflate,
+        )
+        .collect();
+        let extensions_header = extensions_header(offered.iter());
+
         let request = format!(
             "GET {} HTTP/1.1\r\n\
              Host: {}\r\n\
@@ -162,64 +1021,366 @@ impl WebSocket {
              Connection: Upgrade\r\n\
              Sec-WebSocket-Key: {}\r\n\
              Sec-WebSocket-Version: 13\r\n\
+             {}{}{}{}{}\
              \r\n",
-            path, addr, key
+            path,
+            host,
+            key,
+            protocol_header,
+            ticket_header,
+            extensions_header,
+            hello_header,
+            extra_headers
         );
         stream.write_all(request.as_bytes()).await?;
         stream.flush().await?;
 
-        // 4. Read HTTP response
-        let mut reader = BufReader::new(&mut stream);
-        let mut status_line = String::new();
-        timeout(
-            tokio::time::Duration::from_secs(5),
-            reader.read_line(&mut status_line),
-        )
-        .await??;
+        // 4. Read HTTP response. Unbuffered, so frames the server sends
+        // right behind it stay in the socket for the WebSocket to read
+        let head = timeout(Duration::from_secs(5), read_response_head(&mut stream)).await??;
+        let mut lines = head.lines();
+
+        let status_line = lines.next().unwrap_or_default();
+        let status = status_line.split_whitespace().nth(1).unwrap_or_default();
+        if options.max_redirects > 0
+            && matches!(status, "301" | "302" | "303" | "307" | "308")
+            && let Some(location) = lines.clone().find_map(|line| {
+                let (name, value) = line.split_once(':')?;
+                name.eq_ignore_ascii_case("location")
+                    .then(|| value.trim().to_string())
+            })
+        {
+            return Ok(Handshake::Redirect(location));
+        }
+        if matches!(status, "401" | "403") {
+            return Err(super::Error::Unauthorized {
+                status: status.parse().unwrap_or_default(),
+                headers: lines
+                    .filter_map(|line| {
+                        let (name, value) = line.split_once(':')?;
+                        Some((name.trim().to_string(), value.trim().to_string()))
+                    })
+                    .collect(),
+            });
+        }
         if !status_line.starts_with("HTTP/1.1 101") {
             return Err(super::Error::HandshakeFailed(format!(
-                "Expected 101 Switching Protocols, got: {}",
-                status_line.trim_end()
+                "Expected 101 Switching Protocols, got: {status_line}"
             )));
         }
 
         // Read headers
         let mut sec_accept = None;
-        loop {
-            let mut line = String::new();
-            reader.read_line(&mut line).await?;
-            let line = line.trim_end();
-            if line.is_empty() {
-                break; // end of headers
-            }
+        let mut protocol = None;
+        let mut extensions = None;
+        let mut hello = false;
+        for line in lines {
             if let Some((k, v)) = line.split_once(':') {
                 if k.eq_ignore_ascii_case("sec-websocket-accept") {
                     sec_accept = Some(v.trim().to_string());
+                } else if k.eq_ignore_ascii_case("sec-websocket-protocol") {
+                    protocol = Some(v.trim().to_string());
+                } else if k.eq_ignore_ascii_case("sec-websocket-extensions") {
+                    extensions = Some(v.trim().to_string());
+                } else if k.eq_ignore_ascii_case(HELLO_HEADER) {
+                    hello = options.hello;
                 }
             }
         }
 
         // 5. Verify Sec-WebSocket-Accept
-        let expected = {
-            let mut sha1 = Sha1::new();
-            sha1.update(key.as_bytes());
-            sha1.update(b"258EAFA5-E914-47DA-95CA-C5AB0DC85B11");
-            base64::prelude::BASE64_STANDARD.encode(sha1.finalize())
-        };
+        let expected = accept_key(&key);
         if sec_accept.as_deref() != Some(expected.as_str()) {
             return Err(super::Error::HandshakeFailed(
                 "Sec-WebSocket-Accept mismatch".into(),
             ));
         }
 
-        // 6. Upgrade succeeded, split stream
-        let (read, write) = stream.into_split();
+        // 6. The server may only pick a protocol we offered
+        if let Some(p) = &protocol
+            && !options.protocols.contains(p)
+        {
+            return Err(super::Error::HandshakeFailed(format!(
+                "Server selected unoffered protocol: {p}"
+            )));
+        }
+
+        // 7. Nor an extension we didn't offer
+        let compression = match extensions {
+            None => None,
+            Some(ext) => {
+                let token = ext.split(';').next().map(str::trim);
+                match offered.into_iter().find(|c| Some(c.token()) == token) {
+                    Some(compression) => Some(compression),
+                    None => {
+                        return Err(super::Error::HandshakeFailed(format!(
+                            "Server selected unoffered extension: {ext}"
+                        )));
+                    }
+                }
+            }
+        };
+
+        // 8. Upgrade succeeded, split stream
+        Ok(Handshake::Upgraded(Self::from_stream(
+            stream,
+            true,
+            Upgrade {
+                protocol,
+                ticket: None,
+                compression,
+                request: HandshakeRequest::default(),
+                principal: None,
+                claims: None,
+                hello,
+            },
+        )))
+    }
+}
+
+/// How the server answered a client's upgrade request
+enum Handshake {
+    Upgraded(WebSocket),
+    /// Try again at this `Location`
+    Redirect(String),
+}
+
+/// `Sec-WebSocket-Accept` value answering a `Sec-WebSocket-Key`
+pub(crate) fn accept_key(key: &str) -> String {
+    let mut hasher = Sha1::new();
+    hasher.update(key.as_bytes());
+    hasher.update(b"258EAFA5-E914-47DA-95CA-C5AB0DC85B11");
+    Base64.encode(hasher.finalize())
+}
+
+/// Compression extensions in order of preference: `others`, then
+/// permessage-deflate if enabled
+fn compressions<'a>(
+    others: &'a [Arc<dyn Compression>],
+    #[cfg(feature = "deflate")] deflate: bool,
+) -> impl Iterator<Item = Arc<dyn Compression>> + 'a {
+    #[cfg(feature = "deflate")]
+    let deflate =
+        deflate.then(|| Arc::new(super::deflate::PermessageDeflate) as Arc<dyn Compression>);
+    #[cfg(not(feature = "deflate"))]
+    let deflate = None;
+
+    others.iter().cloned().chain(deflate)
+}
+
+/// `Sec-WebSocket-Extensions` line offering or accepting `compressions`
+fn extensions_header<'a>(compressions: impl Iterator<Item = &'a Arc<dyn Compression>>) -> String {
+    let params: Vec<&str> = compressions.map(|c| c.params()).collect();
+    match params.is_empty() {
+        true => String::new(),
+        false => format!("Sec-WebSocket-Extensions: {}\r\n", params.join(", ")),
+    }
+}
+
+/// Headers of the upgrade request that only the handshake may set
+const HANDSHAKE_HEADERS: [&str; 9] = [
+    "host",
+    "upgrade",
+    "connection",
+    "sec-websocket-key",
+    "sec-websocket-version",
+    "sec-websocket-protocol",
+    "sec-websocket-extensions",
+    "session-ticket",
+    "session-hello",
+];
+
+/// `ConnectOptions::headers` as request header lines, refusing names the
+/// handshake owns and anything that would break the request apart
+fn extra_headers(headers: &[(String, String)]) -> super::Result<String> {
+    let mut lines = String::new();
+    for (name, value) in headers {
+        let invalid_name = name.is_empty()
+            || !name
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b));
+        if invalid_name || value.contains(['\r', '\n']) {
+            return Err(super::Error::HandshakeFailed(format!(
+                "Invalid header: {name:?}"
+            )));
+        }
+        if HANDSHAKE_HEADERS.contains(&name.to_ascii_lowercase().as_str()) {
+            return Err(super::Error::HandshakeFailed(format!(
+                "Header {name} is set by the handshake"
+            )));
+        }
+        lines.push_str(&format!("{name}: {value}\r\n"));
+    }
+    Ok(lines)
+}
+
+/// A `ws://` or `wss://` URL taken apart for connecting
+#[derive(Clone)]
+struct WsUrl {
+    secure: bool,
+    /// Host and port to connect to
+    addr: String,
+    /// Value of the `Host` header, with the port only if it isn't the default
+    host: String,
+    /// Path and query
+    path: String,
+}
+
+impl WsUrl {
+    fn parse(url: &str) -> super::Result<Self> {
+        let invalid =
+            |why: &str| super::Error::HandshakeFailed(format!("Invalid URL {url}: {why}"));
+
+        let (scheme, rest) = url.split_once("://").ok_or_else(|| invalid("no scheme"))?;
+        let secure = match scheme.to_ascii_lowercase().as_str() {
+            "ws" => false,
+            "wss" => true,
+            _ => return Err(invalid("scheme isn't ws or wss")),
+        };
+
+        // The fragment never goes on the wire
+        let rest = rest.split('#').next().unwrap_or_default();
+        let (authority, path) = rest.split_at(rest.find(['/', '?']).unwrap_or(rest.len()));
+        if authority.contains('@') {
+            return Err(invalid("credentials in URLs aren't supported"));
+        }
+
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) if !port.contains(']') => {
+                let port = port.parse::<u16>().map_err(|_| invalid("bad port"))?;
+                (host, Some(port))
+            }
+            _ => (authority, None),
+        };
+        if host.is_empty() || host == "[]" {
+            return Err(invalid("no host"));
+        }
 
+        let default_port = if secure { 443 } else { 80 };
+        let port = port.unwrap_or(default_port);
         Ok(Self {
-            id: rand::random(),
-            reader: Arc::new(Mutex::new(read)),
-            writer: Arc::new(Mutex::new(write)),
-            is_server: true,
+            secure,
+            addr: format!("{host}:{port}"),
+            host: match port == default_port {
+                true => host.to_string(),
+                false => format!("{host}:{port}"),
+            },
+            path: match path {
+                "" => "/".to_string(),
+                query if query.starts_with('?') => format!("/{query}"),
+                path => path.to_string(),
+            },
         })
     }
+
+    /// Where a redirect to `location` leads: a path on the same host, or
+    /// a `ws`, `wss`, `http` or `https` URL
+    fn redirect(&self, location: &str) -> super::Result<Self> {
+        if location.starts_with('/') {
+            return Ok(Self {
+                path: location.to_string(),
+                ..self.clone()
+            });
+        }
+
+        let location = match location.split_once("://") {
+            Some((scheme, rest)) if scheme.eq_ignore_ascii_case("http") => format!("ws://{rest}"),
+            Some((scheme, rest)) if scheme.eq_ignore_ascii_case("https") => {
+                format!("wss://{rest}")
+            }
+            _ => location.to_string(),
+        };
+        let next = Self::parse(&location)?;
+
+        if self.secure && !next.secure {
+            return Err(super::Error::HandshakeFailed(format!(
+                "Refusing redirect from wss:// to {location}"
+            )));
+        }
+        #[cfg(not(feature = "tls"))]
+        if next.secure {
+            return Err(super::Error::HandshakeFailed(format!(
+                "Redirect to {location} needs the tls feature"
+            )));
+        }
+        Ok(next)
+    }
+}
+
+/// `addr` without its port, and without brackets around an IPv6 address
+#[cfg(feature = "tls")]
+fn host_of(addr: &str) -> &str {
+    let host = match addr.rsplit_once(':') {
+        Some((host, port)) if !port.contains(']') && !host.is_empty() => host,
+        _ => addr,
+    };
+    host.trim_start_matches('[').trim_end_matches(']')
+}
+
+/// Answer a bad upgrade request with `rejection` and fail the handshake
+async fn refuse<W: AsyncWrite + Unpin>(
+    w: &mut W,
+    rejection: Rejection,
+    why: &str,
+) -> std::io::Result<Upgrade> {
+    rejection.write(w).await?;
+    Err(std::io::Error::new(std::io::ErrorKind::InvalidData, why))
+}
+
+/// Whether `key` is base64 of 16 bytes, as RFC 6455 requires
+fn valid_key(key: &str) -> bool {
+    Base64.decode(key).is_ok_and(|bytes| bytes.len() == 16)
+}
+
+/// Read one request line, taking at most `budget` bytes and finishing by
+/// `deadline`. `None` means the budget ran out first.
+async fn read_request_line(
+    reader: &mut (impl AsyncBufRead + Unpin),
+    deadline: Instant,
+    budget: &mut usize,
+) -> std::io::Result<Option<String>> {
+    let mut line = String::new();
+    let read = timeout_at(deadline, reader.take(*budget as u64).read_line(&mut line))
+        .await
+        .map_err(|_| {
+            std::io::Error::new(std::io::ErrorKind::TimedOut, "Handshake deadline exceeded")
+        })??;
+
+    if read == 0 && *budget > 0 {
+        return Err(std::io::ErrorKind::UnexpectedEof.into());
+    }
+    if !line.ends_with('\n') {
+        return Ok(None);
+    }
+
+    *budget -= read;
+    Ok(Some(line))
+}
+
+fn header_limit() -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        "Request headers exceed the handshake limits",
+    )
+}
+
+/// Largest HTTP response head accepted from a server
+const MAX_RESPONSE_HEAD: usize = 16 * 1024;
+
+/// Read up to and including the blank line ending the response head, one
+/// byte at a time so nothing past it is consumed
+async fn read_response_head(stream: &mut (impl AsyncRead + Unpin)) -> std::io::Result<String> {
+    let mut head = Vec::new();
+
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() >= MAX_RESPONSE_HEAD {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "Response head too large",
+            ));
+        }
+        head.push(stream.read_u8().await?);
+    }
+
+    Ok(String::from_utf8_lossy(&head).into_owned())
 }
@@ -0,0 +1,79 @@
+use tokio::sync::OwnedMutexGuard;
+use tokio::time::timeout;
+
+use super::{Error, Result, WebSocket, Writer};
+
+/// A data message sent frame by frame as its parts become available, see
+/// [`WebSocket::message_writer`]. Other sends on the connection, pongs
+/// included, wait until the message is finished. Dropping it unfinished
+/// leaves the peer mid-message, so later sends fail with
+/// `Error::ConnectionClosed`.
+pub struct MessageWriter {
+    ws: WebSocket,
+    writer: OwnedMutexGuard<Writer>,
+    opcode: u8,
+    /// Payload bytes sent so far; `None` until the first frame
+    sent: Option<usize>,
+    finished: bool,
+}
+
+impl WebSocket {
+    /// Start a text (0x1) or binary (0x2) message without buffering all of
+    /// it: each [`MessageWriter::write`] sends one frame. Such messages are
+    /// never compressed.
+    pub async fn message_writer(&self, opcode: u8) -> Result<MessageWriter> {
+        if !matches!(opcode, 0x1 | 0x2) {
+            return Err(Error::InvalidFrame(format!(
+                "Opcode {opcode} isn't a data message"
+            )));
+        }
+
+        Ok(MessageWriter {
+            ws: self.clone(),
+            writer: self.writer.clone().lock_owned().await,
+            opcode,
+            sent: None,
+            finished: false,
+        })
+    }
+}
+
+impl MessageWriter {
+    /// Send `chunk` as the next frame of the message
+    pub async fn write(&mut self, chunk: &[u8]) -> Result<()> {
+        if chunk.is_empty() {
+            return Ok(());
+        }
+        self.send(false, chunk).await
+    }
+
+    /// Send `last` as the final frame, completing the message
+    pub async fn finish(mut self, last: &[u8]) -> Result<()> {
+        self.send(true, last).await?;
+        self.finished = true;
+        self.ws.outbound.record(self.sent.unwrap_or(0));
+        Ok(())
+    }
+
+    async fn send(&mut self, fin: bool, chunk: &[u8]) -> Result<()> {
+        let opcode = match self.sent {
+            Some(_) => 0x0,
+            None => self.opcode,
+        };
+        self.ws.encode_frame(&mut self.writer, fin, opcode, chunk);
+        self.sent = Some(self.sent.unwrap_or(0) + chunk.len());
+
+        match self.ws.config.write_timeout {
+            Some(dur) => timeout(dur, self.writer.flush_frames()).await?,
+            None => self.writer.flush_frames().await,
+        }
+    }
+}
+
+impl Drop for MessageWriter {
+    fn drop(&mut self) {
+        if self.sent.is_some() && !self.finished {
+            self.writer.broken = true;
+        }
+    }
+}
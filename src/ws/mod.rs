@@ -1,30 +1,222 @@
+pub mod budget;
+pub mod buffers;
+pub mod close;
+pub mod compression;
+pub mod config;
+#[cfg(feature = "deflate")]
+mod deflate;
 pub mod error;
+#[cfg(feature = "framed")]
+pub mod framed;
 pub mod handshake;
+pub mod message_writer;
+mod reader;
+pub mod throughput;
+pub use budget::{MemoryBudget, Pressure};
+pub use buffers::{BufferPool, BufferSource};
+pub use compression::Compression;
+pub use config::Config;
 pub use error::{Error, Result};
+#[cfg(feature = "framed")]
+pub use framed::{RawFrame, WebSocketCodec};
+pub use handshake::{
+    ConnectOptions, CookieAuth, HandshakeConfig, HandshakeLimits, HandshakeRequest, OriginPolicy,
+    Rejection, SESSION_TICKET_HEADER, Upgrade, UpgradeHook,
+};
+pub use message_writer::MessageWriter;
+pub use throughput::{Rate, Throughput};
 
 use std::{
     hash::{Hash, Hasher},
-    sync::Arc,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
 };
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    sync::Mutex,
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt},
+    sync::{Mutex, watch},
+    time::{Duration, Instant, timeout, timeout_at},
 };
 
+use reader::{Assembly, FrameHeader, MAX_HEADER, Reader, Step, unmask};
+use throughput::Meter;
+
+use crate::close_stats::{self, CloseStats, Direction};
+
+/// Byte stream a WebSocket runs over, such as TCP or TLS
+pub trait Stream: AsyncRead + AsyncWrite + Send + Unpin + 'static {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin + 'static> Stream for T {}
+
+pub(crate) type ReadHalf = Box<dyn AsyncRead + Send + Unpin>;
+pub(crate) type WriteHalf = Box<dyn AsyncWrite + Send + Unpin>;
+
 #[derive(Debug, Clone)]
 pub enum Frame {
     Text(String),
     Binary(Vec<u8>),
-    Ping,
-    Pong,
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
     Close,
 }
 
 pub struct WebSocket {
-    pub(crate) reader: Arc<Mutex<tokio::net::tcp::OwnedReadHalf>>,
-    pub(crate) writer: Arc<Mutex<tokio::net::tcp::OwnedWriteHalf>>,
+    pub(crate) reader: Arc<Mutex<Reader>>,
+    pub(crate) writer: Arc<Mutex<Writer>>,
     pub(crate) id: u64,
-    pub(crate) is_server: bool,
+    /// Client side: masks what it sends and refuses masked frames
+    pub(crate) is_client: bool,
+    pub(crate) inbound: Arc<Meter>,
+    pub(crate) outbound: Arc<Meter>,
+    pub(crate) config: Arc<Config>,
+    pub(crate) protocol: Option<String>,
+    pub(crate) ticket: Option<String>,
+    pub(crate) principal: Option<String>,
+    pub(crate) claims: Option<Arc<serde_json::Value>>,
+    /// Charged alongside `config.memory_budget`, see [`crate::tenant`]
+    pub(crate) tenant_budget: Option<Arc<MemoryBudget>>,
+    /// Compression extension negotiated in the handshake
+    pub(crate) compression: Option<Arc<dyn Compression>>,
+    pub(crate) close_stats: Option<Arc<CloseStats>>,
+    pub(crate) closing: Arc<Closing>,
+    /// Payload buffers come from here instead of the global allocator
+    pub(crate) buffers: Option<Arc<dyn BufferSource>>,
+    /// Upgrade request of a server side connection
+    pub(crate) request: Option<Arc<HandshakeRequest>>,
+    /// A hello follows the upgrade, see [`crate::hello`]
+    pub(crate) hello: bool,
+}
+
+/// Progress of the closing handshake, shared by clones of a WebSocket
+#[derive(Default)]
+pub(crate) struct Closing {
+    /// A close frame was sent; nothing may follow it
+    sent: AtomicBool,
+    /// The peer's close frame arrived
+    received: watch::Sender<bool>,
+    /// Pongs received so far
+    pongs: watch::Sender<u64>,
+    /// The peer stopped answering pings
+    dead: watch::Sender<bool>,
+}
+
+/// Buffer reserved up front when reading a payload; larger ones grow as
+/// their bytes arrive
+const READ_CHUNK: u64 = 64 * 1024;
+
+/// Largest payload of a ping, pong or close frame (RFC 6455 section 5.5)
+const MAX_CONTROL_PAYLOAD: usize = 125;
+
+/// How long [`WebSocket::close_with`] waits for the peer's close frame,
+/// unless changed with [`Config::close_timeout`]
+const DEFAULT_CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Inflated size below which `Config::max_compression_ratio` isn't enforced
+const RATIO_EXEMPT: usize = 64 * 1024;
+
+/// First-byte bit marking a compressed message (RFC 7692)
+const RSV1: u8 = 0x40;
+
+/// Largest write buffer kept around between sends
+const SCRATCH_RETAIN: usize = 64 * 1024;
+
+pub(crate) struct Writer {
+    stream: WriteHalf,
+    /// Reused buffer that encoded frames are collected in before writing
+    scratch: Vec<u8>,
+    /// Set while a write is in progress. Still set on the next send means
+    /// the previous write was cancelled or failed partway through a frame.
+    broken: bool,
+}
+
+impl Writer {
+    pub(crate) fn new(stream: WriteHalf) -> Self {
+        Self {
+            stream,
+            scratch: Vec::new(),
+            broken: false,
+        }
+    }
+
+    async fn flush_frames(&mut self) -> Result<()> {
+        if self.broken {
+            self.scratch.clear();
+            return Err(Error::ConnectionClosed);
+        }
+
+        self.broken = true;
+        let res = self.stream.write_all(&self.scratch).await;
+
+        self.scratch.clear();
+        self.scratch.shrink_to(SCRATCH_RETAIN);
+
+        res?;
+        self.stream.flush().await?;
+        self.broken = false;
+        Ok(())
+    }
+}
+
+/// Status code of a close frame payload, or 1005 if it has none
+fn close_code(payload: &[u8]) -> u16 {
+    match payload {
+        [hi, lo, ..] => u16::from_be_bytes([*hi, *lo]),
+        _ => close_stats::NO_STATUS,
+    }
+}
+
+/// Encode a frame header into a stack buffer, returning it with its length.
+/// 14 bytes fits the longest header: 2 + 8 byte length + 4 byte mask key.
+pub(crate) fn encode_header(
+    fin: bool,
+    opcode: u8,
+    len: usize,
+    mask: Option<[u8; 4]>,
+) -> ([u8; 14], usize) {
+    let mut header = [0u8; 14];
+    let mask_bit = if mask.is_some() { 0x80 } else { 0x00 };
+    let fin_bit = if fin { 0x80 } else { 0x00 };
+    header[0] = fin_bit | opcode;
+
+    let mut n = 2;
+    if len < 126 {
+        header[1] = (len as u8) | mask_bit;
+    } else if len <= 0xFFFF {
+        header[1] = 126 | mask_bit;
+        header[2..4].copy_from_slice(&(len as u16).to_be_bytes());
+        n = 4;
+    } else {
+        header[1] = 127 | mask_bit;
+        header[2..10].copy_from_slice(&(len as u64).to_be_bytes());
+        n = 10;
+    }
+
+    if let Some(mask) = mask {
+        header[n..n + 4].copy_from_slice(&mask);
+        n += 4;
+    }
+
+    (header, n)
+}
+
+/// Append a frame to `out`, its payload masked with `mask` if given
+pub(crate) fn put_frame<B>(
+    out: &mut B,
+    fin: bool,
+    opcode: u8,
+    payload: &[u8],
+    mask: Option<[u8; 4]>,
+) where
+    B: Extend<u8> + for<'a> Extend<&'a u8>,
+{
+    let (header, len) = encode_header(fin, opcode, payload.len(), mask);
+    out.extend(&header[..len]);
+
+    match mask {
+        Some(mask) => out.extend(payload.iter().zip(mask.iter().cycle()).map(|(b, m)| b ^ m)),
+        None => out.extend(payload),
+    }
 }
 
 impl Clone for WebSocket {
@@ -32,8 +224,22 @@ impl Clone for WebSocket {
         WebSocket {
             reader: self.reader.clone(),
             writer: self.writer.clone(),
-            is_server: self.is_server.clone(),
+            is_client: self.is_client,
             id: self.id,
+            inbound: self.inbound.clone(),
+            outbound: self.outbound.clone(),
+            config: self.config.clone(),
+            protocol: self.protocol.clone(),
+            ticket: self.ticket.clone(),
+            principal: self.principal.clone(),
+            claims: self.claims.clone(),
+            tenant_budget: self.tenant_budget.clone(),
+            compression: self.compression.clone(),
+            close_stats: self.close_stats.clone(),
+            closing: self.closing.clone(),
+            buffers: self.buffers.clone(),
+            request: self.request.clone(),
+            hello: self.hello,
         }
     }
 }
@@ -53,45 +259,214 @@ impl Hash for WebSocket {
 }
 
 impl WebSocket {
+    pub fn with_config(mut self, config: Config) -> Self {
+        self.config = Arc::new(config);
+        self
+    }
+
+    pub(crate) fn with_tenant_budget(mut self, budget: Option<Arc<MemoryBudget>>) -> Self {
+        self.tenant_budget = budget;
+        self
+    }
+
+    /// Count the close codes this connection sends and receives in `stats`
+    pub fn with_close_stats(mut self, stats: Option<Arc<CloseStats>>) -> Self {
+        self.close_stats = stats;
+        self
+    }
+
+    /// Read frame payloads into buffers from `buffers`
+    pub fn with_buffers(mut self, buffers: Option<Arc<dyn BufferSource>>) -> Self {
+        self.buffers = buffers;
+        self
+    }
+
+    /// An empty payload buffer with room for `capacity` bytes
+    fn take_buffer(&self, capacity: usize) -> Vec<u8> {
+        match &self.buffers {
+            Some(buffers) => buffers.take(capacity),
+            None => Vec::with_capacity(capacity),
+        }
+    }
+
+    fn give_back(&self, buf: Vec<u8>) {
+        if let Some(buffers) = &self.buffers {
+            buffers.give_back(buf);
+        }
+    }
+
+    /// Count a close code in the attached [`CloseStats`], if any
+    pub(crate) fn record_close(&self, direction: Direction, code: u16) {
+        if let Some(stats) = &self.close_stats {
+            stats.record(direction, code);
+        }
+    }
+
+    /// Subprotocol agreed on during the handshake
+    pub fn protocol(&self) -> Option<&str> {
+        self.protocol.as_deref()
+    }
+
+    /// Token of the compression extension agreed on during the handshake,
+    /// e.g. `permessage-deflate`
+    pub fn compression(&self) -> Option<&str> {
+        self.compression.as_ref().map(|c| c.token())
+    }
+
+    /// Session ticket the client presented during the handshake
+    pub fn resume_ticket(&self) -> Option<&str> {
+        self.ticket.as_deref()
+    }
+
+    /// Who the client authenticated as during the handshake, see
+    /// [`HandshakeConfig::cookie_auth`]
+    pub fn principal(&self) -> Option<&str> {
+        self.principal.as_deref()
+    }
+
+    /// Claims of the bearer token the client authenticated with, see
+    /// `SessionServer::with_jwt_auth`
+    pub fn claims(&self) -> Option<&serde_json::Value> {
+        self.claims.as_deref()
+    }
+
+    /// The HTTP request the client upgraded with, on the server side
+    pub fn request(&self) -> Option<&HandshakeRequest> {
+        self.request.as_deref()
+    }
+
     async fn send_frame(&self, opcode: u8, payload: &[u8]) -> Result<()> {
+        match self.config.write_timeout {
+            Some(dur) => timeout(dur, self.send_frame_now(opcode, payload)).await?,
+            None => self.send_frame_now(opcode, payload).await,
+        }
+    }
+
+    async fn send_frame_now(&self, opcode: u8, payload: &[u8]) -> Result<()> {
         let mut writer = self.writer.lock().await;
 
-        let mut header = Vec::with_capacity(10);
-        let mask_bit = if self.is_server { 0x80 } else { 0x00 };
-        header.push(0x80 | opcode); // FIN + opcode
-
-        let len = payload.len();
-        if len < 126 {
-            header.push((len as u8) | mask_bit);
-        } else if len <= 0xFFFF {
-            header.push(126 | mask_bit);
-            header.extend_from_slice(&(len as u16).to_be_bytes());
-        } else {
-            header.push(127 | mask_bit);
-            header.extend_from_slice(&(len as u64).to_be_bytes());
-        }
-
-        if self.is_server {
-            // Generate 4-byte mask key
-            let mask_key: [u8; 4] = rand::random();
-            header.extend_from_slice(&mask_key);
-
-            // Mask the payload
-            let mut masked_payload = payload.to_vec();
-            for i in 0..masked_payload.len() {
-                masked_payload[i] ^= mask_key[i % 4];
-            }
+        self.encode_message(&mut writer, opcode, payload, true);
+        writer.flush_frames().await?;
 
-            writer.write_all(&header).await?;
-            writer.write_all(&masked_payload).await?;
-        } else {
-            writer.write_all(&header).await?;
-            writer.write_all(payload).await?;
+        if opcode < 0x8 {
+            self.outbound.record(payload.len());
         }
 
-        writer.flush().await?;
         Ok(())
     }
+
+    /// Send several data messages with one coalesced write and flush
+    pub async fn send_batch<P: AsRef<[u8]>>(&self, opcode: u8, payloads: &[P]) -> Result<()> {
+        self.send_batch_with(opcode, payloads, true).await
+    }
+
+    /// Like [`Self::send_batch`], but never compressed, for payloads that
+    /// are already compressed or encrypted
+    pub async fn send_batch_uncompressed<P: AsRef<[u8]>>(
+        &self,
+        opcode: u8,
+        payloads: &[P],
+    ) -> Result<()> {
+        self.send_batch_with(opcode, payloads, false).await
+    }
+
+    async fn send_batch_with<P: AsRef<[u8]>>(
+        &self,
+        opcode: u8,
+        payloads: &[P],
+        compress: bool,
+    ) -> Result<()> {
+        self.send_batch_before(opcode, payloads, compress, None)
+            .await
+    }
+
+    /// Send a data message unless `deadline` passes while it waits behind
+    /// other writes, failing with [`Error::Expired`] then. Once writing
+    /// starts the message goes out whole.
+    pub async fn send_before(&self, opcode: u8, payload: &[u8], deadline: Instant) -> Result<()> {
+        self.send_batch_before(opcode, &[payload], true, Some(deadline))
+            .await
+    }
+
+    async fn send_batch_before<P: AsRef<[u8]>>(
+        &self,
+        opcode: u8,
+        payloads: &[P],
+        compress: bool,
+        deadline: Option<Instant>,
+    ) -> Result<()> {
+        let send = self.send_batch_now(opcode, payloads, compress, deadline);
+        match self.config.write_timeout {
+            Some(dur) => timeout(dur, send).await?,
+            None => send.await,
+        }
+    }
+
+    async fn send_batch_now<P: AsRef<[u8]>>(
+        &self,
+        opcode: u8,
+        payloads: &[P],
+        compress: bool,
+        deadline: Option<Instant>,
+    ) -> Result<()> {
+        // Writes queue up on this lock in order
+        let mut writer = match deadline {
+            Some(deadline) => timeout_at(deadline, self.writer.lock())
+                .await
+                .map_err(|_| Error::Expired)?,
+            None => self.writer.lock().await,
+        };
+
+        for payload in payloads {
+            self.encode_message(&mut writer, opcode, payload.as_ref(), compress);
+        }
+        writer.flush_frames().await?;
+
+        for payload in payloads {
+            self.outbound.record(payload.as_ref().len());
+        }
+
+        Ok(())
+    }
+
+    /// Append a message to the write buffer, compressing it if negotiated
+    /// and allowed, and fragmenting it per config
+    fn encode_message(&self, writer: &mut Writer, opcode: u8, payload: &[u8], compress: bool) {
+        if compress
+            && let Some(compression) = &self.compression
+            && opcode < 0x8
+            && payload.len() >= self.config.compression_threshold
+            && let Ok(compressed) = compression.compress(payload)
+        {
+            return self.encode_fragments(writer, RSV1 | opcode, opcode, &compressed);
+        }
+
+        self.encode_fragments(writer, opcode, opcode, payload);
+    }
+
+    /// `first` is the opcode of the first frame, with any RSV bits set
+    fn encode_fragments(&self, writer: &mut Writer, first: u8, opcode: u8, payload: &[u8]) {
+        match self.config.fragment_size {
+            // Control frames must never be fragmented
+            Some(size) if opcode < 0x8 && payload.len() > size => {
+                let mut chunks = payload.chunks(size.max(1)).peekable();
+                let mut opcode = first;
+
+                while let Some(chunk) = chunks.next() {
+                    let fin = chunks.peek().is_none();
+                    self.encode_frame(writer, fin, opcode, chunk);
+                    opcode = 0x0;
+                }
+            }
+            _ => self.encode_frame(writer, true, first, payload),
+        }
+    }
+
+    fn encode_frame(&self, writer: &mut Writer, fin: bool, opcode: u8, payload: &[u8]) {
+        // Clients mask with a fresh key per frame
+        let mask = self.is_client.then(rand::random);
+        put_frame(&mut writer.scratch, fin, opcode, payload, mask);
+    }
 }
 
 impl WebSocket {
@@ -107,25 +482,149 @@ impl WebSocket {
         self.send_frame(0x2, payload).await
     }
 
-    pub async fn send_ping(&self) -> Result<()> {
-        self.send_frame(0x9, &[]).await
+    /// Send a ping carrying up to 125 bytes, which the peer echoes in its pong
+    pub async fn send_ping(&self, payload: &[u8]) -> Result<()> {
+        self.send_control(0x9, payload).await
     }
 
-    pub async fn send_pong(&self) -> Result<()> {
-        self.send_frame(0xA, &[]).await
+    /// Send a pong; an answer to a ping must carry the ping's payload
+    pub async fn send_pong(&self, payload: &[u8]) -> Result<()> {
+        self.send_control(0xA, payload).await
     }
 
+    async fn send_control(&self, opcode: u8, payload: &[u8]) -> Result<()> {
+        if payload.len() > MAX_CONTROL_PAYLOAD {
+            return Err(Error::InvalidFrame(format!(
+                "Control frame payload of {} bytes, over {MAX_CONTROL_PAYLOAD}",
+                payload.len()
+            )));
+        }
+        self.send_frame(opcode, payload).await
+    }
+
+    /// Send a close frame without a status code. Like [`Self::send_close`],
+    /// this doesn't wait for the peer's answer; see [`Self::close_with`].
     pub async fn close(&self) -> Result<()> {
-        self.send_frame(0x8, &[]).await
+        self.send_close_frame(close_stats::NO_STATUS, &[]).await
+    }
+
+    /// Send a close frame carrying a status code (see [`close`]) and reason
+    pub async fn send_close(&self, code: u16, reason: &str) -> Result<()> {
+        let mut payload = Vec::with_capacity(2 + reason.len());
+        payload.extend_from_slice(&code.to_be_bytes());
+        payload.extend_from_slice(reason.as_bytes());
+
+        self.send_close_frame(code, &payload).await
+    }
+
+    /// Only the first close frame is sent; later ones are dropped
+    async fn send_close_frame(&self, code: u16, payload: &[u8]) -> Result<()> {
+        if self.closing.sent.swap(true, Ordering::AcqRel) {
+            return Ok(());
+        }
+
+        self.record_close(Direction::Sent, code);
+        self.send_frame(0x8, payload).await
+    }
+
+    /// Run the closing handshake: send a close frame, wait for the peer's
+    /// close frame for up to [`Config::close_timeout`], then shut the
+    /// connection down. Frames arriving in the meantime are discarded unless
+    /// another task is reading.
+    pub async fn close_with(&self, code: u16, reason: &str) -> Result<()> {
+        self.send_close(code, reason).await?;
+
+        let mut received = self.closing.received.subscribe();
+        let answered = async {
+            tokio::select! {
+                _ = received.wait_for(|r| *r) => {}
+                _ = async {
+                    while !matches!(self.read().await, Ok(Frame::Close) | Err(_)) {}
+                } => {}
+            }
+        };
+        let wait = self.config.close_timeout.unwrap_or(DEFAULT_CLOSE_TIMEOUT);
+        let _ = timeout(wait, answered).await;
+
+        self.shutdown().await
+    }
+
+    /// Shut down the sending side of the underlying stream
+    async fn shutdown(&self) -> Result<()> {
+        let mut writer = self.writer.lock().await;
+        writer.stream.shutdown().await?;
+        Ok(())
+    }
+
+    /// Answer the peer's close frame with one carrying the same code, unless
+    /// we closed first, and shut down. A malformed close payload (RFC 6455
+    /// section 7.4) is answered with 1002 or 1007 instead of an echo, and
+    /// fails the read.
+    async fn close_received(&self, payload: &[u8]) -> Result<()> {
+        let code = close_code(payload);
+        self.record_close(Direction::Received, code);
+        self.closing.received.send_replace(true);
+
+        let malformed = match payload {
+            [_] => Some((
+                close::PROTOCOL_ERROR,
+                "Close payload of one byte".to_string(),
+            )),
+            [_, _, ..] if !close::is_valid(code) => {
+                Some((close::PROTOCOL_ERROR, format!("Invalid close code: {code}")))
+            }
+            [_, _, reason @ ..] if std::str::from_utf8(reason).is_err() => Some((
+                close::INVALID_PAYLOAD,
+                "Invalid UTF-8 in close reason".to_string(),
+            )),
+            _ => None,
+        };
+
+        let res = match &malformed {
+            Some((reply, reason)) => self.send_close(*reply, reason).await,
+            None if code == close_stats::NO_STATUS => self.close().await,
+            None => self.send_close(code, "").await,
+        };
+        res.ok();
+        self.shutdown().await.ok();
+
+        match malformed {
+            Some((_, reason)) => Err(Error::InvalidFrame(reason)),
+            None => Ok(()),
+        }
+    }
+
+    /// Rolling-window data rates in both directions, excluding control frames
+    pub fn throughput(&self) -> Throughput {
+        Throughput {
+            inbound: self.inbound.rate(),
+            outbound: self.outbound.rate(),
+        }
     }
 
+    /// Ping the peer every 15 seconds. With [`Config::pong_timeout`] set,
+    /// a ping left unanswered that long closes the connection with 1001 and
+    /// fails reads with `Error::PongTimeout`, so a half-open connection
+    /// doesn't linger. Pongs are only seen while another task reads.
     pub fn start_ping_loop(&self) {
         let s = self.clone();
         tokio::task::spawn(async move {
             let mut interval = tokio::time::interval(std::time::Duration::from_secs(15));
+            let mut pongs = s.closing.pongs.subscribe();
             loop {
                 interval.tick().await;
-                if s.send_ping().await.is_err() {
+                pongs.mark_unchanged();
+                if s.send_ping(&[]).await.is_err() {
+                    break;
+                }
+
+                let Some(wait) = s.config.pong_timeout else {
+                    continue;
+                };
+                if timeout(wait, pongs.changed()).await.is_err() {
+                    s.closing.dead.send_replace(true);
+                    s.send_close(close::GOING_AWAY, "Pong timeout").await.ok();
+                    s.shutdown().await.ok();
                     break;
                 }
             }
@@ -134,118 +633,410 @@ impl WebSocket {
 }
 
 impl WebSocket {
-    /// Read a full WebSocket frame (handling masking and control frames)
-    /// Returns (opcode, payload)
+    /// Read a single frame, unassembled, with control frames passed through
+    /// as they are. Returns (fin, opcode, payload).
     pub async fn read_frame(&self) -> Result<(bool, u8, Vec<u8>)> {
         let mut reader = self.reader.lock().await;
+        let (header, payload) = self.next_frame(&mut reader).await?;
+        if reader.assembly.is_idle() {
+            reader.held.clear();
+        }
+        Ok((header.fin, header.opcode, payload))
+    }
+
+    /// Next complete frame, unmasked. Partial progress stays in `reader`, so
+    /// this is cancel safe: bytes are only consumed once nothing else is
+    /// awaited before they're stored.
+    async fn next_frame(&self, reader: &mut Reader) -> Result<(FrameHeader, Vec<u8>)> {
+        loop {
+            if reader.frame.is_none() {
+                let Some(header) = FrameHeader::parse(&reader.buf) else {
+                    reader.fill(MAX_HEADER).await?;
+                    continue;
+                };
+
+                self.admit(&header, reader.assembly.buffered()).await?;
+                reader.buf.drain(..header.len);
+                reader.charged = 0;
+
+                let capacity = header.payload_len.min(READ_CHUNK) as usize;
+                reader.frame = Some((header, self.take_buffer(capacity)));
+            }
+
+            let Reader { buf, frame, .. } = reader;
+            let Some((header, payload)) = frame else {
+                continue;
+            };
 
-        // --- 1. Read first 2-byte header ---
-        let mut header = [0u8; 2];
-        reader.read_exact(&mut header).await?;
-
-        let fin = header[0] & 0x80 != 0;
-        let opcode = header[0] & 0x0F;
-        let masked = header[1] & 0x80 != 0;
-        let mut payload_len = (header[1] & 0x7F) as u64;
-
-        // --- 2. Read extended payload length if necessary ---
-        if payload_len == 126 {
-            let mut buf = [0u8; 2];
-            reader.read_exact(&mut buf).await?;
-            payload_len = u16::from_be_bytes(buf) as u64;
-        } else if payload_len == 127 {
-            let mut buf = [0u8; 8];
-            reader.read_exact(&mut buf).await?;
-            payload_len = u64::from_be_bytes(buf);
-        }
-
-        let payload = if masked {
-            // --- 3. Read mask key ---
-            let mut mask = [0u8; 4];
-            reader.read_exact(&mut mask).await?;
-            let mut payload = vec![0u8; payload_len as usize];
-            if payload_len > 0 {
-                reader.read_exact(&mut payload).await?;
-                for i in 0..payload.len() {
-                    payload[i] ^= mask[i % 4];
+            let missing = header.payload_len - payload.len() as u64;
+            let available = missing.min(buf.len() as u64) as usize;
+            payload.extend(buf.drain(..available));
+
+            if payload.len() as u64 == header.payload_len {
+                let (header, mut payload) = reader.frame.take().unwrap_or_else(|| unreachable!());
+                if let Some(mask) = header.mask {
+                    unmask(&mut payload, mask);
                 }
+                return Ok((header, payload));
             }
-            payload
-        } else {
-            // Per spec, client-to-server frames MUST be masked
-            if !self.is_server {
-                self.close().await.ok();
-                return Err(Error::InvalidFrame(
-                    "Received unmasked frame from client".into(),
-                ));
+
+            // Charged as the bytes arrive, so a declared length alone
+            // can't claim the budget
+            let want = missing.min(READ_CHUNK);
+            let covered = payload.len() as u64 + want;
+            if reader.charged < covered {
+                self.charge(reader, (covered - reader.charged) as usize)
+                    .await?;
+                reader.charged = covered;
             }
 
-            let mut payload = vec![0u8; payload_len as usize];
-            if payload_len > 0 {
-                reader.read_exact(&mut payload).await?;
+            reader.fill(want as usize).await?;
+        }
+    }
+
+    /// Check a frame header against the protocol and the configured limits,
+    /// given the `buffered` bytes of its message so far, before any of its
+    /// payload is read
+    async fn admit(&self, header: &FrameHeader, buffered: usize) -> Result<()> {
+        let FrameHeader {
+            opcode,
+            payload_len,
+            ..
+        } = *header;
+
+        if let Some((reason, message)) = header.violation(!self.is_client) {
+            self.send_close(close::PROTOCOL_ERROR, reason).await.ok();
+            return Err(Error::InvalidFrame(message));
+        }
+        if self.config.strict
+            && let Err(reason) = self.check_strict(header)
+        {
+            self.send_close(close::PROTOCOL_ERROR, &reason).await.ok();
+            return Err(Error::InvalidFrame(reason));
+        }
+        if let Some(limit) = self.config.max_frame_size
+            && payload_len > limit as u64
+        {
+            self.send_close(close::MESSAGE_TOO_BIG, "Frame too big")
+                .await
+                .ok();
+            return Err(Error::MessageTooBig {
+                size: payload_len,
+                limit,
+            });
+        }
+        if let Some(limit) = self.config.max_message_size
+            && opcode < 0x8
+        {
+            let size = (buffered as u64).saturating_add(payload_len);
+            if size > limit as u64 {
+                self.send_close(close::MESSAGE_TOO_BIG, "Message too big")
+                    .await
+                    .ok();
+                return Err(Error::MessageTooBig { size, limit });
             }
-            payload
-        };
+        }
 
-        // --- 6. Return opcode + payload ---
-        Ok((fin, opcode, payload))
+        Ok(())
     }
 
+    /// Charge `bytes` more of the message being read against the memory
+    /// budgets, waiting or failing as their [`Pressure`] says
+    async fn charge(&self, reader: &mut Reader, bytes: usize) -> Result<()> {
+        // Collected before being kept, so a cancelled wait releases them
+        let mut reserved = Vec::new();
+        for budget in self.config.memory_budget.iter().chain(&self.tenant_budget) {
+            match budget.reserve(bytes, &reader.held).await {
+                Ok(reservation) => reserved.push(reservation),
+                Err(e) => {
+                    self.close().await.ok();
+                    return Err(e);
+                }
+            }
+        }
+        reader.held.extend(reserved);
+        Ok(())
+    }
+
+    /// Read the next message or control frame. Fails with
+    /// `Error::PongTimeout` once [`Self::start_ping_loop`] gave up on the peer.
+    ///
+    /// Cancel safe: dropping the future, e.g. in a `select!`, loses no data;
+    /// the next call continues the frame or message where it stopped. A
+    /// pong owed for a ping read just before may go unsent.
     pub async fn read(&self) -> Result<Frame> {
-        let (fin, opcode, mut payload) = self.read_frame().await?;
+        let mut dead = self.closing.dead.subscribe();
+
+        tokio::select! {
+            frame = self.read_message() => frame,
+            _ = dead.wait_for(|d| *d) => Err(Error::PongTimeout),
+        }
+    }
+
+    async fn read_message(&self) -> Result<Frame> {
+        let mut reader = self.reader.lock().await;
+
+        loop {
+            let (header, payload) = self.next_frame(&mut reader).await?;
 
-        if !fin {
-            // Continuation loop
-            while let (fin, o, mut p) = self.read_frame().await?
-                && !fin
+            let step = match reader
+                .assembly
+                .push(header, payload, self.buffers.as_deref())
             {
-                match o {
-                    // Continuation
-                    0x0 => payload.append(&mut p),
-                    // Close
-                    0x8 => {
-                        self.close().await.ok();
-                    }
-                    // Ping
-                    0x9 => {
-                        self.send_pong().await.ok();
-                    }
-                    // Pong
-                    0xA => {}
-                    _ => {
-                        self.close().await.ok();
-                        return Err(Error::InvalidFrame(format!("Unknown opcode: {opcode}")));
+                Ok(step) => step,
+                Err(reason) => {
+                    self.send_close(close::PROTOCOL_ERROR, &reason).await.ok();
+                    return Err(Error::InvalidFrame(reason));
+                }
+            };
+
+            match step {
+                Step::Pending => {}
+
+                Step::Message(opcode, compressed, payload) => {
+                    let held = std::mem::take(&mut reader.held);
+                    let frame = self.message(opcode, compressed, payload).await;
+                    drop(held);
+                    return frame;
+                }
+
+                // Close, abandoning any message in progress
+                Step::Control(0x8, payload) => {
+                    reader.assembly = Assembly::Idle;
+                    reader.held.clear();
+                    let res = self.close_received(&payload).await;
+                    self.give_back(payload);
+                    res?;
+                    return Ok(Frame::Close);
+                }
+
+                // Ping or pong; a message in progress picks up on the next read
+                Step::Control(opcode, payload) => {
+                    if reader.assembly.is_idle() {
+                        reader.held.clear();
                     }
+                    return Ok(match opcode {
+                        0x9 => {
+                            self.send_pong(&payload).await.ok();
+                            Frame::Ping(payload)
+                        }
+                        _ => {
+                            self.closing.pongs.send_modify(|n| *n += 1);
+                            Frame::Pong(payload)
+                        }
+                    });
                 }
             }
         }
+    }
+
+    /// Why a frame header breaks `Config::strict`, if it does. RSV1 is only
+    /// allowed on the first frame of a data message, and only with a
+    /// compression extension (RFC 7692 section 6).
+    fn check_strict(&self, header: &FrameHeader) -> std::result::Result<(), String> {
+        let rsv1_allowed = self.compression.is_some() && matches!(header.opcode, 0x1 | 0x2);
+        let allowed = if rsv1_allowed { 0b100 } else { 0 };
+
+        if header.rsv & !allowed != 0 {
+            return Err(format!("Unexpected RSV bits: {:03b}", header.rsv));
+        }
+        match header.opcode {
+            0x0..=0x2 | 0x8..=0xA => Ok(()),
+            opcode => Err(format!("Reserved opcode: {opcode:#x}")),
+        }
+    }
+
+    /// Turn a complete data message into a `Frame`
+    async fn message(&self, opcode: u8, compressed: bool, mut payload: Vec<u8>) -> Result<Frame> {
+        if compressed {
+            let inflated = self.inflate(&payload).await?;
+            self.give_back(std::mem::replace(&mut payload, inflated));
+        }
 
         match opcode {
-            // Close
-            0x8 => {
-                self.close().await.ok();
-                Ok(Frame::Close)
+            // Text
+            0x1 => {
+                self.inbound.record(payload.len());
+                match String::from_utf8(payload) {
+                    Ok(text) => Ok(Frame::Text(text)),
+                    Err(e) if self.config.lenient_utf8 => Ok(Frame::Binary(e.into_bytes())),
+                    Err(e) => {
+                        self.send_close(close::INVALID_PAYLOAD, "Invalid UTF-8")
+                            .await
+                            .ok();
+                        Err(e.into())
+                    }
+                }
             }
 
-            // Ping
-            0x9 => {
-                self.send_pong().await.ok();
-                Ok(Frame::Ping)
+            // Binary
+            _ => {
+                self.inbound.record(payload.len());
+                Ok(Frame::Binary(payload))
             }
+        }
+    }
 
-            // Pong
-            0xA => Ok(Frame::Pong),
+    /// Undo the compression extension on a message whose first frame had
+    /// RSV1 set
+    async fn inflate(&self, payload: &[u8]) -> Result<Vec<u8>> {
+        if let Some(compression) = &self.compression {
+            let size_limit = self
+                .config
+                .max_decompressed_size
+                .or(self.config.max_message_size);
+            let ratio_limit = self
+                .config
+                .max_compression_ratio
+                .map(|ratio| payload.len().saturating_mul(ratio).max(RATIO_EXEMPT));
+            let limit = size_limit.into_iter().chain(ratio_limit).min();
 
-            // Text
-            0x1 => Ok(Frame::Text(String::from_utf8(payload)?)),
+            return match compression.decompress(payload, limit) {
+                Err(e @ Error::MessageTooBig { .. }) => {
+                    self.send_close(close::MESSAGE_TOO_BIG, "Message too big")
+                        .await
+                        .ok();
+                    Err(e)
+                }
+                res => res,
+            };
+        }
 
-            // Binary
-            0x2 => Ok(Frame::Binary(payload)),
+        self.send_close(close::PROTOCOL_ERROR, "Unexpected RSV1")
+            .await
+            .ok();
+        Err(Error::InvalidFrame(
+            "Compressed frame without a compression extension".into(),
+        ))
+    }
+}
 
-            _ => {
-                self.close().await.ok();
-                Err(Error::InvalidFrame(format!("Unknown opcode: {opcode}")))
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncWriteExt, DuplexStream, duplex};
+
+    use super::*;
+
+    const MASK: [u8; 4] = [0x37, 0xfa, 0x21, 0x3d];
+
+    /// Server side socket and the client's end of the pipe
+    fn pair() -> (WebSocket, DuplexStream) {
+        let (server, client) = duplex(1 << 20);
+        let ws = WebSocket::from_stream(server, false, Upgrade::default());
+        (ws, client)
+    }
+
+    fn client_frame(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        put_frame(&mut out, fin, opcode, payload, Some(MASK));
+        out
+    }
+
+    /// Poll `read` once with everything written so far, then drop it
+    async fn cancel_read(ws: &WebSocket) {
+        let read = timeout(Duration::ZERO, ws.read()).await;
+        assert!(read.is_err(), "read finished early: {read:?}");
+    }
+
+    fn binary(frame: Result<Frame>) -> Vec<u8> {
+        match frame {
+            Ok(Frame::Binary(payload)) => payload,
+            other => panic!("expected a binary message, got {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn read_cancelled_mid_header() {
+        let (ws, mut client) = pair();
+        let payload = vec![7; 300];
+        let frame = client_frame(true, 0x2, &payload);
+
+        // 2 + 2 bytes of length + 4 of mask; stop inside the extended length
+        client.write_all(&frame[..3]).await.unwrap();
+        cancel_read(&ws).await;
+        {
+            let reader = ws.reader.lock().await;
+            assert_eq!(reader.buf.len(), 3);
+            assert!(reader.frame.is_none());
+        }
+
+        client.write_all(&frame[3..]).await.unwrap();
+        assert_eq!(binary(ws.read().await), payload);
+    }
+
+    #[tokio::test]
+    async fn read_cancelled_mid_payload() {
+        let (ws, mut client) = pair();
+        let payload: Vec<u8> = (0..3 * READ_CHUNK as usize).map(|i| i as u8).collect();
+        let frame = client_frame(true, 0x2, &payload);
+
+        let split = frame.len() / 2;
+        client.write_all(&frame[..split]).await.unwrap();
+        cancel_read(&ws).await;
+        {
+            let reader = ws.reader.lock().await;
+            let (_, partial) = reader.frame.as_ref().expect("no frame in progress");
+            assert!(!partial.is_empty() && partial.len() < payload.len());
+        }
+
+        client.write_all(&frame[split..]).await.unwrap();
+        assert_eq!(binary(ws.read().await), payload);
+    }
+
+    #[tokio::test]
+    async fn read_cancelled_mid_fragment() {
+        let (ws, mut client) = pair();
+        let mut frames = client_frame(false, 0x1, b"hello, ");
+        frames.extend(client_frame(false, 0x0, b"fragmented "));
+        let last = client_frame(true, 0x0, b"world");
+
+        // Everything up to the middle of the last fragment's payload
+        let split = frames.len() + last.len() - 2;
+        frames.extend(&last);
+        client.write_all(&frames[..split]).await.unwrap();
+        cancel_read(&ws).await;
+        {
+            let reader = ws.reader.lock().await;
+            assert!(!reader.assembly.is_idle());
+            assert!(reader.frame.is_some());
+        }
+
+        client.write_all(&frames[split..]).await.unwrap();
+        match ws.read().await {
+            Ok(Frame::Text(text)) => assert_eq!(text, "hello, fragmented world"),
+            other => panic!("expected a text message, got {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn close_abandons_partial_message() {
+        let (ws, mut client) = pair();
+        let mut frames = client_frame(false, 0x2, &[1, 2, 3]);
+        frames.extend(client_frame(true, 0x8, &close::NORMAL.to_be_bytes()));
+        client.write_all(&frames).await.unwrap();
+
+        assert!(matches!(ws.read().await, Ok(Frame::Close)));
+        let reader = ws.reader.lock().await;
+        assert!(reader.assembly.is_idle());
+        assert!(reader.held.is_empty());
+    }
+
+    #[tokio::test]
+    async fn read_cancelled_at_every_byte() {
+        let (ws, mut client) = pair();
+        let payload: Vec<u8> = (0..200).collect();
+        let mut frames = client_frame(false, 0x2, &payload[..90]);
+        frames.extend(client_frame(true, 0x9, b"ping"));
+        frames.extend(client_frame(true, 0x0, &payload[90..]));
+
+        for byte in &frames[..frames.len() - 1] {
+            client.write_all(&[*byte]).await.unwrap();
+            if let Ok(frame) = timeout(Duration::ZERO, ws.read()).await {
+                assert!(matches!(frame, Ok(Frame::Ping(ref p)) if p == b"ping"));
             }
         }
+
+        client.write_all(&frames[frames.len() - 1..]).await.unwrap();
+        assert_eq!(binary(ws.read().await), payload);
     }
 }
@@ -0,0 +1,356 @@
+use tokio::io::AsyncReadExt;
+
+use super::budget::Reservation;
+use super::{BufferSource, Error, MAX_CONTROL_PAYLOAD, ReadHalf, Result};
+
+/// Longest frame header: 2 bytes, a 64-bit length and a mask key
+pub(crate) const MAX_HEADER: usize = 14;
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct FrameHeader {
+    pub(crate) fin: bool,
+    pub(crate) rsv1: bool,
+    /// RSV1, RSV2 and RSV3 in the low three bits
+    pub(crate) rsv: u8,
+    pub(crate) opcode: u8,
+    pub(crate) mask: Option<[u8; 4]>,
+    pub(crate) payload_len: u64,
+    /// Bytes the header itself takes
+    pub(crate) len: usize,
+}
+
+impl FrameHeader {
+    /// Parse the header at the start of `buf`, or `None` if it isn't all
+    /// there yet
+    pub(crate) fn parse(buf: &[u8]) -> Option<Self> {
+        let [b0, b1, rest @ ..] = buf else {
+            return None;
+        };
+
+        let (payload_len, mut len) = match b1 & 0x7F {
+            126 => (
+                u16::from_be_bytes(rest.get(..2)?.try_into().ok()?) as u64,
+                4,
+            ),
+            127 => (u64::from_be_bytes(rest.get(..8)?.try_into().ok()?), 10),
+            n => (n as u64, 2),
+        };
+
+        let mask = match b1 & 0x80 != 0 {
+            true => {
+                let mask = buf.get(len..len + 4)?.try_into().ok()?;
+                len += 4;
+                Some(mask)
+            }
+            false => None,
+        };
+
+        Some(Self {
+            fin: b0 & 0x80 != 0,
+            rsv1: b0 & super::RSV1 != 0,
+            rsv: (b0 >> 4) & 0x7,
+            opcode: b0 & 0x0F,
+            mask,
+            payload_len,
+            len,
+        })
+    }
+}
+
+impl FrameHeader {
+    /// How the frame breaks RFC 6455 whatever the config, as a close reason
+    /// and an error message. `masked` says whether the peer must mask, as
+    /// clients must and servers must not (section 5.1).
+    pub(crate) fn violation(&self, masked: bool) -> Option<(&'static str, String)> {
+        if self.opcode >= 0x8 && self.payload_len > MAX_CONTROL_PAYLOAD as u64 {
+            return Some((
+                "Control frame too long",
+                format!(
+                    "Control frame of {} bytes, over {MAX_CONTROL_PAYLOAD}",
+                    self.payload_len
+                ),
+            ));
+        }
+        match (self.mask.is_some(), masked) {
+            (false, true) => Some((
+                "Unmasked frame",
+                "Received unmasked frame from client".into(),
+            )),
+            (true, false) => Some(("Masked frame", "Received masked frame from server".into())),
+            _ => None,
+        }
+    }
+}
+
+/// Undo the masking of a payload received with `mask`
+pub(crate) fn unmask(payload: &mut [u8], mask: [u8; 4]) {
+    for (i, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
+    }
+}
+
+/// Where message assembly stands between frames (RFC 6455 section 5.4)
+#[derive(Debug, Default)]
+pub(crate) enum Assembly {
+    /// Between messages
+    #[default]
+    Idle,
+    /// Inside a message whose first frame lacked FIN
+    Fragmented {
+        opcode: u8,
+        /// RSV1 of the first frame
+        compressed: bool,
+        payload: Vec<u8>,
+    },
+}
+
+/// What a frame amounts to once fed to an [`Assembly`]
+#[derive(Debug)]
+pub(crate) enum Step {
+    /// Part of a message that isn't complete yet
+    Pending,
+    /// A complete data message: opcode, whether compressed, payload
+    Message(u8, bool, Vec<u8>),
+    /// A control frame, which may arrive between fragments without
+    /// disturbing the message around it
+    Control(u8, Vec<u8>),
+}
+
+impl Assembly {
+    /// Bytes of the message being assembled
+    pub(crate) fn buffered(&self) -> usize {
+        match self {
+            Assembly::Idle => 0,
+            Assembly::Fragmented { payload, .. } => payload.len(),
+        }
+    }
+
+    pub(crate) fn is_idle(&self) -> bool {
+        matches!(self, Assembly::Idle)
+    }
+
+    /// Advance by one frame, or say why the frame breaks the protocol.
+    /// Continuation payloads, once copied into the message, go back to
+    /// `buffers`.
+    pub(crate) fn push(
+        &mut self,
+        header: FrameHeader,
+        payload: Vec<u8>,
+        buffers: Option<&dyn BufferSource>,
+    ) -> std::result::Result<Step, String> {
+        match (header.opcode, std::mem::take(self)) {
+            (0x8..=0xA, state) => {
+                *self = state;
+                match header.fin {
+                    true => Ok(Step::Control(header.opcode, payload)),
+                    false => Err("Fragmented control frame".into()),
+                }
+            }
+            (0x1 | 0x2, Assembly::Idle) if header.fin => {
+                Ok(Step::Message(header.opcode, header.rsv1, payload))
+            }
+            (0x1 | 0x2, Assembly::Idle) => {
+                *self = Assembly::Fragmented {
+                    opcode: header.opcode,
+                    compressed: header.rsv1,
+                    payload,
+                };
+                Ok(Step::Pending)
+            }
+            (0x1 | 0x2, Assembly::Fragmented { .. }) => {
+                Err("New message before the previous one finished".into())
+            }
+            (0x0, Assembly::Idle) => Err("Continuation frame outside a message".into()),
+            (
+                0x0,
+                Assembly::Fragmented {
+                    opcode,
+                    compressed,
+                    payload: mut message,
+                },
+            ) => {
+                message.extend_from_slice(&payload);
+                if let Some(buffers) = buffers {
+                    buffers.give_back(payload);
+                }
+                if header.fin {
+                    return Ok(Step::Message(opcode, compressed, message));
+                }
+                *self = Assembly::Fragmented {
+                    opcode,
+                    compressed,
+                    payload: message,
+                };
+                Ok(Step::Pending)
+            }
+            (opcode, state) => {
+                *self = state;
+                Err(format!("Unknown opcode: {opcode}"))
+            }
+        }
+    }
+}
+
+/// Read side of a connection with everything parsed so far. Progress lives
+/// here rather than in the read future, so a read dropped at any await,
+/// e.g. by losing a `select!`, resumes where it stopped on the next call.
+pub(crate) struct Reader {
+    stream: ReadHalf,
+    /// Bytes read from the stream that haven't been parsed yet
+    pub(crate) buf: Vec<u8>,
+    /// Frame whose payload is arriving, and its payload so far
+    pub(crate) frame: Option<(FrameHeader, Vec<u8>)>,
+    pub(crate) assembly: Assembly,
+    /// Budget charged for the message being read
+    pub(crate) held: Vec<Reservation>,
+    /// Bytes of `frame`'s payload covered by `held`
+    pub(crate) charged: u64,
+}
+
+impl Reader {
+    pub(crate) fn new(stream: ReadHalf) -> Self {
+        Self {
+            stream,
+            buf: Vec::new(),
+            frame: None,
+            assembly: Assembly::Idle,
+            held: Vec::new(),
+            charged: 0,
+        }
+    }
+
+    /// Read up to `want` more bytes into `buf`. Cancel safe: a dropped call
+    /// has read nothing.
+    pub(crate) async fn fill(&mut self, want: usize) -> Result<()> {
+        self.buf.reserve(want);
+
+        let read = (&mut self.stream)
+            .take(want as u64)
+            .read_buf(&mut self.buf)
+            .await?;
+        if read == 0 {
+            return Err(Error::Io(std::io::ErrorKind::UnexpectedEof.into()));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(fin: bool, opcode: u8, payload: &[u8]) -> FrameHeader {
+        FrameHeader {
+            fin,
+            rsv1: false,
+            rsv: 0,
+            opcode,
+            mask: None,
+            payload_len: payload.len() as u64,
+            len: 2,
+        }
+    }
+
+    fn push(
+        assembly: &mut Assembly,
+        fin: bool,
+        opcode: u8,
+        payload: &[u8],
+    ) -> std::result::Result<Step, String> {
+        assembly.push(header(fin, opcode, payload), payload.to_vec(), None)
+    }
+
+    #[test]
+    fn fragmented_text() {
+        let mut assembly = Assembly::Idle;
+        assert!(matches!(
+            push(&mut assembly, false, 0x1, b"frag"),
+            Ok(Step::Pending)
+        ));
+        assert!(matches!(
+            push(&mut assembly, false, 0x0, b"men"),
+            Ok(Step::Pending)
+        ));
+        assert_eq!(assembly.buffered(), 7);
+
+        match push(&mut assembly, true, 0x0, b"ted") {
+            Ok(Step::Message(0x1, false, payload)) => assert_eq!(payload, b"fragmented"),
+            other => panic!("unexpected {other:?}"),
+        }
+        assert!(assembly.is_idle());
+    }
+
+    #[test]
+    fn fragmented_binary() {
+        let mut assembly = Assembly::Idle;
+        assert!(matches!(
+            push(&mut assembly, false, 0x2, &[1, 2]),
+            Ok(Step::Pending)
+        ));
+
+        match push(&mut assembly, true, 0x0, &[3]) {
+            Ok(Step::Message(0x2, false, payload)) => assert_eq!(payload, [1, 2, 3]),
+            other => panic!("unexpected {other:?}"),
+        }
+        assert!(assembly.is_idle());
+    }
+
+    #[test]
+    fn ping_between_fragments() {
+        let mut assembly = Assembly::Idle;
+        push(&mut assembly, false, 0x1, b"a").unwrap();
+
+        match push(&mut assembly, true, 0x9, b"ping") {
+            Ok(Step::Control(0x9, payload)) => assert_eq!(payload, b"ping"),
+            other => panic!("unexpected {other:?}"),
+        }
+        assert_eq!(assembly.buffered(), 1);
+
+        match push(&mut assembly, true, 0x0, b"b") {
+            Ok(Step::Message(0x1, _, payload)) => assert_eq!(payload, b"ab"),
+            other => panic!("unexpected {other:?}"),
+        }
+    }
+
+    #[test]
+    fn close_between_fragments() {
+        let mut assembly = Assembly::Idle;
+        push(&mut assembly, false, 0x2, &[1, 2, 3]).unwrap();
+
+        // Handed up as is; the reader drops the partial message on close
+        match push(&mut assembly, true, 0x8, &[0x03, 0xe8]) {
+            Ok(Step::Control(0x8, payload)) => assert_eq!(payload, [0x03, 0xe8]),
+            other => panic!("unexpected {other:?}"),
+        }
+    }
+
+    #[test]
+    fn continuation_outside_a_message() {
+        let mut assembly = Assembly::Idle;
+        assert!(push(&mut assembly, true, 0x0, b"x").is_err());
+        assert!(assembly.is_idle());
+    }
+
+    #[test]
+    fn fragmented_control_frame() {
+        let mut assembly = Assembly::Idle;
+        assert!(push(&mut assembly, false, 0x9, b"ping").is_err());
+    }
+
+    #[test]
+    fn new_message_before_the_last_finished() {
+        let mut assembly = Assembly::Idle;
+        push(&mut assembly, false, 0x1, b"a").unwrap();
+        assert!(push(&mut assembly, true, 0x2, b"b").is_err());
+    }
+
+    #[test]
+    fn unknown_opcode() {
+        let mut assembly = Assembly::Idle;
+        push(&mut assembly, false, 0x1, b"a").unwrap();
+
+        let err = push(&mut assembly, true, 0x3, b"").unwrap_err();
+        assert_eq!(err, "Unknown opcode: 3");
+        assert_eq!(assembly.buffered(), 1);
+    }
+}
@@ -0,0 +1,74 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use tokio::time::Instant;
+
+/// Length of the rolling window, in one-second buckets.
+const WINDOW_SECS: u64 = 10;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, serde::Serialize)]
+pub struct Rate {
+    pub bytes_per_sec: f64,
+    pub messages_per_sec: f64,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, serde::Serialize)]
+pub struct Throughput {
+    pub inbound: Rate,
+    pub outbound: Rate,
+}
+
+#[derive(Default)]
+struct Bucket {
+    second: AtomicU64,
+    bytes: AtomicU64,
+    messages: AtomicU64,
+}
+
+/// Lock-free per-second counters over the last `WINDOW_SECS` seconds.
+pub(crate) struct Meter {
+    start: Instant,
+    buckets: [Bucket; WINDOW_SECS as usize],
+}
+
+impl Meter {
+    pub(crate) fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            buckets: std::array::from_fn(|_| Bucket::default()),
+        }
+    }
+
+    pub(crate) fn record(&self, bytes: usize) {
+        let now = self.start.elapsed().as_secs();
+        let bucket = &self.buckets[(now % WINDOW_SECS) as usize];
+
+        if bucket.second.swap(now, Ordering::Relaxed) != now {
+            bucket.bytes.store(0, Ordering::Relaxed);
+            bucket.messages.store(0, Ordering::Relaxed);
+        }
+
+        bucket.bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+        bucket.messages.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn rate(&self) -> Rate {
+        let elapsed = self.start.elapsed();
+        let now = elapsed.as_secs();
+
+        let (mut bytes, mut messages) = (0, 0);
+        for bucket in &self.buckets {
+            let second = bucket.second.load(Ordering::Relaxed);
+            if second <= now && now - second < WINDOW_SECS {
+                bytes += bucket.bytes.load(Ordering::Relaxed);
+                messages += bucket.messages.load(Ordering::Relaxed);
+            }
+        }
+
+        let window = elapsed.as_secs_f64().clamp(1.0, WINDOW_SECS as f64);
+
+        Rate {
+            bytes_per_sec: bytes as f64 / window,
+            messages_per_sec: messages as f64 / window,
+        }
+    }
+}